@@ -0,0 +1,75 @@
+//! The interface every attitude filter implements, so one node can run any of them.
+
+use crate::rotation::{self, Covariance, Quaternion};
+
+/// What a filter did with a magnetometer heading measurement
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum HeadingUpdate {
+    /// Heading corrected towards the measurement, which put it this far (rad) anticlockwise of the
+    /// estimate
+    Applied(f64),
+    /// Measurement put the heading this far (rad) anticlockwise of the estimate, outside the gate
+    Rejected(f64),
+}
+
+/// An attitude filter fusing gyro and accelerometer samples in body axes.
+///
+/// Each IMU sample is fed as a `predict` with the gyro followed by an `update` with the
+/// accelerometer. Filters that need an attitude to start from take it from the first accelerometer
+/// sample, and report no orientation until then.
+pub trait AttitudeEstimator: Send {
+    /// Name the filter is selected by
+    fn name(&self) -> &'static str;
+
+    /// Propagate the attitude by `dt` seconds of body rate `gyro` (rad/s)
+    fn predict(&mut self, gyro: [f64; 3], dt: f64);
+
+    /// Correct roll and pitch with a specific force sample `accel` (m/s^2)
+    fn update(&mut self, accel: [f64; 3]);
+
+    /// Correct with a specific force sample trusted less than usual, its noise standard deviation
+    /// multiplied by `noise_scale`. Filters without a noise model to scale use it as a normal update.
+    fn update_scaled(&mut self, accel: [f64; 3], noise_scale: f64) {
+        let _ = noise_scale;
+        self.update(accel);
+    }
+
+    /// Body to world orientation, None until the filter has initialized
+    fn orientation(&self) -> Option<Quaternion>;
+
+    /// Orientation as roll, pitch and yaw (ZYX), None until the filter has initialized
+    fn euler(&self) -> Option<[f64; 3]> {
+        self.orientation().map(rotation::quaternion_to_euler)
+    }
+
+    /// Covariance of roll, pitch and yaw (rad^2), None if the filter doesn't track one or hasn't
+    /// initialized
+    fn euler_covariance(&self) -> Option<Covariance> {
+        None
+    }
+
+    /// Normalized innovation squared of the accelerometer update made since the last prediction,
+    /// None if there wasn't one or the filter doesn't compute it
+    fn nis(&self) -> Option<f64> {
+        None
+    }
+
+    /// Gyro bias (rad/s) the filter has estimated and is removing, None if it doesn't estimate one
+    fn gyro_bias(&self) -> Option<[f64; 3]> {
+        None
+    }
+
+    /// Correct the heading with a magnetometer sample `mag` (body axes, any unit) whose horizontal
+    /// part points `heading` rad (ENU, anticlockwise from east), `noise` rad 1 sigma, unless it's
+    /// more than `gate` sigmas from the estimate. An infinite gate means the heading is known to have
+    /// drifted to the measurement, so the filter should open up its uncertainty to take it. None if
+    /// the filter hasn't initialized or doesn't take magnetometer updates, in which case its heading
+    /// has to be corrected from outside.
+    fn update_mag(&mut self, mag: [f64; 3], heading: f64, noise: f64, gate: f64) -> Option<HeadingUpdate> {
+        let _ = (mag, heading, noise, gate);
+        None
+    }
+
+    /// Forget the attitude, the next accelerometer sample starts the filter again
+    fn reset(&mut self);
+}
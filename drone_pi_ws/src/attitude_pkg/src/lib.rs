@@ -0,0 +1,7 @@
+//! Attitude math and the estimator interface shared by the sensor fusion nodes.
+//!
+//! Quaternions are `[w, x, y, z]` arrays rotating body axes into the world frame (ENU, REP-103), and
+//! Euler angles are roll, pitch and yaw applied yaw first (ZYX), in radians.
+
+pub mod estimator;
+pub mod rotation;
@@ -0,0 +1,317 @@
+//! Conversions between quaternions, rotation matrices and Euler angles, and the quaternion algebra
+//! the estimators need.
+//!
+//! At pitch +-90 degrees (gimbal lock) roll and yaw rotate about the same axis and only their
+//! difference (or sum, pointing down) is defined. The conversions back to Euler angles put all of it
+//! in yaw and report zero roll there, so the angles still reproduce the same rotation.
+
+use std::f64::consts::{FRAC_PI_2, PI};
+
+/// How close |sin(pitch)| has to get to 1 to be treated as gimbal lock
+const GIMBAL_LOCK_THRESHOLD: f64 = 1.0 - 1e-9;
+
+pub type Quaternion = [f64; 4];
+pub type RotationMatrix = [[f64; 3]; 3];
+pub type Covariance = [[f64; 3]; 3];
+
+pub const IDENTITY: Quaternion = [1.0, 0.0, 0.0, 0.0];
+
+/// Quaternion for roll, pitch and yaw applied yaw first (ZYX)
+pub fn euler_to_quaternion(roll: f64, pitch: f64, yaw: f64) -> Quaternion {
+    let (sr, cr) = (roll / 2.0).sin_cos();
+    let (sp, cp) = (pitch / 2.0).sin_cos();
+    let (sy, cy) = (yaw / 2.0).sin_cos();
+    [
+        cr * cp * cy + sr * sp * sy,
+        sr * cp * cy - cr * sp * sy,
+        cr * sp * cy + sr * cp * sy,
+        cr * cp * sy - sr * sp * cy,
+    ]
+}
+
+/// Roll, pitch and yaw (ZYX) of a unit quaternion, pitch in [-pi/2, pi/2] and the others in [-pi, pi)
+pub fn quaternion_to_euler(q: Quaternion) -> [f64; 3] {
+    let [w, x, y, z] = q;
+    let sin_pitch = 2.0 * (w * y - x * z);
+    if sin_pitch >= GIMBAL_LOCK_THRESHOLD {
+        return [0.0, FRAC_PI_2, wrap_angle(-2.0 * x.atan2(w))];
+    }
+    if sin_pitch <= -GIMBAL_LOCK_THRESHOLD {
+        return [0.0, -FRAC_PI_2, wrap_angle(2.0 * x.atan2(w))];
+    }
+
+    let roll = (2.0 * (w * x + y * z)).atan2(1.0 - 2.0 * (x * x + y * y));
+    let pitch = sin_pitch.asin();
+    let yaw = (2.0 * (w * z + x * y)).atan2(1.0 - 2.0 * (y * y + z * z));
+    [wrap_angle(roll), pitch, wrap_angle(yaw)]
+}
+
+/// Quaternion for a rotation vector (axis times angle, rad)
+pub fn rotation_vector_to_quaternion(v: [f64; 3]) -> Quaternion {
+    let angle = (v[0] * v[0] + v[1] * v[1] + v[2] * v[2]).sqrt();
+    if angle < 1e-12 {
+        return normalize([1.0, v[0] / 2.0, v[1] / 2.0, v[2] / 2.0]);
+    }
+    let (sin, cos) = (angle / 2.0).sin_cos();
+    [cos, v[0] / angle * sin, v[1] / angle * sin, v[2] / angle * sin]
+}
+
+/// Level attitude with gravity along a body frame specific force sample: roll and pitch from it, yaw 0
+pub fn gravity_to_quaternion(accel: [f64; 3]) -> Quaternion {
+    let roll = accel[1].atan2(accel[2]);
+    let pitch = (-accel[0]).atan2(accel[1].hypot(accel[2]));
+    euler_to_quaternion(roll, pitch, 0.0)
+}
+
+/// Rotation matrix of a unit quaternion, v_world = m * v_body
+pub fn quaternion_to_matrix(q: Quaternion) -> RotationMatrix {
+    let [w, x, y, z] = q;
+    [
+        [1.0 - 2.0 * (y * y + z * z), 2.0 * (x * y - w * z), 2.0 * (x * z + w * y)],
+        [2.0 * (x * y + w * z), 1.0 - 2.0 * (x * x + z * z), 2.0 * (y * z - w * x)],
+        [2.0 * (x * z - w * y), 2.0 * (y * z + w * x), 1.0 - 2.0 * (x * x + y * y)],
+    ]
+}
+
+/// Unit quaternion of a rotation matrix, with a non-negative w
+pub fn matrix_to_quaternion(m: RotationMatrix) -> Quaternion {
+    // Shepperd's method: solve from whichever of w, x, y, z is largest to avoid dividing by a small one
+    let trace = m[0][0] + m[1][1] + m[2][2];
+    let q = if trace > m[0][0] && trace > m[1][1] && trace > m[2][2] {
+        let s = 2.0 * (1.0 + trace).sqrt();
+        [s / 4.0, (m[2][1] - m[1][2]) / s, (m[0][2] - m[2][0]) / s, (m[1][0] - m[0][1]) / s]
+    } else if m[0][0] > m[1][1] && m[0][0] > m[2][2] {
+        let s = 2.0 * (1.0 + m[0][0] - m[1][1] - m[2][2]).sqrt();
+        [(m[2][1] - m[1][2]) / s, s / 4.0, (m[0][1] + m[1][0]) / s, (m[0][2] + m[2][0]) / s]
+    } else if m[1][1] > m[2][2] {
+        let s = 2.0 * (1.0 + m[1][1] - m[0][0] - m[2][2]).sqrt();
+        [(m[0][2] - m[2][0]) / s, (m[0][1] + m[1][0]) / s, s / 4.0, (m[1][2] + m[2][1]) / s]
+    } else {
+        let s = 2.0 * (1.0 + m[2][2] - m[0][0] - m[1][1]).sqrt();
+        [(m[1][0] - m[0][1]) / s, (m[0][2] + m[2][0]) / s, (m[1][2] + m[2][1]) / s, s / 4.0]
+    };
+    let q = normalize(q);
+    if q[0] < 0.0 {
+        q.map(|c| -c)
+    } else {
+        q
+    }
+}
+
+/// Rotation matrix for roll, pitch and yaw applied yaw first (ZYX), Rz(yaw) * Ry(pitch) * Rx(roll)
+pub fn euler_to_matrix(roll: f64, pitch: f64, yaw: f64) -> RotationMatrix {
+    let (sr, cr) = roll.sin_cos();
+    let (sp, cp) = pitch.sin_cos();
+    let (sy, cy) = yaw.sin_cos();
+    [
+        [cy * cp, cy * sp * sr - sy * cr, cy * sp * cr + sy * sr],
+        [sy * cp, sy * sp * sr + cy * cr, sy * sp * cr - cy * sr],
+        [-sp, cp * sr, cp * cr],
+    ]
+}
+
+/// Roll, pitch and yaw (ZYX) of a rotation matrix, with the same ranges as `quaternion_to_euler`
+pub fn matrix_to_euler(m: RotationMatrix) -> [f64; 3] {
+    let sin_pitch = -m[2][0];
+    if sin_pitch >= GIMBAL_LOCK_THRESHOLD {
+        // m[0][1] = -sin(yaw - roll), m[1][1] = cos(yaw - roll)
+        return [0.0, FRAC_PI_2, (-m[0][1]).atan2(m[1][1])];
+    }
+    if sin_pitch <= -GIMBAL_LOCK_THRESHOLD {
+        // m[0][1] = -sin(yaw + roll), m[1][1] = cos(yaw + roll)
+        return [0.0, -FRAC_PI_2, (-m[0][1]).atan2(m[1][1])];
+    }
+
+    let roll = m[2][1].atan2(m[2][2]);
+    let pitch = sin_pitch.clamp(-1.0, 1.0).asin();
+    let yaw = m[1][0].atan2(m[0][0]);
+    [wrap_angle(roll), pitch, wrap_angle(yaw)]
+}
+
+/// Hamilton product a * b
+pub fn multiply(a: Quaternion, b: Quaternion) -> Quaternion {
+    [
+        a[0] * b[0] - a[1] * b[1] - a[2] * b[2] - a[3] * b[3],
+        a[0] * b[1] + a[1] * b[0] + a[2] * b[3] - a[3] * b[2],
+        a[0] * b[2] - a[1] * b[3] + a[2] * b[0] + a[3] * b[1],
+        a[0] * b[3] + a[1] * b[2] - a[2] * b[1] + a[3] * b[0],
+    ]
+}
+
+/// Inverse rotation of a unit quaternion
+pub fn conjugate(q: Quaternion) -> Quaternion {
+    [q[0], -q[1], -q[2], -q[3]]
+}
+
+/// Scale to unit length, the identity if the quaternion has collapsed to zero
+pub fn normalize(q: Quaternion) -> Quaternion {
+    let norm = q.iter().map(|c| c * c).sum::<f64>().sqrt();
+    if norm > 0.0 && norm.is_finite() {
+        q.map(|c| c / norm)
+    } else {
+        IDENTITY
+    }
+}
+
+/// Rotate a body frame vector into the world frame
+pub fn rotate_vector(q: Quaternion, v: [f64; 3]) -> [f64; 3] {
+    let m = quaternion_to_matrix(q);
+    [0, 1, 2].map(|row| m[row][0] * v[0] + m[row][1] * v[1] + m[row][2] * v[2])
+}
+
+/// Wrap an angle to [-pi, pi)
+pub fn wrap_angle(angle: f64) -> f64 {
+    (angle + PI).rem_euclid(2.0 * PI) - PI
+}
+
+/// Maps body rates to roll, pitch and yaw rates (ZYX), and so a small rotation in body axes to the
+/// change in the angles. It's singular at gimbal lock, so pitch is held just short of +-90 degrees.
+pub fn euler_rate_matrix(roll: f64, pitch: f64) -> [[f64; 3]; 3] {
+    let (sr, cr) = roll.sin_cos();
+    let max_pitch = GIMBAL_LOCK_THRESHOLD.asin();
+    let (sp, cp) = pitch.clamp(-max_pitch, max_pitch).sin_cos();
+    [
+        [1.0, sr * sp / cp, cr * sp / cp],
+        [0.0, cr, -sr],
+        [0.0, sr / cp, cr / cp],
+    ]
+}
+
+/// Covariance of roll, pitch and yaw given the covariance of a small rotation error in body axes
+pub fn body_to_euler_covariance(q: Quaternion, covariance: Covariance) -> Covariance {
+    let [roll, pitch, _] = quaternion_to_euler(q);
+    let e = euler_rate_matrix(roll, pitch);
+    sandwich(&e, &covariance)
+}
+
+/// Covariance of the rotation error in body axes given the covariance of a quaternion's components.
+/// The error rotation is twice the vector part of conj(q) * dq.
+pub fn quaternion_to_body_covariance(q: Quaternion, covariance: [[f64; 4]; 4]) -> Covariance {
+    let [w, x, y, z] = q;
+    let g = [
+        [-2.0 * x, 2.0 * w, 2.0 * z, -2.0 * y],
+        [-2.0 * y, -2.0 * z, 2.0 * w, 2.0 * x],
+        [-2.0 * z, 2.0 * y, -2.0 * x, 2.0 * w],
+    ];
+    sandwich(&g, &covariance)
+}
+
+/// a * covariance * a^T
+fn sandwich<const N: usize>(a: &[[f64; N]; 3], covariance: &[[f64; N]; N]) -> Covariance {
+    let mut result = [[0.0; 3]; 3];
+    for (row, result_row) in result.iter_mut().enumerate() {
+        for (col, value) in result_row.iter_mut().enumerate() {
+            for i in 0..N {
+                for j in 0..N {
+                    *value += a[row][i] * covariance[i][j] * a[col][j];
+                }
+            }
+        }
+    }
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TOLERANCE: f64 = 1e-9;
+
+    fn assert_same_rotation(a: RotationMatrix, b: RotationMatrix) {
+        for row in 0..3 {
+            for col in 0..3 {
+                assert!(
+                    (a[row][col] - b[row][col]).abs() < TOLERANCE,
+                    "{:?} != {:?}",
+                    a,
+                    b
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn quaternion_and_matrix_agree_on_euler_angles() {
+        let (roll, pitch, yaw) = (0.3, -0.7, 2.1);
+        assert_same_rotation(
+            quaternion_to_matrix(euler_to_quaternion(roll, pitch, yaw)),
+            euler_to_matrix(roll, pitch, yaw),
+        );
+    }
+
+    #[test]
+    fn euler_round_trip_away_from_gimbal_lock() {
+        for &(roll, pitch, yaw) in &[(0.0, 0.0, 0.0), (0.3, -0.7, 2.1), (-3.0, 1.2, -0.4), (1.0, -1.5, 3.1)] {
+            let from_quaternion = quaternion_to_euler(euler_to_quaternion(roll, pitch, yaw));
+            let from_matrix = matrix_to_euler(euler_to_matrix(roll, pitch, yaw));
+            for angles in [from_quaternion, from_matrix] {
+                assert!((angles[0] - roll).abs() < TOLERANCE, "{:?}", angles);
+                assert!((angles[1] - pitch).abs() < TOLERANCE, "{:?}", angles);
+                assert!((angles[2] - yaw).abs() < TOLERANCE, "{:?}", angles);
+            }
+        }
+    }
+
+    #[test]
+    fn gimbal_lock_folds_roll_into_yaw() {
+        for &pitch in &[FRAC_PI_2, -FRAC_PI_2] {
+            for &(roll, yaw) in &[(0.0, 0.0), (0.4, 1.0), (-2.0, 2.5), (3.0, -3.0)] {
+                let expected = euler_to_matrix(roll, pitch, yaw);
+
+                let [r, p, y] = quaternion_to_euler(euler_to_quaternion(roll, pitch, yaw));
+                assert_eq!(r, 0.0);
+                assert_eq!(p, pitch);
+                assert!(y.is_finite());
+                assert_same_rotation(euler_to_matrix(r, p, y), expected);
+
+                let [r, p, y] = matrix_to_euler(expected);
+                assert_eq!(r, 0.0);
+                assert_eq!(p, pitch);
+                assert_same_rotation(euler_to_matrix(r, p, y), expected);
+            }
+        }
+    }
+
+    #[test]
+    fn matrix_round_trip_at_gimbal_lock_and_half_turns() {
+        let cases = [
+            (0.4, FRAC_PI_2, 1.0),
+            (0.4, -FRAC_PI_2, 1.0),
+            (PI, 0.0, 0.0),
+            (0.0, 0.0, PI),
+            (PI, 0.0, PI / 2.0),
+        ];
+        for &(roll, pitch, yaw) in &cases {
+            let m = euler_to_matrix(roll, pitch, yaw);
+            assert_same_rotation(quaternion_to_matrix(matrix_to_quaternion(m)), m);
+        }
+    }
+
+    #[test]
+    fn euler_rate_matrix_maps_small_body_rotations() {
+        let (roll, pitch, yaw) = (0.3, -0.7, 2.1);
+        let q = euler_to_quaternion(roll, pitch, yaw);
+        let e = euler_rate_matrix(roll, pitch);
+        for axis in 0..3 {
+            let mut step = [0.0; 3];
+            step[axis] = 1e-6;
+            let half = step.map(|angle| angle / 2.0);
+            let [r, p, y] = quaternion_to_euler(multiply(q, [1.0, half[0], half[1], half[2]]));
+            let change = [r - roll, p - pitch, y - yaw];
+            for row in 0..3 {
+                assert!((change[row] / 1e-6 - e[row][axis]).abs() < 1e-4, "{:?} {:?}", change, e);
+            }
+        }
+    }
+
+    #[test]
+    fn rotate_vector_matches_quaternion_product() {
+        let q = euler_to_quaternion(0.3, -0.7, 2.1);
+        let v = [1.0, -2.0, 0.5];
+        let rotated = multiply(multiply(q, [0.0, v[0], v[1], v[2]]), conjugate(q));
+        let expected = rotate_vector(q, v);
+        for axis in 0..3 {
+            assert!((rotated[axis + 1] - expected[axis]).abs() < TOLERANCE);
+        }
+    }
+}
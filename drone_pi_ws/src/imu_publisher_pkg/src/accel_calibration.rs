@@ -0,0 +1,127 @@
+//! Guided six-position accelerometer calibration.
+//!
+//! Walks through resting the IMU on each of its six faces, averages the accelerometer on each one,
+//! fits offsets, scale factors and cross-axis misalignment, and writes them to the sensor's
+//! calibration file where `imu_publisher` picks them up on its next start.
+//!
+//! Usage: accel_calibration [--source icm20948|synthetic] [--device /dev/spidev0.0]
+//!                          [--samples 1000] [--calibration-dir DIR]
+use imu_publisher_pkg::calibration::{self, CalibrationFile};
+use imu_publisher_pkg::error::ImuError;
+use imu_publisher_pkg::fitting::fit_accel_calibration;
+use imu_publisher_pkg::imu_source::{self, GRAVITY};
+use imu_publisher_pkg::sensor_config::SensorConfig;
+use std::io::{self, BufRead, Write};
+use std::path::PathBuf;
+use std::thread;
+use std::time::Duration;
+
+/// Largest per-axis standard deviation (m/s^2) accepted while the IMU is supposed to be resting
+const MAX_STILL_STD: f32 = 0.15;
+
+fn main() -> Result<(), Box<dyn std::error::Error>> {
+    let mut source_kind = "icm20948".to_string();
+    let mut device = "/dev/spidev0.0".to_string();
+    let mut samples = 1000;
+    let mut calibration_dir = calibration::default_calibration_dir();
+
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        let mut value = || args.next().ok_or(format!("Missing value for {}", arg));
+        match arg.as_str() {
+            "--source" => source_kind = value()?,
+            "--device" => device = value()?,
+            "--samples" => samples = value()?.parse()?,
+            "--calibration-dir" => calibration_dir = PathBuf::from(value()?),
+            _ => return Err(format!("Unknown argument {}", arg).into()),
+        }
+    }
+
+    let mut source = imu_source::open_source(&source_kind, &device, SensorConfig::default())?;
+    source.initialize()?;
+
+    let g = GRAVITY as f32;
+    let faces: [(&str, [f32; 3]); 6] = [
+        ("+Z up (flat, right side up)", [0.0, 0.0, g]),
+        ("-Z up (upside down)", [0.0, 0.0, -g]),
+        ("+X up", [g, 0.0, 0.0]),
+        ("-X up", [-g, 0.0, 0.0]),
+        ("+Y up", [0.0, g, 0.0]),
+        ("-Y up", [0.0, -g, 0.0]),
+    ];
+
+    let stdin = io::stdin();
+    let mut poses = Vec::new();
+    for (face, reference) in faces.iter() {
+        loop {
+            print!("Rest the IMU with {} and press Enter ", face);
+            io::stdout().flush()?;
+            stdin.lock().read_line(&mut String::new())?;
+
+            let (mean, std) = average_accel(source.as_mut(), samples)?;
+            println!(
+                "  mean [{:.3}, {:.3}, {:.3}] std [{:.3}, {:.3}, {:.3}]",
+                mean[0], mean[1], mean[2], std[0], std[1], std[2]
+            );
+
+            if std.iter().any(|s| *s > MAX_STILL_STD) {
+                println!("  Motion detected, hold it still and try again");
+                continue;
+            }
+            // The axis pointing up should read close to +/-g with the expected sign
+            let axis = reference.iter().position(|r| *r != 0.0).unwrap();
+            if mean[axis] * reference[axis].signum() < 0.5 * g {
+                println!("  That doesn't look like {}, try again", face);
+                continue;
+            }
+            poses.push((mean, *reference));
+            break;
+        }
+    }
+
+    let accel = fit_accel_calibration(&poses)?;
+    println!("Offset:       {:?}", accel.offset);
+    println!("Scale:        {:?}", accel.scale);
+    println!("Misalignment: {:?}", accel.misalignment);
+    for ((mean, reference), (face, _)) in poses.iter().zip(faces.iter()) {
+        let corrected = accel.apply(*mean);
+        let error = (0..3).map(|i| (corrected[i] - reference[i]).powi(2)).sum::<f32>().sqrt();
+        println!("  {}: residual {:.4} m/s^2", face, error);
+    }
+
+    // Keep any other calibration already stored for this sensor
+    let path = calibration::calibration_path(&calibration_dir, &source.sensor_id());
+    let mut file = CalibrationFile::load(&path)?.unwrap_or_default();
+    file.accel = Some(accel);
+    file.save(&path)?;
+    println!("Saved accelerometer calibration to {}", path.display());
+
+    Ok(())
+}
+
+/// Mean and standard deviation of `samples` accelerometer readings taken at ~1 kHz
+fn average_accel(
+    source: &mut dyn imu_source::ImuSource,
+    samples: usize,
+) -> Result<([f32; 3], [f32; 3]), ImuError> {
+    let mut sum = [0.0f64; 3];
+    let mut sum_sq = [0.0f64; 3];
+    for _ in 0..samples {
+        let accel = source.read_accel()?;
+        for axis in 0..3 {
+            sum[axis] += accel[axis] as f64;
+            sum_sq[axis] += (accel[axis] as f64).powi(2);
+        }
+        thread::sleep(Duration::from_millis(1));
+    }
+
+    let n = samples.max(1) as f64;
+    let mut mean = [0.0; 3];
+    let mut std = [0.0; 3];
+    for axis in 0..3 {
+        let m = sum[axis] / n;
+        mean[axis] = m as f32;
+        std[axis] = (sum_sq[axis] / n - m * m).max(0.0).sqrt() as f32;
+    }
+    Ok((mean, std))
+}
@@ -0,0 +1,381 @@
+use crate::error::ImuError;
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// Everything we know about one sensor's errors, persisted as TOML between boots
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct CalibrationFile {
+    pub gyro: Option<GyroCalibration>,
+    pub gyro_thermal: Option<GyroThermalCalibration>,
+    pub accel: Option<AccelCalibration>,
+    pub mag: Option<MagCalibration>,
+    pub noise: Option<NoiseCalibration>,
+}
+
+/// Gyroscope zero-rate offset in rad/s, subtracted from every reading
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct GyroCalibration {
+    pub bias: [f32; 3],
+    pub samples: usize,
+    pub temperature: Option<f32>, // Degrees C the bias was measured at, if known
+}
+
+/// Gyroscope bias drift with temperature, fitted over a warm-up run:
+/// `bias(T) = sum over k of coefficients[axis][k] * (T - reference_temperature)^k`
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct GyroThermalCalibration {
+    pub reference_temperature: f32,  // Degrees C
+    pub coefficients: [Vec<f32>; 3], // rad/s per degree C^k, constant term first
+    pub temperature_range: [f32; 2], // Degrees C covered by the warm-up run
+}
+
+impl GyroThermalCalibration {
+    /// Modelled bias at `temperature`, held at the edge of the fitted range rather than extrapolated
+    pub fn bias(&self, temperature: f32) -> [f32; 3] {
+        let dt = temperature.clamp(self.temperature_range[0], self.temperature_range[1]) - self.reference_temperature;
+        self.coefficients
+            .each_ref()
+            .map(|coefficients| coefficients.iter().rev().fold(0.0, |sum, c| sum * dt + c))
+    }
+}
+
+/// Accelerometer correction from the six-position calibration:
+/// `corrected = misalignment * diag(scale) * (raw - offset)`
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct AccelCalibration {
+    pub offset: [f32; 3],            // m/s^2
+    pub scale: [f32; 3],             // Unitless gain per axis
+    pub misalignment: [[f32; 3]; 3], // Cross-axis coupling, unit diagonal
+}
+
+impl AccelCalibration {
+    /// Correct a raw accelerometer sample
+    pub fn apply(&self, raw: [f32; 3]) -> [f32; 3] {
+        let mut scaled = [0.0; 3];
+        for (axis, value) in scaled.iter_mut().enumerate() {
+            *value = (raw[axis] - self.offset[axis]) * self.scale[axis];
+        }
+
+        let m = &self.misalignment;
+        [
+            m[0][0] * scaled[0] + m[0][1] * scaled[1] + m[0][2] * scaled[2],
+            m[1][0] * scaled[0] + m[1][1] * scaled[1] + m[1][2] * scaled[2],
+            m[2][0] * scaled[0] + m[2][1] * scaled[1] + m[2][2] * scaled[2],
+        ]
+    }
+}
+
+/// Magnetometer hard and soft iron correction from the ellipsoid fit:
+/// `corrected = soft_iron * (raw - hard_iron)`
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct MagCalibration {
+    pub hard_iron: [f32; 3],      // uT
+    pub soft_iron: [[f32; 3]; 3], // Maps the fitted ellipsoid back onto a sphere
+    pub field_strength: f32,      // uT, radius of that sphere
+}
+
+impl MagCalibration {
+    /// Correct a raw magnetometer sample
+    pub fn apply(&self, raw: [f32; 3]) -> [f32; 3] {
+        let centered = [
+            raw[0] - self.hard_iron[0],
+            raw[1] - self.hard_iron[1],
+            raw[2] - self.hard_iron[2],
+        ];
+
+        let m = &self.soft_iron;
+        [
+            m[0][0] * centered[0] + m[0][1] * centered[1] + m[0][2] * centered[2],
+            m[1][0] * centered[0] + m[1][1] * centered[1] + m[1][2] * centered[2],
+            m[2][0] * centered[0] + m[2][1] * centered[1] + m[2][2] * centered[2],
+        ]
+    }
+}
+
+/// Per-axis noise variance of the published accelerometer and gyroscope samples, measured at rest
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub struct NoiseCalibration {
+    pub accel_variance: [f32; 3], // (m/s^2)^2
+    pub gyro_variance: [f32; 3],  // (rad/s)^2
+}
+
+impl CalibrationFile {
+    /// Gyro bias to subtract at `temperature`.
+    ///
+    /// With a thermal model, the bias from the last still calibration is carried along the model
+    /// from the temperature it was measured at, so the calibration sets the offset and the model how
+    /// it drifts. Without a still calibration the model is used on its own.
+    pub fn gyro_bias(&self, temperature: Option<f32>) -> [f32; 3] {
+        let measured = self.gyro.as_ref().map(|gyro| (gyro.bias, gyro.temperature));
+        let (Some(thermal), Some(temperature)) = (&self.gyro_thermal, temperature) else {
+            return measured.map(|(bias, _)| bias).unwrap_or([0.0; 3]);
+        };
+
+        let model = thermal.bias(temperature);
+        match measured {
+            Some((bias, Some(measured_at))) => {
+                let at_calibration = thermal.bias(measured_at);
+                [0, 1, 2].map(|axis| bias[axis] + model[axis] - at_calibration[axis])
+            }
+            // Measured at an unknown temperature, so there's nothing to anchor the model to
+            Some((bias, None)) => bias,
+            None => model,
+        }
+    }
+
+    /// Read a calibration file, returning None if it doesn't exist yet
+    pub fn load(path: &Path) -> Result<Option<Self>, ImuError> {
+        if !path.exists() {
+            return Ok(None);
+        }
+        let contents = fs::read_to_string(path)
+            .map_err(|e| ImuError::Calibration(format!("Failed to read {}: {}", path.display(), e)))?;
+        let file = toml::from_str(&contents)
+            .map_err(|e| ImuError::Calibration(format!("Failed to parse {}: {}", path.display(), e)))?;
+        Ok(Some(file))
+    }
+
+    /// Write the calibration file, creating its directory if needed
+    pub fn save(&self, path: &Path) -> Result<(), ImuError> {
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)
+                .map_err(|e| ImuError::Calibration(format!("Failed to create {}: {}", dir.display(), e)))?;
+        }
+        let contents = toml::to_string_pretty(self)
+            .map_err(|e| ImuError::Calibration(format!("Failed to serialize calibration: {}", e)))?;
+        fs::write(path, contents)
+            .map_err(|e| ImuError::Calibration(format!("Failed to write {}: {}", path.display(), e)))
+    }
+}
+
+/// Calibration file for a sensor, one per sensor id so swapping boards doesn't reuse stale values
+pub fn calibration_path(dir: &Path, sensor_id: &str) -> PathBuf {
+    dir.join(format!("{}.toml", sensor_id))
+}
+
+/// Default directory for calibration files, `~/.ros/imu_calibration`
+pub fn default_calibration_dir() -> PathBuf {
+    let home = std::env::var("HOME").unwrap_or_else(|_| ".".to_string());
+    Path::new(&home).join(".ros").join("imu_calibration")
+}
+
+/// Outcome of feeding a sample to a calibrator
+#[derive(Clone, Debug, PartialEq)]
+pub enum CalibrationStatus<T> {
+    InProgress,
+    Done(T),
+    Rejected(String),
+}
+
+/// Averages gyroscope readings while the vehicle sits still to find the zero-rate bias.
+///
+/// Calibration is rejected as soon as either sensor shows motion: a gyro axis straying more than
+/// `motion_threshold` rad/s from its running mean, or the accelerometer magnitude changing by more
+/// than `accel_threshold` m/s^2.
+pub struct GyroCalibrator {
+    samples_required: usize,
+    motion_threshold: f32,
+    accel_threshold: f32,
+    gyro_sum: [f64; 3],
+    accel_norm_sum: f64,
+    count: usize,
+}
+
+impl GyroCalibrator {
+    /// Number of samples to average before the running means are trusted for motion checks
+    const SETTLE_SAMPLES: usize = 50;
+
+    pub fn new(samples_required: usize, motion_threshold: f32, accel_threshold: f32) -> Self {
+        Self {
+            samples_required: samples_required.max(Self::SETTLE_SAMPLES),
+            motion_threshold,
+            accel_threshold,
+            gyro_sum: [0.0; 3],
+            accel_norm_sum: 0.0,
+            count: 0,
+        }
+    }
+
+    /// Add a raw accel/gyro pair, returning the bias once enough still samples have been collected
+    pub fn add_sample(&mut self, accel: [f32; 3], gyro: [f32; 3]) -> CalibrationStatus<GyroCalibration> {
+        let accel_norm = (accel[0] * accel[0] + accel[1] * accel[1] + accel[2] * accel[2]).sqrt() as f64;
+
+        if self.count >= Self::SETTLE_SAMPLES {
+            let n = self.count as f64;
+            for (axis, value) in gyro.iter().enumerate() {
+                let mean = self.gyro_sum[axis] / n;
+                if (*value as f64 - mean).abs() > self.motion_threshold as f64 {
+                    return CalibrationStatus::Rejected(format!(
+                        "gyro axis {} moved {:.4} rad/s from its mean",
+                        axis,
+                        *value as f64 - mean
+                    ));
+                }
+            }
+            let accel_mean = self.accel_norm_sum / n;
+            if (accel_norm - accel_mean).abs() > self.accel_threshold as f64 {
+                return CalibrationStatus::Rejected(format!(
+                    "accelerometer magnitude changed by {:.3} m/s^2",
+                    accel_norm - accel_mean
+                ));
+            }
+        }
+
+        for (sum, value) in self.gyro_sum.iter_mut().zip(gyro.iter()) {
+            *sum += *value as f64;
+        }
+        self.accel_norm_sum += accel_norm;
+        self.count += 1;
+
+        if self.count < self.samples_required {
+            return CalibrationStatus::InProgress;
+        }
+
+        let n = self.count as f64;
+        CalibrationStatus::Done(GyroCalibration {
+            bias: [
+                (self.gyro_sum[0] / n) as f32,
+                (self.gyro_sum[1] / n) as f32,
+                (self.gyro_sum[2] / n) as f32,
+            ],
+            samples: self.count,
+            temperature: None,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TOLERANCE: f32 = 1e-6;
+    const LEVEL: [f32; 3] = [0.0, 0.0, 9.81];
+    const BIAS: [f32; 3] = [0.01, -0.02, 0.005];
+
+    /// Gyro sample at rest: the bias plus a small alternating wobble that averages out
+    fn still_gyro(i: usize) -> [f32; 3] {
+        let wobble = if i.is_multiple_of(2) { 1e-3 } else { -1e-3 };
+        BIAS.map(|bias| bias + wobble)
+    }
+
+    fn thermal() -> GyroThermalCalibration {
+        // Linear drift of 1 mrad/s per degree on X only, fitted from 20 to 50 degrees
+        GyroThermalCalibration {
+            reference_temperature: 30.0,
+            coefficients: [vec![0.0, 1e-3], vec![0.0], vec![0.0]],
+            temperature_range: [20.0, 50.0],
+        }
+    }
+
+    #[test]
+    fn still_samples_average_to_the_bias() {
+        let mut calibrator = GyroCalibrator::new(200, 0.01, 0.2);
+        for i in 0..199 {
+            assert!(matches!(calibrator.add_sample(LEVEL, still_gyro(i)), CalibrationStatus::InProgress));
+        }
+        match calibrator.add_sample(LEVEL, still_gyro(199)) {
+            CalibrationStatus::Done(gyro) => {
+                assert_eq!(gyro.samples, 200);
+                for (bias, expected) in gyro.bias.iter().zip(BIAS) {
+                    assert!((bias - expected).abs() < TOLERANCE, "{:?}", gyro.bias);
+                }
+            }
+            other => panic!("{:?}", other),
+        }
+    }
+
+    #[test]
+    fn motion_rejects_the_calibration() {
+        // A turn on one gyro axis
+        let mut calibrator = GyroCalibrator::new(200, 0.01, 0.2);
+        for i in 0..100 {
+            calibrator.add_sample(LEVEL, still_gyro(i));
+        }
+        match calibrator.add_sample(LEVEL, [BIAS[0], BIAS[1], BIAS[2] + 0.05]) {
+            CalibrationStatus::Rejected(reason) => assert!(reason.contains("gyro axis 2"), "{}", reason),
+            other => panic!("{:?}", other),
+        }
+
+        // The vehicle being picked up, seen on the accelerometer with the gyro still quiet
+        let mut calibrator = GyroCalibrator::new(200, 0.01, 0.2);
+        for i in 0..100 {
+            calibrator.add_sample(LEVEL, still_gyro(i));
+        }
+        match calibrator.add_sample([0.0, 0.0, 10.5], still_gyro(100)) {
+            CalibrationStatus::Rejected(reason) => assert!(reason.contains("accelerometer"), "{}", reason),
+            other => panic!("{:?}", other),
+        }
+    }
+
+    #[test]
+    fn motion_before_the_means_settle_is_not_judged() {
+        // The first samples have no mean to compare against yet
+        let mut calibrator = GyroCalibrator::new(100, 0.01, 0.2);
+        assert!(matches!(calibrator.add_sample(LEVEL, [0.05, 0.0, 0.0]), CalibrationStatus::InProgress));
+    }
+
+    #[test]
+    fn thermal_model_is_anchored_at_the_calibration_temperature() {
+        let mut file = CalibrationFile {
+            gyro: Some(GyroCalibration {
+                bias: BIAS,
+                samples: 2000,
+                temperature: Some(35.0),
+            }),
+            gyro_thermal: Some(thermal()),
+            ..CalibrationFile::default()
+        };
+
+        // At the calibration temperature the measured bias holds exactly
+        assert_eq!(file.gyro_bias(Some(35.0)), BIAS);
+        // 10 degrees warmer, X has drifted 10 mrad/s from there
+        let warm = file.gyro_bias(Some(45.0));
+        assert!((warm[0] - (BIAS[0] + 0.01)).abs() < TOLERANCE, "{:?}", warm);
+        assert_eq!(&warm[1..], &BIAS[1..]);
+        // Past the fitted range the drift is held at the range's edge
+        assert_eq!(file.gyro_bias(Some(80.0)), file.gyro_bias(Some(50.0)));
+        // Without a temperature the measured bias is all there is
+        assert_eq!(file.gyro_bias(None), BIAS);
+
+        // Measured at an unknown temperature there's nothing to anchor the model to
+        file.gyro.as_mut().unwrap().temperature = None;
+        assert_eq!(file.gyro_bias(Some(45.0)), BIAS);
+
+        // And without a measurement the model is used on its own
+        file.gyro = None;
+        assert!((file.gyro_bias(Some(45.0))[0] - 0.015).abs() < TOLERANCE);
+    }
+
+    #[test]
+    fn calibration_file_round_trips() {
+        let dir = std::env::temp_dir().join(format!("imu_calibration_test_{}", std::process::id()));
+        let path = calibration_path(&dir, "icm20948_spidev0.0");
+        assert!(CalibrationFile::load(&path).unwrap().is_none());
+
+        let file = CalibrationFile {
+            gyro: Some(GyroCalibration {
+                bias: BIAS,
+                samples: 2000,
+                temperature: Some(35.0),
+            }),
+            gyro_thermal: Some(thermal()),
+            noise: Some(NoiseCalibration {
+                accel_variance: [1e-4, 2e-4, 3e-4],
+                gyro_variance: [1e-6, 2e-6, 3e-6],
+            }),
+            ..CalibrationFile::default()
+        };
+        file.save(&path).unwrap();
+        let loaded = CalibrationFile::load(&path).unwrap().unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+
+        let gyro = loaded.gyro.unwrap();
+        assert_eq!((gyro.bias, gyro.samples, gyro.temperature), (BIAS, 2000, Some(35.0)));
+        let thermal = loaded.gyro_thermal.unwrap();
+        assert_eq!(thermal.coefficients, [vec![0.0, 1e-3], vec![0.0], vec![0.0]]);
+        assert_eq!(thermal.temperature_range, [20.0, 50.0]);
+        assert_eq!(loaded.noise, file.noise);
+        assert!(loaded.accel.is_none() && loaded.mag.is_none());
+    }
+}
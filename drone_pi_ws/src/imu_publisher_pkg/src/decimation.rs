@@ -0,0 +1,272 @@
+//! Decimation of the sensor rate samples to the published rate.
+//!
+//! The sensor runs at its full output data rate so vibration can be tracked and notched, but
+//! publishing every sample floods DDS. `Decimator` takes the corrected body axis samples at the
+//! sensor rate and emits one output per `factor` of them. Rates are low-pass filtered below the
+//! output Nyquist frequency first, so vibration above it can't alias down into the band consumers
+//! care about. Alongside, the samples are integrated into a delta angle and delta velocity over
+//! each output interval, with the coning and sculling corrections for rotation within the interval
+//! (Savage's two-speed algorithm), for consumers that want increments rather than rates.
+
+use crate::error::ImuError;
+use crate::filter::{FilterSettings, LowPassFilter};
+use std::time::Duration;
+
+/// Anti-aliasing cutoff as a fraction of the output rate, 80% of the output Nyquist frequency
+const ANTI_ALIAS_CUTOFF: f32 = 0.4;
+
+/// Butterworth order of the anti-aliasing filter
+const ANTI_ALIAS_ORDER: usize = 4;
+
+/// What the published `Imu` message carries
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum OutputMode {
+    Rate,  // Anti-aliased rates at the end of each interval
+    Delta, // Mean rates over each interval from the coning/sculling corrected increments
+}
+
+impl OutputMode {
+    pub fn from_name(name: &str) -> Result<Self, ImuError> {
+        match name {
+            "rate" => Ok(OutputMode::Rate),
+            "delta" => Ok(OutputMode::Delta),
+            other => Err(ImuError::Config(format!("Output mode must be rate or delta, got {}", other))),
+        }
+    }
+}
+
+/// One published sample, in body axes
+#[derive(Clone, Copy, Debug)]
+pub struct DecimatedSample {
+    pub accel: [f32; 3],          // m/s^2, anti-aliased
+    pub gyro: [f32; 3],           // rad/s, anti-aliased
+    pub delta_angle: [f32; 3],    // Rotation vector over the interval, rad, coning corrected
+    pub delta_velocity: [f32; 3], // m/s over the interval in the axes at its start, sculling corrected
+    pub interval: f32,            // s covered by the increments
+    pub timestamp: Duration,      // Last sample in the interval, since the UNIX epoch
+}
+
+impl DecimatedSample {
+    /// Mean acceleration and angular rate over the interval, from the increments
+    pub fn mean_rates(&self) -> ([f32; 3], [f32; 3]) {
+        (
+            self.delta_velocity.map(|dv| dv / self.interval),
+            self.delta_angle.map(|da| da / self.interval),
+        )
+    }
+}
+
+pub struct Decimator {
+    factor: usize,
+    period: f64,                                     // Sensor sample period, s
+    filters: Option<(LowPassFilter, LowPassFilter)>, // Accel and gyro anti-aliasing, None when not decimating
+    count: usize,                                    // Samples so far this interval
+    alpha: [f64; 3],                                 // Summed angle increments this interval
+    nu: [f64; 3],                                    // Summed velocity increments this interval
+    coning: [f64; 3],
+    sculling: [f64; 3],
+}
+
+impl Decimator {
+    /// Decimate from `sample_rate_hz` by the whole number factor that comes closest to `output_rate_hz`
+    pub fn new(sample_rate_hz: f64, output_rate_hz: f64) -> Result<Self, ImuError> {
+        if !(output_rate_hz > 0.0 && output_rate_hz <= sample_rate_hz) {
+            return Err(ImuError::Config(format!(
+                "Output rate must be between 0 and the sensor rate ({} Hz), got {}",
+                sample_rate_hz, output_rate_hz
+            )));
+        }
+        let factor = ((sample_rate_hz / output_rate_hz).round() as usize).max(1);
+
+        let filters = if factor > 1 {
+            let settings = FilterSettings {
+                sample_rate_hz: sample_rate_hz as f32,
+                cutoff_hz: ANTI_ALIAS_CUTOFF * (sample_rate_hz / factor as f64) as f32,
+                order: ANTI_ALIAS_ORDER,
+            };
+            Some((LowPassFilter::new(&settings)?, LowPassFilter::new(&settings)?))
+        } else {
+            None
+        };
+
+        Ok(Self {
+            factor,
+            period: 1.0 / sample_rate_hz,
+            filters,
+            count: 0,
+            alpha: [0.0; 3],
+            nu: [0.0; 3],
+            coning: [0.0; 3],
+            sculling: [0.0; 3],
+        })
+    }
+
+    /// Sensor samples per published sample
+    pub fn factor(&self) -> usize {
+        self.factor
+    }
+
+    /// Published rate in Hz
+    pub fn output_rate_hz(&self) -> f64 {
+        1.0 / (self.period * self.factor as f64)
+    }
+
+    /// Bandwidth the published samples are limited to in Hz, None when not decimating
+    pub fn bandwidth_hz(&self) -> Option<f32> {
+        self.filters.as_ref().map(|_| ANTI_ALIAS_CUTOFF * self.output_rate_hz() as f32)
+    }
+
+    /// Add a body axis sample at the sensor rate, returning the published sample if it completes an interval
+    pub fn push(&mut self, accel: [f32; 3], gyro: [f32; 3], timestamp: Duration) -> Option<DecimatedSample> {
+        let d_theta = gyro.map(|w| w as f64 * self.period);
+        let d_v = accel.map(|a| a as f64 * self.period);
+
+        // Corrections use the increments summed before this sample, so update them before the sums
+        let coning = cross(self.alpha, d_theta);
+        let sculling_a = cross(self.alpha, d_v);
+        let sculling_b = cross(self.nu, d_theta);
+        for axis in 0..3 {
+            self.coning[axis] += 0.5 * coning[axis];
+            self.sculling[axis] += 0.5 * (sculling_a[axis] + sculling_b[axis]);
+            self.alpha[axis] += d_theta[axis];
+            self.nu[axis] += d_v[axis];
+        }
+
+        let (accel, gyro) = match self.filters.as_mut() {
+            Some((accel_filter, gyro_filter)) => (accel_filter.run(accel), gyro_filter.run(gyro)),
+            None => (accel, gyro),
+        };
+
+        self.count += 1;
+        if self.count < self.factor {
+            return None;
+        }
+
+        // Velocity increments were summed in a rotating frame, express them in the body axes at the
+        // start of the interval like the angle
+        let rotation = cross(self.alpha, self.nu);
+        let delta_angle = [0, 1, 2].map(|axis| (self.alpha[axis] + self.coning[axis]) as f32);
+        let delta_velocity =
+            [0, 1, 2].map(|axis| (self.nu[axis] + 0.5 * rotation[axis] + self.sculling[axis]) as f32);
+        let sample = DecimatedSample {
+            accel,
+            gyro,
+            delta_angle,
+            delta_velocity,
+            interval: (self.period * self.count as f64) as f32,
+            timestamp,
+        };
+        self.start_interval();
+        Some(sample)
+    }
+
+    /// Drop the current interval and the filter state, after a gap neither belongs to the new data
+    pub fn reset(&mut self) {
+        if let Some((accel_filter, gyro_filter)) = self.filters.as_mut() {
+            accel_filter.reset();
+            gyro_filter.reset();
+        }
+        self.start_interval();
+    }
+
+    fn start_interval(&mut self) {
+        self.count = 0;
+        self.alpha = [0.0; 3];
+        self.nu = [0.0; 3];
+        self.coning = [0.0; 3];
+        self.sculling = [0.0; 3];
+    }
+}
+
+fn cross(a: [f64; 3], b: [f64; 3]) -> [f64; 3] {
+    [
+        a[1] * b[2] - a[2] * b[1],
+        a[2] * b[0] - a[0] * b[2],
+        a[0] * b[1] - a[1] * b[0],
+    ]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::f64::consts::PI;
+
+    const SAMPLE_RATE: f64 = 1000.0;
+    const FACTOR: usize = 10;
+    const HALF_ANGLE: f64 = 0.05; // rad, half the cone angle
+    const CONING_RATE: f64 = 2.0 * PI * 10.0; // rad/s the cone axis sweeps round at
+
+    /// Body to world orientation of classical coning motion at `t`, [w, x, y, z]: the body Z axis
+    /// traces a cone about world Z without the body turning about its own axis on average
+    fn coning_attitude(t: f64) -> [f64; 4] {
+        let phase = CONING_RATE * t;
+        [HALF_ANGLE.cos(), HALF_ANGLE.sin() * phase.cos(), HALF_ANGLE.sin() * phase.sin(), 0.0]
+    }
+
+    /// Body axis rotation vector taking the attitude at `from` to the one at `to`
+    fn rotation_between(from: f64, to: f64) -> [f64; 3] {
+        let a = coning_attitude(from);
+        let b = coning_attitude(to);
+        // conj(a) * b
+        let w = a[0] * b[0] + a[1] * b[1] + a[2] * b[2] + a[3] * b[3];
+        let v = [
+            a[0] * b[1] - a[1] * b[0] - a[2] * b[3] + a[3] * b[2],
+            a[0] * b[2] - a[2] * b[0] - a[3] * b[1] + a[1] * b[3],
+            a[0] * b[3] - a[3] * b[0] - a[1] * b[2] + a[2] * b[1],
+        ];
+        let sin_half = (v[0] * v[0] + v[1] * v[1] + v[2] * v[2]).sqrt();
+        let angle = 2.0 * sin_half.atan2(w);
+        v.map(|c| c * angle / sin_half)
+    }
+
+    /// Mean body rate over the sensor sample period ending at `t`, as an integrating gyro reports it
+    fn coning_gyro(t: f64) -> [f32; 3] {
+        let period = 1.0 / SAMPLE_RATE;
+        let (start, end) = (CONING_RATE * (t - period), CONING_RATE * t);
+        let sin_cone = (2.0 * HALF_ANGLE).sin();
+        let cos_cone = (2.0 * HALF_ANGLE).cos();
+        [
+            sin_cone * (end.cos() - start.cos()) / period,
+            sin_cone * (end.sin() - start.sin()) / period,
+            -CONING_RATE * (1.0 - cos_cone),
+        ]
+        .map(|w| w as f32)
+    }
+
+    fn error(a: [f64; 3], b: [f64; 3]) -> f64 {
+        ((a[0] - b[0]).powi(2) + (a[1] - b[1]).powi(2) + (a[2] - b[2]).powi(2)).sqrt()
+    }
+
+    #[test]
+    fn coning_correction_recovers_the_rotation_over_each_interval() {
+        let mut decimator = Decimator::new(SAMPLE_RATE, SAMPLE_RATE / FACTOR as f64).unwrap();
+        assert_eq!(decimator.factor(), FACTOR);
+
+        let mut corrected_error: f64 = 0.0;
+        let mut uncorrected_error: f64 = 0.0;
+        let mut summed = [0.0; 3];
+        let mut intervals = 0;
+        for k in 1..=20 * FACTOR {
+            let t = k as f64 / SAMPLE_RATE;
+            let gyro = coning_gyro(t);
+            for (sum, w) in summed.iter_mut().zip(gyro) {
+                *sum += w as f64 / SAMPLE_RATE;
+            }
+            let Some(sample) = decimator.push([0.0, 0.0, 9.8], gyro, Duration::from_secs_f64(t)) else {
+                continue;
+            };
+
+            let expected = rotation_between(t - FACTOR as f64 / SAMPLE_RATE, t);
+            corrected_error = corrected_error.max(error(sample.delta_angle.map(|a| a as f64), expected));
+            uncorrected_error = uncorrected_error.max(error(summed, expected));
+            summed = [0.0; 3];
+            intervals += 1;
+        }
+        assert_eq!(intervals, 20);
+
+        // The summed rates miss the rotation the cone adds about body Z, the correction picks up all
+        // but the truncation error of a first order algorithm at 10 samples per interval
+        assert!(corrected_error < 5e-6, "{}", corrected_error);
+        assert!(uncorrected_error > 50.0 * corrected_error, "{} vs {}", uncorrected_error, corrected_error);
+    }
+}
@@ -0,0 +1,77 @@
+//! Errors from the IMU publisher and its tools.
+
+use crate::registers::Register;
+use thiserror::Error;
+
+#[derive(Debug, Error)]
+pub enum ImuError {
+    /// The SPI device couldn't be opened or configured
+    #[error("Failed to open SPI device {path}: {source}")]
+    Open { path: String, source: std::io::Error },
+
+    /// A bus transfer failed, usually transient (noise, a loose connector)
+    #[error("SPI transfer failed: {0}")]
+    Spi(String),
+
+    /// The driver reported an error, which comes from the SPI bus underneath
+    #[error("IMU driver error: {0}")]
+    Driver(String),
+
+    /// Something answered but it isn't the expected chip
+    #[error("Unexpected {device} id 0x{found:02X}, expected 0x{expected:02X}")]
+    WrongDevice { device: &'static str, found: u8, expected: u8 },
+
+    /// The magnetometer on the auxiliary I2C bus didn't respond
+    #[error("Auxiliary I2C: {0}")]
+    AuxI2c(String),
+
+    /// A configuration register didn't hold the value just written to it
+    #[error("{register:?} reads back 0x{found:02X} after writing 0x{written:02X}")]
+    Readback { register: Register, written: u8, found: u8 },
+
+    /// The FIFO filled up before it was read and samples were lost
+    #[error("IMU FIFO overflowed, samples were lost")]
+    FifoOverflow,
+
+    /// Read before `initialize` succeeded
+    #[error("IMU not initialized")]
+    NotInitialized,
+
+    /// An invalid setting, from a parameter or the command line
+    #[error("Invalid configuration: {0}")]
+    Config(String),
+
+    /// Reading, writing or fitting a calibration
+    #[error("Calibration: {0}")]
+    Calibration(String),
+
+    #[error("ROS: {0}")]
+    Ros(String),
+}
+
+impl ImuError {
+    /// Whether retrying the same operation, or reinitializing the sensor, may clear the fault. A FIFO
+    /// overflow has already been cleared by the read that found it.
+    pub fn is_transient(&self) -> bool {
+        matches!(
+            self,
+            ImuError::Spi(_)
+                | ImuError::Driver(_)
+                | ImuError::AuxI2c(_)
+                | ImuError::Readback { .. }
+                | ImuError::NotInitialized
+        )
+    }
+}
+
+impl From<rclrs::RclrsError> for ImuError {
+    fn from(err: rclrs::RclrsError) -> Self {
+        ImuError::Ros(format!("{:?}", err))
+    }
+}
+
+impl From<rclrs::DeclarationError> for ImuError {
+    fn from(err: rclrs::DeclarationError) -> Self {
+        ImuError::Ros(format!("{:?}", err))
+    }
+}
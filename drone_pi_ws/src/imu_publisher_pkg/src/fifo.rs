@@ -0,0 +1,309 @@
+//! ICM-20948 FIFO readout.
+//!
+//! Reading the accelerometer and gyroscope registers separately gives samples from different
+//! instants, and whatever the sensor produced between two polls is lost. Instead both sensors run at
+//! as close to the same output data rate as their dividers allow, and each gyro sample is written
+//! into the FIFO with the latest accel sample as one 12 byte frame (accel XYZ then gyro XYZ,
+//! big-endian), which is drained in bursts. Frames are timestamped by counting
+//! sample periods, so their spacing is the sensor's own ODR rather than when we got round to reading.
+
+use crate::error::ImuError;
+use crate::imu_source::{ImuFrame, GRAVITY};
+use crate::registers::*;
+use embedded_hal::spi::SpiBus;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Accel XYZ and gyro XYZ, two bytes each
+pub const FIFO_FRAME_LEN: usize = 12;
+
+/// Internal sample clock the gyro ODR divider counts down from. The FIFO takes a frame at every gyro
+/// sample, so this is also what the frame rate follows.
+pub const GYRO_BASE_SAMPLE_RATE_HZ: f64 = 1100.0;
+
+/// Internal sample clock the accel ODR divider counts down from
+pub const ACCEL_BASE_SAMPLE_RATE_HZ: f64 = 1125.0;
+
+/// Upper bound on one burst so a backed up FIFO doesn't hold the bus for too long. A full 512 byte
+/// FIFO holds 42 frames.
+const MAX_FRAMES_PER_READ: usize = 32;
+
+/// Fraction of the timestamp error corrected per read, slow enough to average out read latency
+const CLOCK_CORRECTION_GAIN: f64 = 0.01;
+
+/// Timestamp error (in sample periods) beyond which the clock is resynchronized instead of steered
+const CLOCK_RESYNC_PERIODS: f64 = 10.0;
+
+const USER_CTRL_FIFO_EN: u8 = 0x40;
+const FIFO_EN_2_ACCEL: u8 = 0x10;
+const FIFO_EN_2_GYRO_XYZ: u8 = 0x0E;
+const FIFO_RST_ALL: u8 = 0x1F;
+const FIFO_MODE_STREAM: u8 = 0x00;
+const INT_STATUS_2_FIFO_OVERFLOW: u8 = 0x1F;
+
+#[derive(Clone, Debug)]
+pub struct FifoConfig {
+    pub sample_rate_divider: u8, // GYRO_SMPLRT_DIV, frames arrive at 1100 Hz / (1 + divider)
+    pub accel_scale: f32,        // m/s^2 per LSB
+    pub gyro_scale: f32,         // rad/s per LSB
+}
+
+impl Default for FifoConfig {
+    fn default() -> Self {
+        // The driver's default ranges, +-2 g and +-250 dps
+        Self {
+            sample_rate_divider: 0,
+            accel_scale: (GRAVITY / 16384.0) as f32,
+            gyro_scale: (250.0f64.to_radians() / 32768.0) as f32,
+        }
+    }
+}
+
+impl FifoConfig {
+    pub fn sample_rate_hz(&self) -> f64 {
+        GYRO_BASE_SAMPLE_RATE_HZ / (1.0 + self.sample_rate_divider as f64)
+    }
+}
+
+/// Synchronized accel/gyro frames from the ICM-20948 FIFO
+pub struct Fifo {
+    config: FifoConfig,
+    clock: FrameClock,
+    overflows: u64,
+}
+
+impl Fifo {
+    /// Start streaming accel and gyro into the FIFO. Both must already be set to the ODR and ranges
+    /// in `config` (see `SensorConfig::apply`).
+    pub fn initialize<B: SpiBus>(registers: &Registers<B>, config: FifoConfig) -> Result<Self, ImuError> {
+        registers.write(FIFO_EN_1, 0)?; // No auxiliary I2C data, the magnetometer is read separately
+        registers.write(FIFO_EN_2, FIFO_EN_2_ACCEL | FIFO_EN_2_GYRO_XYZ)?;
+        registers.write(FIFO_MODE, FIFO_MODE_STREAM)?;
+        registers.modify(USER_CTRL, USER_CTRL_FIFO_EN, USER_CTRL_FIFO_EN)?;
+        reset(registers)?;
+
+        let period = 1.0 / config.sample_rate_hz();
+        Ok(Self {
+            config,
+            clock: FrameClock::new(period),
+            overflows: 0,
+        })
+    }
+
+    /// Output data rate in Hz
+    pub fn sample_rate_hz(&self) -> f64 {
+        self.config.sample_rate_hz()
+    }
+
+    /// Number of times the FIFO overflowed and samples were lost
+    pub fn overflows(&self) -> u64 {
+        self.overflows
+    }
+
+    /// Drain the frames written since the last read, oldest first
+    pub fn read<B: SpiBus>(&mut self, registers: &Registers<B>) -> Result<Vec<ImuFrame>, ImuError> {
+        // After an overflow the oldest frames are gone and the FIFO may no longer start on a frame
+        // boundary, so start again from empty
+        if registers.read(INT_STATUS_2)? & INT_STATUS_2_FIFO_OVERFLOW != 0 {
+            self.overflows += 1;
+            reset(registers)?;
+            self.clock.resync();
+            return Err(ImuError::FifoOverflow);
+        }
+
+        let mut count_bytes = [0u8; 2];
+        registers.read_burst(FIFO_COUNTH, &mut count_bytes)?;
+        let count = (((count_bytes[0] & 0x1F) as usize) << 8) | count_bytes[1] as usize;
+
+        let frames = (count / FIFO_FRAME_LEN).min(MAX_FRAMES_PER_READ);
+        if frames == 0 {
+            return Ok(Vec::new());
+        }
+
+        let mut buffer = vec![0u8; frames * FIFO_FRAME_LEN];
+        registers.read_burst(FIFO_R_W, &mut buffer)?;
+        let backlog = count / FIFO_FRAME_LEN - frames; // Left for the next read
+
+        let stamps = self.clock.stamp(frames, backlog);
+        Ok(buffer
+            .chunks_exact(FIFO_FRAME_LEN)
+            .zip(stamps)
+            .map(|(frame, timestamp)| {
+                let value = |i: usize| i16::from_be_bytes([frame[2 * i], frame[2 * i + 1]]) as f32;
+                ImuFrame {
+                    accel: [0, 1, 2].map(|i| value(i) * self.config.accel_scale),
+                    gyro: [3, 4, 5].map(|i| value(i) * self.config.gyro_scale),
+                    timestamp,
+                }
+            })
+            .collect())
+    }
+}
+
+/// Empty the FIFO
+fn reset<B: SpiBus>(registers: &Registers<B>) -> Result<(), ImuError> {
+    registers.write(FIFO_RST, FIFO_RST_ALL)?;
+    registers.write(FIFO_RST, 0)
+}
+
+/// Timestamps frames one sample period apart, kept in line with the host clock.
+///
+/// The sensor's oscillator isn't exactly the nominal rate, so counting periods alone would drift
+/// away from the host clock. The newest frame of each read was sampled, on average, half a period
+/// before the read; the error from that is fed back slowly, and the clock jumps straight to the
+/// host clock if it's wildly off (startup, overflow, a long stall).
+pub struct FrameClock {
+    period: f64,       // s
+    next: Option<f64>, // Time of the next frame, s since the UNIX epoch
+}
+
+impl FrameClock {
+    pub fn new(period: f64) -> Self {
+        Self { period, next: None }
+    }
+
+    /// Forget the timeline, the next read starts it again from the host clock
+    pub fn resync(&mut self) {
+        self.next = None;
+    }
+
+    /// Timestamps for `frames` frames just read, with `backlog` more still waiting in the FIFO
+    pub fn stamp(&mut self, frames: usize, backlog: usize) -> Vec<Duration> {
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs_f64();
+
+        // Where the newest frame read should be in time
+        let expected_last = now - (backlog as f64 + 0.5) * self.period;
+        let first = match self.next {
+            Some(next) => {
+                let error = expected_last - (next + (frames - 1) as f64 * self.period);
+                if error.abs() > CLOCK_RESYNC_PERIODS * self.period {
+                    expected_last - (frames - 1) as f64 * self.period
+                } else {
+                    next + CLOCK_CORRECTION_GAIN * error
+                }
+            }
+            None => expected_last - (frames - 1) as f64 * self.period,
+        };
+
+        self.next = Some(first + frames as f64 * self.period);
+        (0..frames)
+            .map(|i| Duration::from_secs_f64(first + i as f64 * self.period))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock_spi::{MockIcm20948, MOCK_FIFO_SIZE};
+    use std::sync::{Arc, Mutex};
+
+    /// Largest stamp spacing error, s. Stamps are f64 seconds since the epoch, good to about 0.25 us.
+    const STAMP_TOLERANCE: f64 = 1e-6;
+
+    fn streaming_fifo() -> (Arc<Mutex<MockIcm20948>>, Registers<MockIcm20948>, Fifo) {
+        let device = Arc::new(Mutex::new(MockIcm20948::new()));
+        let registers = Registers::new(Arc::clone(&device));
+        let fifo = Fifo::initialize(&registers, FifoConfig::default()).unwrap();
+        (device, registers, fifo)
+    }
+
+    /// Frame `n` in raw counts, every axis different so mixed up frames or axes show
+    fn counts(n: i16) -> ([i16; 3], [i16; 3]) {
+        ([n, n + 100, n + 200], [-n, -n - 100, -n - 200])
+    }
+
+    fn push(device: &Mutex<MockIcm20948>, frames: std::ops::Range<i16>) {
+        let mut device = device.lock().unwrap();
+        for n in frames {
+            let (accel, gyro) = counts(n);
+            device.push_frame(accel, gyro);
+        }
+    }
+
+    fn assert_frame(frame: &ImuFrame, n: i16) {
+        let config = FifoConfig::default();
+        let (accel, gyro) = counts(n);
+        assert_eq!(frame.accel, accel.map(|c| c as f32 * config.accel_scale), "frame {}", n);
+        assert_eq!(frame.gyro, gyro.map(|c| c as f32 * config.gyro_scale), "frame {}", n);
+    }
+
+    fn seconds(stamp: Duration) -> f64 {
+        stamp.as_secs_f64()
+    }
+
+    #[test]
+    fn frames_decode_with_accel_and_gyro_from_the_same_sample() {
+        let (device, registers, mut fifo) = streaming_fifo();
+        push(&device, 1..6);
+        let frames = fifo.read(&registers).unwrap();
+        assert_eq!(frames.len(), 5);
+        for (frame, n) in frames.iter().zip(1..) {
+            assert_frame(frame, n);
+        }
+        assert_eq!(device.lock().unwrap().queued_frames(), 0);
+        assert!(fifo.read(&registers).unwrap().is_empty());
+    }
+
+    #[test]
+    fn reads_are_capped_and_the_rest_left_for_the_next() {
+        let (device, registers, mut fifo) = streaming_fifo();
+        let queued = (MOCK_FIFO_SIZE / FIFO_FRAME_LEN) as i16;
+        push(&device, 0..queued);
+
+        let first = fifo.read(&registers).unwrap();
+        assert_eq!(first.len(), MAX_FRAMES_PER_READ);
+        let second = fifo.read(&registers).unwrap();
+        assert_eq!(second.len(), queued as usize - MAX_FRAMES_PER_READ);
+        for (frame, n) in first.iter().chain(&second).zip(0..) {
+            assert_frame(frame, n);
+        }
+
+        // The backlog was accounted for, so the two reads line up on one timeline
+        let period = 1.0 / GYRO_BASE_SAMPLE_RATE_HZ;
+        let gap = seconds(second[0].timestamp) - seconds(first[MAX_FRAMES_PER_READ - 1].timestamp);
+        assert!((gap - period).abs() < CLOCK_CORRECTION_GAIN * period + STAMP_TOLERANCE, "{}", gap);
+    }
+
+    #[test]
+    fn overflow_resets_the_fifo_and_is_reported() {
+        let (device, registers, mut fifo) = streaming_fifo();
+        let capacity = (MOCK_FIFO_SIZE / FIFO_FRAME_LEN) as i16;
+        push(&device, 0..capacity + 1);
+
+        assert!(matches!(fifo.read(&registers), Err(ImuError::FifoOverflow)));
+        assert_eq!(fifo.overflows(), 1);
+        assert_eq!(device.lock().unwrap().queued_frames(), 0);
+
+        // Back on frame boundaries afterwards
+        push(&device, 7..9);
+        let frames = fifo.read(&registers).unwrap();
+        assert_eq!(frames.len(), 2);
+        assert_frame(&frames[0], 7);
+        assert_frame(&frames[1], 8);
+    }
+
+    #[test]
+    fn frame_clock_spaces_stamps_by_the_sample_period() {
+        let period = 1.0 / FifoConfig { sample_rate_divider: 10, ..FifoConfig::default() }.sample_rate_hz();
+        let mut clock = FrameClock::new(period);
+
+        let before = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs_f64();
+        let first = clock.stamp(10, 2);
+        for pair in first.windows(2) {
+            assert!((seconds(pair[1]) - seconds(pair[0]) - period).abs() < STAMP_TOLERANCE, "{:?}", pair);
+        }
+        // The newest frame read was taken, on average, half a period before the two still waiting
+        let newest = seconds(first[9]);
+        assert!((before - 2.5 * period - newest).abs() < 1e-3, "{} {}", before, newest);
+
+        // The next read carries on a period after the last stamp, steered only slightly towards
+        // the host clock
+        let second = clock.stamp(4, 0);
+        for pair in second.windows(2) {
+            assert!((seconds(pair[1]) - seconds(pair[0]) - period).abs() < STAMP_TOLERANCE, "{:?}", pair);
+        }
+        let gap = seconds(second[0]) - newest;
+        let steering = CLOCK_CORRECTION_GAIN * CLOCK_RESYNC_PERIODS * period;
+        assert!((gap - period).abs() < steering + STAMP_TOLERANCE, "{}", gap);
+    }
+}
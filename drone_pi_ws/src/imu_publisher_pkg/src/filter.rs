@@ -0,0 +1,215 @@
+//! Butterworth low-pass filtering of three-axis samples.
+//!
+//! Orders above two are built as cascaded biquads. Each second-order section gets the Q of one of
+//! the Butterworth pole pairs, and odd orders add a first-order section, so the cascade has the
+//! maximally flat response of a single high-order Butterworth filter.
+
+use crate::error::ImuError;
+use biquad::{Biquad, Coefficients, DirectForm1, ToHertz};
+use std::f32::consts::PI;
+
+/// Highest supported order, beyond this the sections get too sharp to be stable in f32
+pub const MAX_ORDER: usize = 8;
+
+/// Passes of the first sample used to settle the filter state after a (re)start
+const PRIME_ITERATIONS: usize = 1000;
+
+/// Relative difference allowed between the filter's design rate and the sensor's, to allow for the
+/// ODR being a fraction that doesn't print exactly
+const SAMPLE_RATE_TOLERANCE: f32 = 1e-3;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct FilterSettings {
+    pub sample_rate_hz: f32,
+    pub cutoff_hz: f32,
+    pub order: usize,
+}
+
+impl FilterSettings {
+    /// Check the filter is designed for `sensor_rate_hz`, the rate samples actually arrive at. At
+    /// any other rate its cutoff lands somewhere else.
+    pub fn check_sample_rate(&self, sensor_rate_hz: f32) -> Result<(), ImuError> {
+        if (self.sample_rate_hz - sensor_rate_hz).abs() > SAMPLE_RATE_TOLERANCE * sensor_rate_hz {
+            return Err(ImuError::Config(format!(
+                "Filter sample rate {} Hz doesn't match the IMU's output data rate ({} Hz)",
+                self.sample_rate_hz, sensor_rate_hz
+            )));
+        }
+        Ok(())
+    }
+
+    /// Coefficients for each section of the cascade
+    pub fn sections(&self) -> Result<Vec<Coefficients<f32>>, ImuError> {
+        if self.order == 0 || self.order > MAX_ORDER {
+            return Err(ImuError::Config(format!("Filter order must be between 1 and {}, got {}", MAX_ORDER, self.order)));
+        }
+        if self.cutoff_hz <= 0.0 || self.cutoff_hz >= self.sample_rate_hz / 2.0 {
+            return Err(ImuError::Config(format!(
+                "Cutoff {} Hz must be between 0 and the Nyquist frequency ({} Hz)",
+                self.cutoff_hz,
+                self.sample_rate_hz / 2.0
+            )));
+        }
+
+        // Pole pair k of an order n Butterworth filter sits (2k - 1) * pi / 2n from the imaginary axis
+        let n = self.order as f32;
+        let mut sections = Vec::new();
+        for k in 1..=self.order / 2 {
+            let angle = (2 * k - 1) as f32 * PI / (2.0 * n);
+            let q = 1.0 / (2.0 * angle.sin());
+            let coeffs = Coefficients::<f32>::from_params(
+                biquad::Type::LowPass,
+                self.sample_rate_hz.hz(),
+                self.cutoff_hz.hz(),
+                q,
+            )
+            .map_err(|e| ImuError::Config(format!("Invalid filter section: {:?}", e)))?;
+            sections.push(coeffs);
+        }
+
+        // The real pole of an odd order filter, bilinear transformed
+        if self.order % 2 == 1 {
+            let k = (PI * self.cutoff_hz / self.sample_rate_hz).tan();
+            sections.push(Coefficients {
+                a1: (k - 1.0) / (k + 1.0),
+                a2: 0.0,
+                b0: k / (k + 1.0),
+                b1: k / (k + 1.0),
+                b2: 0.0,
+            });
+        }
+
+        Ok(sections)
+    }
+}
+
+/// Independent low-pass filter cascades for the x, y and z axes
+pub struct LowPassFilter {
+    axes: [Vec<DirectForm1<f32>>; 3],
+    primed: bool,
+}
+
+impl LowPassFilter {
+    pub fn new(settings: &FilterSettings) -> Result<Self, ImuError> {
+        let sections = settings.sections()?;
+        let cascade = || sections.iter().map(|coeffs| DirectForm1::<f32>::new(*coeffs)).collect::<Vec<_>>();
+        Ok(Self {
+            axes: [cascade(), cascade(), cascade()],
+            primed: false,
+        })
+    }
+
+    /// Filter one sample
+    pub fn run(&mut self, sample: [f32; 3]) -> [f32; 3] {
+        // Starting from zero state would ramp up from 0, e.g. a 1 g step on the accelerometer's Z
+        // axis. Settle on the first sample instead, as if it had been held forever.
+        if !self.primed {
+            for (cascade, value) in self.axes.iter_mut().zip(sample.iter()) {
+                for _ in 0..PRIME_ITERATIONS {
+                    run_cascade(cascade, *value);
+                }
+            }
+            self.primed = true;
+        }
+
+        let mut filtered = [0.0; 3];
+        for ((cascade, value), output) in self.axes.iter_mut().zip(sample.iter()).zip(filtered.iter_mut()) {
+            *output = run_cascade(cascade, *value);
+        }
+        filtered
+    }
+
+    /// Forget the filter state, the next sample primes it again
+    pub fn reset(&mut self) {
+        for section in self.axes.iter_mut().flatten() {
+            section.reset_state();
+        }
+        self.primed = false;
+    }
+}
+
+fn run_cascade(cascade: &mut [DirectForm1<f32>], input: f32) -> f32 {
+    cascade.iter_mut().fold(input, |value, section| section.run(value))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SAMPLE_RATE: f32 = 1000.0;
+    const CUTOFF: f32 = 50.0;
+    const TOLERANCE: f32 = 1e-4;
+
+    fn settings(order: usize) -> FilterSettings {
+        FilterSettings {
+            sample_rate_hz: SAMPLE_RATE,
+            cutoff_hz: CUTOFF,
+            order,
+        }
+    }
+
+    /// Amplitude a sine at `frequency` comes out with, from its RMS over a second after settling
+    fn gain(order: usize, frequency: f32) -> f32 {
+        let mut filter = LowPassFilter::new(&settings(order)).unwrap();
+        let samples = SAMPLE_RATE as usize;
+        let mut sum_squares = 0.0;
+        for i in 0..2 * samples {
+            let value = (2.0 * PI * frequency * i as f32 / SAMPLE_RATE).sin();
+            let output = filter.run([value, 0.0, 0.0])[0];
+            if i >= samples {
+                sum_squares += output * output;
+            }
+        }
+        (2.0 * sum_squares / samples as f32).sqrt()
+    }
+
+    #[test]
+    fn response_is_3_db_down_at_the_cutoff_whatever_the_order() {
+        for order in 1..=4 {
+            assert!((gain(order, 2.0) - 1.0).abs() < 0.01, "order {}", order);
+            let at_cutoff = gain(order, CUTOFF);
+            assert!((at_cutoff - std::f32::consts::FRAC_1_SQRT_2).abs() < 0.01, "order {}: {}", order, at_cutoff);
+        }
+    }
+
+    #[test]
+    fn attenuation_above_the_cutoff_steepens_with_the_order() {
+        // An analog Butterworth filter passes 1 / sqrt(1 + 4^2n) two octaves up, and the bilinear
+        // transform only squeezes the stopband further
+        for order in 1..=4 {
+            let attenuated = gain(order, 4.0 * CUTOFF);
+            let analog = 1.0 / (1.0 + 4.0f32.powi(2 * order as i32)).sqrt();
+            assert!(attenuated < analog, "order {}: {} > {}", order, attenuated, analog);
+        }
+    }
+
+    #[test]
+    fn filter_primes_on_its_first_sample_and_again_after_a_reset() {
+        let mut filter = LowPassFilter::new(&settings(4)).unwrap();
+        // A 1 g accelerometer comes out at 1 g straight away rather than ramping up from 0
+        for _ in 0..10 {
+            let output = filter.run([0.1, -0.2, 9.81]);
+            assert!(output.iter().zip([0.1, -0.2, 9.81]).all(|(a, b)| (a - b).abs() < TOLERANCE), "{:?}", output);
+        }
+
+        // After a reset the old state is forgotten rather than filtered towards
+        filter.reset();
+        let output = filter.run([0.0, 0.0, -9.81]);
+        assert!((output[2] + 9.81).abs() < TOLERANCE, "{:?}", output);
+    }
+
+    #[test]
+    fn invalid_settings_are_rejected() {
+        assert!(settings(0).sections().is_err());
+        assert!(settings(MAX_ORDER + 1).sections().is_err());
+        assert!(FilterSettings { cutoff_hz: SAMPLE_RATE / 2.0, ..settings(2) }.sections().is_err());
+        assert_eq!(settings(3).sections().unwrap().len(), 2);
+    }
+
+    #[test]
+    fn sample_rate_must_match_the_sensor() {
+        let third = FilterSettings { sample_rate_hz: 366.7, ..settings(2) };
+        assert!(third.check_sample_rate(1100.0 / 3.0).is_ok());
+        assert!(settings(2).check_sample_rate(1100.0).is_err());
+    }
+}
@@ -0,0 +1,365 @@
+use crate::error::ImuError;
+use crate::calibration::{AccelCalibration, GyroThermalCalibration, MagCalibration};
+use nalgebra::{DMatrix, DVector, Dyn, Matrix3, Vector3, SVD};
+
+/// Singular values of a fit's design matrix below this fraction of the largest mean the data can't
+/// tell its unknowns apart
+const RANK_TOLERANCE: f64 = 1e-9;
+
+/// Fit accelerometer offsets, scale factors and cross-axis misalignment from still poses.
+///
+/// Each pose pairs the averaged raw reading with the specific force the sensor should have measured,
+/// e.g. `[0, 0, +g]` when lying flat with +Z up. The model is `true = A * raw + b`, solved by least
+/// squares one output axis at a time, so at least four non-degenerate poses are needed (six faces
+/// is the usual procedure).
+pub fn fit_accel_calibration(poses: &[([f32; 3], [f32; 3])]) -> Result<AccelCalibration, ImuError> {
+    if poses.len() < 4 {
+        return Err(ImuError::Calibration(format!("Need at least 4 poses, got {}", poses.len())));
+    }
+
+    // Rows are [raw_x, raw_y, raw_z, 1]
+    let design = DMatrix::from_fn(poses.len(), 4, |row, col| {
+        if col < 3 {
+            poses[row].0[col] as f64
+        } else {
+            1.0
+        }
+    });
+    let svd = design.svd(true, true);
+    if !full_rank(&svd, 4) {
+        return Err(ImuError::Calibration(
+            "Poses don't pin down every axis, use at least four different orientations".to_string(),
+        ));
+    }
+
+    let mut a = Matrix3::zeros();
+    let mut b = Vector3::zeros();
+    for axis in 0..3 {
+        let target = DVector::from_fn(poses.len(), |row, _| poses[row].1[axis] as f64);
+        let solution = svd
+            .solve(&target, 1e-9)
+            .map_err(|e| ImuError::Calibration(e.to_string()))?;
+        for col in 0..3 {
+            a[(axis, col)] = solution[col];
+        }
+        b[axis] = solution[3];
+    }
+
+    // Split A into misalignment * diag(scale), with unit diagonal misalignment
+    let a_inv = a
+        .try_inverse()
+        .ok_or_else(|| ImuError::Calibration("Accelerometer fit is singular, check the poses".to_string()))?;
+    let offset = -(a_inv * b);
+    let scale = Vector3::new(a[(0, 0)], a[(1, 1)], a[(2, 2)]);
+    let mut misalignment = [[0.0f32; 3]; 3];
+    for (row, values) in misalignment.iter_mut().enumerate() {
+        for (col, value) in values.iter_mut().enumerate() {
+            *value = (a[(row, col)] / scale[col]) as f32;
+        }
+    }
+
+    Ok(AccelCalibration {
+        offset: [offset[0] as f32, offset[1] as f32, offset[2] as f32],
+        scale: [scale[0] as f32, scale[1] as f32, scale[2] as f32],
+        misalignment,
+    })
+}
+
+/// Fit hard and soft iron correction to magnetometer samples taken while rotating through many
+/// orientations.
+///
+/// The samples are fitted to a general ellipsoid
+/// `a x^2 + b y^2 + c z^2 + 2d xy + 2e xz + 2f yz + 2g x + 2h y + 2i z = 1`.
+/// Its center is the hard iron offset, and the symmetric square root of its shape matrix, scaled by
+/// the mean radius, maps it back onto a sphere so the corrected field keeps its magnitude in uT.
+pub fn fit_mag_calibration(samples: &[[f32; 3]]) -> Result<MagCalibration, ImuError> {
+    if samples.len() < 9 {
+        return Err(ImuError::Calibration(format!("Need at least 9 samples, got {}", samples.len())));
+    }
+
+    let design = DMatrix::from_fn(samples.len(), 9, |row, col| {
+        let [x, y, z] = samples[row].map(|v| v as f64);
+        match col {
+            0 => x * x,
+            1 => y * y,
+            2 => z * z,
+            3 => 2.0 * x * y,
+            4 => 2.0 * x * z,
+            5 => 2.0 * y * z,
+            6 => 2.0 * x,
+            7 => 2.0 * y,
+            _ => 2.0 * z,
+        }
+    });
+    let svd = design.svd(true, true);
+    if !full_rank(&svd, 9) {
+        return Err(ImuError::Calibration(
+            "Samples don't determine an ellipsoid, rotate through more orientations".to_string(),
+        ));
+    }
+    let ones = DVector::from_element(samples.len(), 1.0);
+    let v = svd
+        .solve(&ones, 1e-12)
+        .map_err(|e| ImuError::Calibration(e.to_string()))?;
+
+    let shape = Matrix3::new(
+        v[0], v[3], v[4],
+        v[3], v[1], v[5],
+        v[4], v[5], v[2],
+    );
+    let linear = Vector3::new(v[6], v[7], v[8]);
+
+    let shape_inv = shape
+        .try_inverse()
+        .ok_or_else(|| {
+            ImuError::Calibration("Magnetometer fit is singular, rotate through more orientations".to_string())
+        })?;
+    let center = -(shape_inv * linear);
+
+    // Shift to the center: (m - c)^T shape (m - c) = 1 + c^T shape c
+    let k = 1.0 + (center.transpose() * shape * center)[0];
+    let normalized = shape / k;
+
+    let eigen = normalized.symmetric_eigen();
+    if eigen.eigenvalues.iter().any(|value| *value <= 0.0) {
+        return Err(ImuError::Calibration(
+            "Samples don't form an ellipsoid, rotate through more orientations".to_string(),
+        ));
+    }
+
+    // Geometric mean of the semi-axes, so the corrected sphere keeps the average field strength
+    let radius = eigen.eigenvalues.iter().map(|value| value.powf(-0.5)).product::<f64>().cbrt();
+    let sqrt_values = Matrix3::from_diagonal(&eigen.eigenvalues.map(|value| value.sqrt()));
+    let soft_iron = eigen.eigenvectors * sqrt_values * eigen.eigenvectors.transpose() * radius;
+
+    let mut soft_iron_rows = [[0.0f32; 3]; 3];
+    for (row, values) in soft_iron_rows.iter_mut().enumerate() {
+        for (col, value) in values.iter_mut().enumerate() {
+            *value = soft_iron[(row, col)] as f32;
+        }
+    }
+
+    Ok(MagCalibration {
+        hard_iron: [center[0] as f32, center[1] as f32, center[2] as f32],
+        soft_iron: soft_iron_rows,
+        field_strength: radius as f32,
+    })
+}
+
+/// Fit a polynomial of `order` in temperature to each gyro axis' bias, from `(temperature, mean gyro)`
+/// pairs recorded while the IMU sat still and warmed up.
+///
+/// The polynomial is in temperature relative to the mean of the samples, which keeps the least
+/// squares problem well conditioned. The run has to cover at least `min_span` degrees C, or the
+/// slope is mostly fitting noise.
+pub fn fit_gyro_thermal(
+    samples: &[(f32, [f32; 3])],
+    order: usize,
+    min_span: f32,
+) -> Result<GyroThermalCalibration, ImuError> {
+    if samples.len() <= order {
+        return Err(ImuError::Calibration(format!(
+            "Need more than {} samples for an order {} fit, got {}",
+            order,
+            order,
+            samples.len()
+        )));
+    }
+
+    let (min, max) = samples
+        .iter()
+        .fold((f32::MAX, f32::MIN), |(min, max), (t, _)| (min.min(*t), max.max(*t)));
+    if max - min < min_span {
+        return Err(ImuError::Calibration(format!(
+            "Temperature only changed by {:.1} C, need at least {:.1} C",
+            max - min,
+            min_span
+        )));
+    }
+
+    let reference = samples.iter().map(|(t, _)| *t as f64).sum::<f64>() / samples.len() as f64;
+    let design = DMatrix::from_fn(samples.len(), order + 1, |row, col| {
+        (samples[row].0 as f64 - reference).powi(col as i32)
+    });
+    let svd = design.svd(true, true);
+    if !full_rank(&svd, order + 1) {
+        return Err(ImuError::Calibration(format!(
+            "Too few distinct temperatures for an order {} fit, keep logging while the sensor warms up",
+            order
+        )));
+    }
+
+    let mut coefficients: [Vec<f32>; 3] = Default::default();
+    for (axis, axis_coefficients) in coefficients.iter_mut().enumerate() {
+        let target = DVector::from_fn(samples.len(), |row, _| samples[row].1[axis] as f64);
+        let solution = svd
+            .solve(&target, 1e-12)
+            .map_err(|e| ImuError::Calibration(e.to_string()))?;
+        *axis_coefficients = solution.iter().map(|c| *c as f32).collect();
+    }
+
+    Ok(GyroThermalCalibration {
+        reference_temperature: reference as f32,
+        coefficients,
+        temperature_range: [min, max],
+    })
+}
+
+/// Whether a least squares problem has independent columns, all `columns` of them
+fn full_rank(svd: &SVD<f64, Dyn, Dyn>, columns: usize) -> bool {
+    let largest = svd.singular_values.max();
+    svd.singular_values
+        .iter()
+        .filter(|value| **value > RANK_TOLERANCE * largest)
+        .count()
+        == columns
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::imu_source::GRAVITY;
+
+    /// Relative agreement expected of values that went through f32
+    const TOLERANCE: f32 = 1e-4;
+
+    fn assert_close(actual: &[f32], expected: &[f32]) {
+        for (a, e) in actual.iter().zip(expected) {
+            assert!((a - e).abs() <= TOLERANCE * e.abs().max(1.0), "{:?} != {:?}", actual, expected);
+        }
+    }
+
+    #[test]
+    fn accel_fit_recovers_offset_scale_and_misalignment() {
+        let misalignment = Matrix3::new(1.0, 0.01, -0.02, 0.015, 1.0, 0.005, -0.01, 0.02, 1.0);
+        let scale = [1.02, 0.98, 1.01];
+        let offset = [0.1, -0.2, 0.15];
+        let a = misalignment * Matrix3::from_diagonal(&Vector3::from(scale));
+        let a_inv = a.try_inverse().unwrap();
+
+        // The six faces, raw readings being what the miscalibrated sensor reports: raw = A^-1 true + offset
+        let g = GRAVITY as f32;
+        let faces = [[g, 0.0, 0.0], [-g, 0.0, 0.0], [0.0, g, 0.0], [0.0, -g, 0.0], [0.0, 0.0, g], [0.0, 0.0, -g]];
+        let poses: Vec<([f32; 3], [f32; 3])> = faces
+            .iter()
+            .map(|truth| {
+                let raw = a_inv * Vector3::from(truth.map(|v| v as f64)) + Vector3::from(offset);
+                (raw.map(|v| v as f32).into(), *truth)
+            })
+            .collect();
+
+        let calibration = fit_accel_calibration(&poses).unwrap();
+        assert_close(&calibration.offset, &offset.map(|v| v as f32));
+        assert_close(&calibration.scale, &scale.map(|v| v as f32));
+        for row in 0..3 {
+            let expected = [0, 1, 2].map(|col| misalignment[(row, col)] as f32);
+            assert_close(&calibration.misalignment[row], &expected);
+        }
+        for (raw, truth) in &poses {
+            assert_close(&calibration.apply(*raw), truth);
+        }
+    }
+
+    #[test]
+    fn accel_fit_from_one_orientation_is_an_error() {
+        let poses = vec![([0.1, -0.05, 9.9], [0.0, 0.0, GRAVITY as f32]); 6];
+        assert!(matches!(fit_accel_calibration(&poses), Err(ImuError::Calibration(_))));
+    }
+
+    /// `count` directions spread evenly over the sphere
+    fn sphere(count: usize) -> Vec<Vector3<f64>> {
+        let golden_angle = std::f64::consts::PI * (3.0 - 5.0f64.sqrt());
+        (0..count)
+            .map(|i| {
+                let z = 1.0 - 2.0 * (i as f64 + 0.5) / count as f64;
+                let r = (1.0 - z * z).sqrt();
+                let angle = golden_angle * i as f64;
+                Vector3::new(r * angle.cos(), r * angle.sin(), z)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn mag_fit_maps_the_ellipsoid_back_onto_a_sphere() {
+        let field = 50.0; // uT
+        let hard_iron = Vector3::new(12.0, -8.0, 25.0);
+        // Symmetric soft iron distortion, so the fit's symmetric correction undoes it exactly
+        let distortion = Matrix3::new(1.1, 0.05, 0.02, 0.05, 0.95, -0.03, 0.02, -0.03, 1.05);
+        let directions = sphere(200);
+        let samples: Vec<[f32; 3]> = directions
+            .iter()
+            .map(|u| (distortion * u * field + hard_iron).map(|v| v as f32).into())
+            .collect();
+
+        let calibration = fit_mag_calibration(&samples).unwrap();
+        assert_close(&calibration.hard_iron, &[12.0, -8.0, 25.0]);
+        // Radius of the sphere with the ellipsoid's volume
+        let expected_strength = (field * distortion.determinant().cbrt()) as f32;
+        assert!((calibration.field_strength / expected_strength - 1.0).abs() < TOLERANCE);
+
+        for (sample, u) in samples.iter().zip(&directions) {
+            let expected = (u * expected_strength as f64).map(|v| v as f32);
+            assert_close(&calibration.apply(*sample), &[expected[0], expected[1], expected[2]]);
+        }
+    }
+
+    #[test]
+    fn mag_fit_without_enough_orientations_is_an_error() {
+        let one_orientation = vec![[20.0, -5.0, -40.0]; 50];
+        assert!(matches!(fit_mag_calibration(&one_orientation), Err(ImuError::Calibration(_))));
+
+        // Turned about Z only, so the samples lie on one circle
+        let flat: Vec<[f32; 3]> = (0..50)
+            .map(|i| {
+                let angle = i as f32 * 0.2;
+                [30.0 * angle.cos() + 5.0, 30.0 * angle.sin(), -40.0]
+            })
+            .collect();
+        assert!(matches!(fit_mag_calibration(&flat), Err(ImuError::Calibration(_))));
+    }
+
+    /// Gyro bias (rad/s) drifting quadratically with temperature
+    fn thermal_bias(temperature: f32) -> [f32; 3] {
+        let dt = temperature - 25.0;
+        [
+            0.01 + 2e-4 * dt + 3e-6 * dt * dt,
+            -0.02 - 1e-4 * dt,
+            0.005 + 5e-5 * dt - 2e-6 * dt * dt,
+        ]
+    }
+
+    #[test]
+    fn thermal_fit_recovers_the_bias_curve() {
+        // A warm-up run from 20 to 50 C, not centred on the model's 25 C
+        let samples: Vec<(f32, [f32; 3])> = (0..=300)
+            .map(|i| {
+                let temperature = 20.0 + i as f32 * 0.1;
+                (temperature, thermal_bias(temperature))
+            })
+            .collect();
+
+        let calibration = fit_gyro_thermal(&samples, 2, 10.0).unwrap();
+        assert_eq!(calibration.temperature_range, [20.0, 50.0]);
+        for temperature in [20.0, 27.5, 35.0, 42.5, 50.0] {
+            assert_close(&calibration.bias(temperature), &thermal_bias(temperature));
+        }
+        // Held at the edges of the warm-up run
+        assert_close(&calibration.bias(10.0), &thermal_bias(20.0));
+        assert_close(&calibration.bias(60.0), &thermal_bias(50.0));
+    }
+
+    #[test]
+    fn thermal_fit_with_too_few_temperatures_is_an_error() {
+        // Plenty of samples and span, but only two temperatures to fit a quadratic through
+        let samples: Vec<(f32, [f32; 3])> = (0..100)
+            .map(|i| {
+                let temperature = [20.0, 40.0][i % 2];
+                (temperature, thermal_bias(temperature))
+            })
+            .collect();
+        assert!(matches!(fit_gyro_thermal(&samples, 2, 10.0), Err(ImuError::Calibration(_))));
+        assert!(fit_gyro_thermal(&samples, 1, 10.0).is_ok());
+
+        assert!(matches!(fit_gyro_thermal(&samples[..2], 2, 0.0), Err(ImuError::Calibration(_))));
+    }
+}
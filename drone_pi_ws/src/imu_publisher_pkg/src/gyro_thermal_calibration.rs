@@ -0,0 +1,136 @@
+//! Gyroscope bias temperature calibration.
+//!
+//! Records the gyro bias against die temperature while the IMU sits still and warms up, fits a
+//! polynomial to each axis, and writes the model to the sensor's calibration file where
+//! `imu_publisher` uses it to follow the bias as the board heats up. Start with the IMU cold (powered
+//! off for a while) and keep it still for the whole run; any second with motion in it is discarded.
+//!
+//! Usage: gyro_thermal_calibration [--source icm20948|synthetic] [--device /dev/spidev0.0]
+//!                                 [--duration 600] [--order 2] [--calibration-dir DIR]
+use imu_publisher_pkg::calibration::{self, CalibrationFile};
+use imu_publisher_pkg::fitting::fit_gyro_thermal;
+use imu_publisher_pkg::imu_source;
+use imu_publisher_pkg::sensor_config::SensorConfig;
+use std::io::{self, BufRead, Write};
+use std::path::PathBuf;
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// Warm-up the run must cover for the fit to mean anything, degrees C
+const MIN_TEMPERATURE_SPAN: f32 = 3.0;
+
+/// Gyro standard deviation within a one second block above which the IMU was moved, rad/s
+const MAX_GYRO_STD: f32 = 0.02;
+
+fn main() -> Result<(), Box<dyn std::error::Error>> {
+    let mut source_kind = "icm20948".to_string();
+    let mut device = "/dev/spidev0.0".to_string();
+    let mut duration = 600.0;
+    let mut order = 2;
+    let mut calibration_dir = calibration::default_calibration_dir();
+
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        let mut value = || args.next().ok_or(format!("Missing value for {}", arg));
+        match arg.as_str() {
+            "--source" => source_kind = value()?,
+            "--device" => device = value()?,
+            "--duration" => duration = value()?.parse()?,
+            "--order" => order = value()?.parse()?,
+            "--calibration-dir" => calibration_dir = PathBuf::from(value()?),
+            _ => return Err(format!("Unknown argument {}", arg).into()),
+        }
+    }
+
+    let mut source = imu_source::open_source(&source_kind, &device, SensorConfig::default())?;
+    source.initialize()?;
+    if source.read_temperature()?.is_none() {
+        return Err(format!("The {} source has no temperature sensor", source_kind).into());
+    }
+
+    print!("Press Enter, then leave the IMU still for {} s while it warms up ", duration);
+    io::stdout().flush()?;
+    io::stdin().lock().read_line(&mut String::new())?;
+
+    // One (mean temperature, mean gyro) sample per second, the bias moves far slower than that
+    let mut samples = Vec::new();
+    let mut discarded = 0;
+    let start = Instant::now();
+    let mut block_start = Instant::now();
+    let mut gyro_sum = [0.0f64; 3];
+    let mut gyro_sum_sq = [0.0f64; 3];
+    let mut gyro_count = 0;
+    let mut temperature_sum = 0.0f64;
+    let mut temperature_count = 0;
+    while start.elapsed().as_secs_f64() < duration {
+        for frame in source.read_frames()? {
+            for axis in 0..3 {
+                let value = frame.gyro[axis] as f64;
+                gyro_sum[axis] += value;
+                gyro_sum_sq[axis] += value * value;
+            }
+            gyro_count += 1;
+        }
+        if let Some(temperature) = source.read_temperature()? {
+            temperature_sum += temperature as f64;
+            temperature_count += 1;
+        }
+
+        if block_start.elapsed() >= Duration::from_secs(1) && gyro_count > 0 && temperature_count > 0 {
+            let n = gyro_count as f64;
+            let mean = gyro_sum.map(|sum| sum / n);
+            let still = (0..3).all(|axis| {
+                let variance = (gyro_sum_sq[axis] / n - mean[axis] * mean[axis]).max(0.0);
+                variance.sqrt() as f32 <= MAX_GYRO_STD
+            });
+            let temperature = (temperature_sum / temperature_count as f64) as f32;
+            if still {
+                samples.push((temperature, mean.map(|value| value as f32)));
+            } else {
+                discarded += 1;
+            }
+            println!(
+                "  {} s, {:.2} C, {} samples, {} discarded for motion",
+                start.elapsed().as_secs(),
+                temperature,
+                samples.len(),
+                discarded
+            );
+
+            block_start = Instant::now();
+            gyro_sum = [0.0; 3];
+            gyro_sum_sq = [0.0; 3];
+            gyro_count = 0;
+            temperature_sum = 0.0;
+            temperature_count = 0;
+        }
+        thread::sleep(Duration::from_millis(5));
+    }
+
+    let thermal = fit_gyro_thermal(&samples, order, MIN_TEMPERATURE_SPAN)?;
+    println!("Reference temperature: {:.2} C", thermal.reference_temperature);
+    println!("Temperature range:     {:?} C", thermal.temperature_range);
+    for (axis, coefficients) in ["x", "y", "z"].iter().zip(&thermal.coefficients) {
+        println!("Gyro {} coefficients:   {:?}", axis, coefficients);
+    }
+
+    // How much of the bias the model leaves behind, as a sanity check of the fit
+    let residuals: Vec<f32> = samples
+        .iter()
+        .flat_map(|(temperature, gyro)| {
+            let model = thermal.bias(*temperature);
+            (0..3).map(move |axis| gyro[axis] - model[axis])
+        })
+        .collect();
+    let rms = (residuals.iter().map(|r| r * r).sum::<f32>() / residuals.len() as f32).sqrt();
+    println!("Residual: {:.5} rad/s RMS", rms);
+
+    // Keep any other calibration already stored for this sensor
+    let path = calibration::calibration_path(&calibration_dir, &source.sensor_id());
+    let mut file = CalibrationFile::load(&path)?.unwrap_or_default();
+    file.gyro_thermal = Some(thermal);
+    file.save(&path)?;
+    println!("Saved gyro thermal calibration to {}", path.display());
+
+    Ok(())
+}
@@ -0,0 +1,306 @@
+//! IMU fault tracking.
+//!
+//! A single failed SPI read is usually noise on the bus and the next one succeeds, so reads are
+//! retried before a loop gives up on them. When reads keep failing the sensor may have reset or
+//! browned out and lost its configuration, so it's reinitialized every `reinit_after` failed loops.
+//! After `unhealthy_after` failed loops in a row the sensor is declared unhealthy, and stays that way
+//! until a read succeeds again. A FIFO overflow isn't a fault of the sensor and isn't retried: the
+//! FIFO has just been emptied, so a retry would read nothing and hide the gap. It's counted instead.
+
+use crate::error::ImuError;
+use std::time::{Duration, Instant};
+
+/// Least time between logged failures of a secondary sensor's reads
+const SECONDARY_LOG_INTERVAL: Duration = Duration::from_secs(5);
+
+#[derive(Clone, Copy, Debug)]
+pub struct FaultPolicy {
+    pub max_retries: u32,     // Immediate retries of a failed read within one loop
+    pub reinit_after: u32,    // Consecutive failed loops between reinitialization attempts, 0 to never reinitialize
+    pub unhealthy_after: u32, // Consecutive failed loops before the sensor is declared unhealthy
+}
+
+impl Default for FaultPolicy {
+    fn default() -> Self {
+        Self {
+            max_retries: 2,
+            reinit_after: 10,
+            unhealthy_after: 50,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum HealthState {
+    Healthy,
+    Degraded,  // Recent reads failed, but not enough in a row to give up on the sensor
+    Unhealthy, // Nothing is being published until a read succeeds
+}
+
+/// What the caller should do after a failed loop
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FaultAction {
+    Continue,
+    Reinitialize,
+}
+
+/// What came of one loop's read of the sensor
+#[derive(Debug)]
+pub enum ReadOutcome<T> {
+    /// Read, possibly after retries
+    Read(T),
+    /// The FIFO overflowed and was emptied, the samples in it were lost
+    Overflow,
+    /// Still failing after the retries
+    Failed(ImuError, FaultAction),
+}
+
+pub struct SensorHealth {
+    policy: FaultPolicy,
+    consecutive_failures: u32,
+    total_failures: u64,
+    reinits: u64,
+    overflows: u64,
+    last_error: Option<String>,
+}
+
+impl SensorHealth {
+    pub fn new(policy: FaultPolicy) -> Self {
+        Self {
+            policy,
+            consecutive_failures: 0,
+            total_failures: 0,
+            reinits: 0,
+            overflows: 0,
+            last_error: None,
+        }
+    }
+
+    pub fn policy(&self) -> FaultPolicy {
+        self.policy
+    }
+
+    pub fn state(&self) -> HealthState {
+        if self.consecutive_failures == 0 {
+            HealthState::Healthy
+        } else if self.consecutive_failures < self.policy.unhealthy_after {
+            HealthState::Degraded
+        } else {
+            HealthState::Unhealthy
+        }
+    }
+
+    /// Whether the error is worth retrying straight away, given how many retries this loop already made
+    pub fn should_retry(&self, err: &ImuError, attempt: u32) -> bool {
+        err.is_transient() && attempt < self.policy.max_retries
+    }
+
+    /// Read the sensor with `read`, retrying transient faults, and record how it went
+    pub fn read<T>(&mut self, mut read: impl FnMut() -> Result<T, ImuError>) -> ReadOutcome<T> {
+        let mut attempt = 0;
+        loop {
+            match read() {
+                Ok(value) => {
+                    self.record_success();
+                    return ReadOutcome::Read(value);
+                }
+                Err(ImuError::FifoOverflow) => {
+                    self.record_overflow();
+                    return ReadOutcome::Overflow;
+                }
+                Err(err) if self.should_retry(&err, attempt) => attempt += 1,
+                Err(err) => {
+                    let action = self.record_failure(&err);
+                    return ReadOutcome::Failed(err, action);
+                }
+            }
+        }
+    }
+
+    /// A loop read the sensor successfully
+    pub fn record_success(&mut self) {
+        self.consecutive_failures = 0;
+    }
+
+    /// A loop gave up reading the sensor after its retries
+    pub fn record_failure(&mut self, err: &ImuError) -> FaultAction {
+        self.consecutive_failures = self.consecutive_failures.saturating_add(1);
+        self.total_failures += 1;
+        self.last_error = Some(err.to_string());
+
+        let reinit_after = self.policy.reinit_after;
+        if reinit_after > 0 && err.is_transient() && self.consecutive_failures.is_multiple_of(reinit_after) {
+            self.reinits += 1;
+            FaultAction::Reinitialize
+        } else {
+            FaultAction::Continue
+        }
+    }
+
+    /// A loop found the FIFO overflowed. The sensor answered, so it counts as a working read.
+    pub fn record_overflow(&mut self) {
+        self.overflows += 1;
+        self.consecutive_failures = 0;
+    }
+
+    pub fn consecutive_failures(&self) -> u32 {
+        self.consecutive_failures
+    }
+
+    pub fn total_failures(&self) -> u64 {
+        self.total_failures
+    }
+
+    /// Reinitialization attempts so far
+    pub fn reinits(&self) -> u64 {
+        self.reinits
+    }
+
+    /// FIFO overflows so far, each losing the samples that were in it
+    pub fn overflows(&self) -> u64 {
+        self.overflows
+    }
+
+    pub fn last_error(&self) -> Option<&str> {
+        self.last_error.as_deref()
+    }
+}
+
+/// Failed reads of a secondary sensor, the magnetometer or the thermometer. They don't stop the
+/// accel/gyro data, so they're counted for the diagnostics rather than handled like a sensor fault,
+/// and logged at most once every few seconds so a dead magnetometer doesn't flood the console.
+#[derive(Default)]
+pub struct SecondaryReads {
+    failures: u64,
+    last_error: Option<String>,
+    last_logged: Option<Instant>,
+}
+
+impl SecondaryReads {
+    /// Count a read that failed at `now`, returning whether it should be logged
+    pub fn record_failure(&mut self, err: &ImuError, now: Instant) -> bool {
+        self.failures += 1;
+        self.last_error = Some(err.to_string());
+        let log = self
+            .last_logged
+            .is_none_or(|last| now.saturating_duration_since(last) >= SECONDARY_LOG_INTERVAL);
+        if log {
+            self.last_logged = Some(now);
+        }
+        log
+    }
+
+    pub fn failures(&self) -> u64 {
+        self.failures
+    }
+
+    pub fn last_error(&self) -> Option<&str> {
+        self.last_error.as_deref()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fifo::{Fifo, FifoConfig, FIFO_FRAME_LEN};
+    use crate::mock_spi::{MockIcm20948, MOCK_FIFO_SIZE};
+    use crate::registers::Registers;
+    use std::sync::{Arc, Mutex};
+
+    fn push_frames(device: &Mutex<MockIcm20948>, count: usize) {
+        let mut device = device.lock().unwrap();
+        for _ in 0..count {
+            device.push_frame([0, 0, 8192], [1, -1, 0]);
+        }
+    }
+
+    #[test]
+    fn fifo_overflow_is_counted_and_not_retried() {
+        let device = Arc::new(Mutex::new(MockIcm20948::new()));
+        let registers = Registers::new(Arc::clone(&device));
+        let mut fifo = Fifo::initialize(&registers, FifoConfig::default()).unwrap();
+        let mut health = SensorHealth::new(FaultPolicy::default());
+
+        push_frames(&device, MOCK_FIFO_SIZE / FIFO_FRAME_LEN + 1);
+        let mut reads = 0;
+        let outcome = health.read(|| {
+            reads += 1;
+            fifo.read(&registers)
+        });
+        assert!(matches!(outcome, ReadOutcome::Overflow), "{:?}", outcome);
+        assert_eq!(reads, 1);
+        assert_eq!(health.overflows(), 1);
+        assert_eq!(health.total_failures(), 0);
+        assert_eq!(health.state(), HealthState::Healthy);
+
+        // The FIFO was emptied, and the next frames read normally
+        push_frames(&device, 3);
+        match health.read(|| fifo.read(&registers)) {
+            ReadOutcome::Read(frames) => assert_eq!(frames.len(), 3),
+            other => panic!("{:?}", other),
+        }
+        assert_eq!(health.overflows(), 1);
+    }
+
+    #[test]
+    fn transient_faults_are_retried_within_the_loop() {
+        let mut health = SensorHealth::new(FaultPolicy::default());
+        let mut failures_left = 2;
+        let outcome = health.read(|| {
+            if failures_left > 0 {
+                failures_left -= 1;
+                return Err(ImuError::Spi("noise".to_string()));
+            }
+            Ok(())
+        });
+        assert!(matches!(outcome, ReadOutcome::Read(())));
+        assert_eq!(health.total_failures(), 0);
+    }
+
+    #[test]
+    fn persistent_faults_reinitialize_then_go_unhealthy() {
+        let policy = FaultPolicy {
+            max_retries: 1,
+            reinit_after: 3,
+            unhealthy_after: 5,
+        };
+        let mut health = SensorHealth::new(policy);
+        let mut reads = 0;
+        let mut actions = Vec::new();
+        for _ in 0..5 {
+            match health.read(|| -> Result<(), ImuError> {
+                reads += 1;
+                Err(ImuError::Spi("no response".to_string()))
+            }) {
+                ReadOutcome::Failed(_, action) => actions.push(action),
+                other => panic!("{:?}", other),
+            }
+        }
+        assert_eq!(reads, 10);
+        assert_eq!(actions[2], FaultAction::Reinitialize);
+        assert_eq!(actions.iter().filter(|action| **action == FaultAction::Reinitialize).count(), 1);
+        assert_eq!(health.state(), HealthState::Unhealthy);
+        assert_eq!(health.last_error(), Some("SPI transfer failed: no response"));
+
+        assert!(matches!(health.read(|| Ok(())), ReadOutcome::Read(())));
+        assert_eq!(health.state(), HealthState::Healthy);
+        assert_eq!(health.total_failures(), 5);
+    }
+
+    #[test]
+    fn secondary_failures_are_all_counted_but_logged_at_most_every_interval() {
+        let mut mag = SecondaryReads::default();
+        let start = Instant::now();
+        let err = ImuError::AuxI2c("timed out waiting for the transfer".to_string());
+
+        assert!(mag.record_failure(&err, start));
+        for ms in [1, 500, 4999] {
+            assert!(!mag.record_failure(&err, start + Duration::from_millis(ms)));
+        }
+        assert!(mag.record_failure(&err, start + SECONDARY_LOG_INTERVAL));
+        assert!(!mag.record_failure(&err, start + SECONDARY_LOG_INTERVAL + Duration::from_secs(1)));
+
+        assert_eq!(mag.failures(), 6);
+        assert_eq!(mag.last_error(), Some("Auxiliary I2C: timed out waiting for the transfer"));
+    }
+}
@@ -1,200 +1,1009 @@
-use rclrs::{create_node, Context, Node, Publisher, RclrsError, QOS_PROFILE_DEFAULT};
+use rclrs::{
+    create_node, Context, MandatoryParameter, Node, Publisher, QoSDurabilityPolicy, QoSHistoryPolicy, QoSProfile,
+    RclrsError, Service, QOS_PROFILE_DEFAULT,
+};
 use sensor_msgs::msg::Imu as ImuMsg;
-use icm20948_driver_rust::imu::{Accelerometer, Gyroscope, IMU}; // Custom Rust driver, https://github.com/OrlandoQuintana/icm20948-driver-rust
-use icm20948_driver_rust::spi_core::SpiCore;
-use linux_embedded_hal::spidev::{Spidev, SpidevOptions, SpiModeFlags}; // Wraps embedded-hal code used in the driver for use on Linux
-use linux_embedded_hal::SpidevBus;
-use std::sync::{Arc, Mutex};
-use std::time::Duration;
-use biquad::{Biquad, Coefficients, DirectForm1, ToHertz}; // Crate for Butterworth low pass filter
-use std::time::{SystemTime, UNIX_EPOCH};
+use sensor_msgs::msg::{MagneticField, Temperature};
+use std_msgs::msg::Float32MultiArray;
+use geometry_msgs::msg::{Quaternion, Transform, TransformStamped, Vector3, Vector3Stamped};
+use tf2_msgs::msg::TFMessage;
+use diagnostic_msgs::msg::{DiagnosticArray, DiagnosticStatus, KeyValue};
+use std_srvs::srv::{Trigger, Trigger_Request, Trigger_Response};
+use imu_publisher_pkg::calibration::{self, CalibrationFile, CalibrationStatus, GyroCalibration, GyroCalibrator};
+use imu_publisher_pkg::decimation::{DecimatedSample, Decimator, OutputMode};
+use imu_publisher_pkg::error::ImuError;
+use imu_publisher_pkg::filter::{FilterSettings, LowPassFilter};
+use imu_publisher_pkg::health::{FaultAction, FaultPolicy, HealthState, ReadOutcome, SecondaryReads, SensorHealth};
+use imu_publisher_pkg::imu_source::{self, ImuFrame, ImuSource, MagSample};
+use imu_publisher_pkg::mounting::Mounting;
+use imu_publisher_pkg::noise::{self, NoiseEstimator};
+use imu_publisher_pkg::sensor_config::SensorConfig;
+use imu_publisher_pkg::vibration::{NotchBank, VibrationAnalyzer, VibrationSettings};
+use scheduler_pkg::{PeriodicScheduler, SchedulerConfig, SchedulerStats};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{mpsc, Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
+/// How often the die temperature is read, it changes far slower than the sample rate
+const TEMPERATURE_PERIOD: Duration = Duration::from_millis(100);
 
-/// Struct containing the ROS2 node, publisher, IMU components. a filter parameters
+/// Time the calibrate_gyro service waits beyond the calibration's own length before giving up
+const CALIBRATION_REPLY_MARGIN: Duration = Duration::from_secs(2);
+
+/// Callers of the calibrate_gyro service waiting on the calibration they started. Each is sent the
+/// outcome's message, Ok if the new bias was applied and saved.
+type CalibrationReplies = Arc<Mutex<Vec<mpsc::Sender<Result<String, String>>>>>;
+
+/// Struct containing the ROS2 node, publisher, IMU source. a filter parameters
 struct IMUPublisherNode {
     node: Arc<Node>,
     publisher: Arc<Publisher<ImuMsg>>,
-    imu: IMU<SpidevBus>,
-    accel: Accelerometer<SpidevBus>,
-    gyro: Gyroscope<SpidevBus>,
-    filter_x: Mutex<DirectForm1<f32>>,
-    filter_y: Mutex<DirectForm1<f32>>,
-    filter_z: Mutex<DirectForm1<f32>>,
+    mag_publisher: Arc<Publisher<MagneticField>>,
+    peaks_publisher: Arc<Publisher<Float32MultiArray>>,
+    diagnostics_publisher: Arc<Publisher<DiagnosticArray>>,
+    temperature_publisher: Arc<Publisher<Temperature>>,
+    delta_angle_publisher: Arc<Publisher<Vector3Stamped>>,
+    delta_velocity_publisher: Arc<Publisher<Vector3Stamped>>,
+    _tf_static_publisher: Arc<Publisher<TFMessage>>,
+    mounting: Mounting,
+    scheduler_config: SchedulerConfig,
+    source: Box<dyn ImuSource>,
+    health: SensorHealth,
+    mag_reads: SecondaryReads,
+    temperature_reads: SecondaryReads,
+    accel_filter: LowPassFilter,
+    gyro_filter: LowPassFilter,
+    filter_settings: FilterSettings, // Last requested through the parameters, even if rejected
+    filter_parameters: FilterParameters,
+    vibration: Option<(VibrationAnalyzer, NotchBank)>, // None while the notch filters are disabled
+    vibration_settings: Option<VibrationSettings>,     // Last requested through the parameters
+    vibration_parameters: VibrationParameters,
+    decimator: Decimator, // Sensor rate samples down to the published rate
+    output_mode: OutputMode,
+    calibration: CalibrationFile, // Last saved calibration for this sensor
+    calibration_path: PathBuf,
+    gyro_bias: [f32; 3], // From the calibration, carried along the thermal model to the current temperature
+    temperature: Option<f32>, // Degrees C, None until first read or if the source has no thermometer
+    last_temperature_read: Option<Instant>,
+    gyro_calibrator: Option<GyroCalibrator>, // Some while a gyro calibration is running
+    gyro_calibration_settings: (usize, f32, f32), // Samples, gyro motion threshold, accel motion threshold
+    noise: NoiseEstimator, // Per-axis variance published as the message covariances
+    calibration_request: Arc<AtomicBool>, // Set at startup and by the calibrate_gyro service
+    calibration_replies: CalibrationReplies,
+    _calibration_service: Arc<Service<Trigger>>,
 }
 
 impl IMUPublisherNode {
-    /// Create a new IMU Publisher Node
-    fn new(context: &Context) -> Result<Self, RclrsError> {
+    /// Create a new IMU Publisher Node, reading from the backend selected by the `imu_source` parameter
+    fn new(context: &Context) -> Result<Self, ImuError> {
         
-        // Standard ROS2 Rust node and publisher initialization
-        let node = create_node(context, "imu_publisher").unwrap();
+        // Standard ROS2 Rust node initialization
+        let node = create_node(context, "imu_publisher")?;
+
+        // "icm20948" reads the real sensor over SPI, "synthetic" simulates one for running off the Pi
+        let source_param = node
+            .declare_parameter("imu_source")
+            .default(Arc::<str>::from("icm20948"))
+            .mandatory()?;
+
+        // Sensor ranges and rates, written to the IMU when it's initialized
+        let accel_range = node
+            .declare_parameter("sensor.accel_range_g") // 2, 4, 8 or 16
+            .default(2_i64)
+            .mandatory()?;
+        let gyro_range = node
+            .declare_parameter("sensor.gyro_range_dps") // 250, 500, 1000 or 2000
+            .default(250_i64)
+            .mandatory()?;
+        let accel_dlpf = node
+            .declare_parameter("sensor.accel_dlpf_hz") // On-chip low-pass bandwidth, rounded to the nearest supported
+            .default(246.0)
+            .mandatory()?;
+        let gyro_dlpf = node
+            .declare_parameter("sensor.gyro_dlpf_hz")
+            .default(196.6)
+            .mandatory()?;
+        let odr = node
+            .declare_parameter("sensor.odr_hz") // 1100 Hz divided by a whole number
+            .default(1100.0)
+            .mandatory()?;
+        let sensor_config = SensorConfig::new(
+            accel_range.get(),
+            gyro_range.get(),
+            accel_dlpf.get(),
+            gyro_dlpf.get(),
+            odr.get(),
+        )?;
+        println!(
+            "IMU configured for +-{} g, +-{} dps, DLPF {} / {} Hz, ODR {:.1} / {:.1} Hz",
+            sensor_config.accel_range.g(),
+            sensor_config.gyro_range.dps(),
+            sensor_config.accel_bandwidth_hz(),
+            sensor_config.gyro_bandwidth_hz(),
+            sensor_config.accel_sample_rate_hz(),
+            sensor_config.gyro_sample_rate_hz()
+        );
+
+        let source = imu_source::open_source(&source_param.get(), "/dev/spidev0.0", sensor_config)?;
+
+        Self::with_source(node, source, sensor_config)
+    }
+
+    /// Create the publisher and filters around an already constructed node and an IMU source
+    /// configured with `sensor_config`
+    fn with_source(
+        node: Arc<Node>,
+        source: Box<dyn ImuSource>,
+        sensor_config: SensorConfig,
+    ) -> Result<Self, ImuError> {
         let publisher = node
-            .create_publisher::<ImuMsg>("/raw_imu", QOS_PROFILE_DEFAULT)
-            .unwrap();
-
-        // Configure Butterworth filter coefficients
-        let coeffs = Coefficients::<f32>::from_params(
-            biquad::Type::LowPass,
-            1000.0.hz(), // Sampling frequency (adjust as per your IMU's rate)
-            80.0.hz(),   // Cutoff frequency
-            0.707,      // Q factor (Butterworth characteristic)
-        )
-        .unwrap();
-
-        // Create filters for each axis
-        let filter_x = Mutex::new(DirectForm1::<f32>::new(coeffs));
-        let filter_y = Mutex::new(DirectForm1::<f32>::new(coeffs));
-        let filter_z = Mutex::new(DirectForm1::<f32>::new(coeffs));
-
-        // Configure IMU
-        let mut spidev = Spidev::open("/dev/spidev0.0").expect("Failed to open SPI device");
-        spidev
-            .configure(
-                &SpidevOptions::new()
-                    .bits_per_word(8)
-                    .max_speed_hz(7_000_000)
-                    .mode(SpiModeFlags::SPI_MODE_0)
-                    .build(),
-            )
-            .expect("Failed to configure SPI device");
-
-        let spidev_bus = SpidevBus::from(linux_embedded_hal::SpidevBus(spidev));
-        let spi = Arc::new(Mutex::new(SpiCore::new(spidev_bus)));
-        let imu = IMU::new(Arc::clone(&spi));
-        let accel = Accelerometer::new(Arc::clone(&spi));
-        let gyro = Gyroscope::new(Arc::clone(&spi));
+            .create_publisher::<ImuMsg>("/raw_imu", QOS_PROFILE_DEFAULT)?;
+        let mag_publisher = node
+            .create_publisher::<MagneticField>("/raw_mag", QOS_PROFILE_DEFAULT)?;
+        let peaks_publisher = node
+            .create_publisher::<Float32MultiArray>("/gyro_vibration_peaks", QOS_PROFILE_DEFAULT)?;
+        let diagnostics_publisher = node
+            .create_publisher::<DiagnosticArray>("/diagnostics", QOS_PROFILE_DEFAULT)?;
+        let temperature_publisher = node
+            .create_publisher::<Temperature>("/imu_temperature", QOS_PROFILE_DEFAULT)?;
+        let delta_angle_publisher = node
+            .create_publisher::<Vector3Stamped>("/imu_delta_angle", QOS_PROFILE_DEFAULT)?;
+        let delta_velocity_publisher = node
+            .create_publisher::<Vector3Stamped>("/imu_delta_velocity", QOS_PROFILE_DEFAULT)?;
+
+        // Board orientation and position in the airframe. Samples are rotated into body axes, so
+        // imu_link is the IMU's position with base_link's axes
+        let mounting_roll = node
+            .declare_parameter("mounting.roll_deg") // Board rotation relative to the body, applied yaw, pitch, roll
+            .default(0.0)
+            .mandatory()?;
+        let mounting_pitch = node
+            .declare_parameter("mounting.pitch_deg")
+            .default(0.0)
+            .mandatory()?;
+        let mounting_yaw = node
+            .declare_parameter("mounting.yaw_deg")
+            .default(0.0)
+            .mandatory()?;
+        let mounting_x = node
+            .declare_parameter("mounting.x") // m from base_link, forward
+            .default(0.0)
+            .mandatory()?;
+        let mounting_y = node
+            .declare_parameter("mounting.y") // m, left
+            .default(0.0)
+            .mandatory()?;
+        let mounting_z = node
+            .declare_parameter("mounting.z") // m, up
+            .default(0.0)
+            .mandatory()?;
+        let mounting = Mounting::new(
+            [mounting_roll.get(), mounting_pitch.get(), mounting_yaw.get()],
+            [mounting_x.get(), mounting_y.get(), mounting_z.get()],
+        );
+
+        // Latched, so nodes that start later still get the transform
+        let tf_static_qos = QoSProfile {
+            history: QoSHistoryPolicy::KeepLast { depth: 1 },
+            durability: QoSDurabilityPolicy::TransientLocal,
+            ..QOS_PROFILE_DEFAULT
+        };
+        let _tf_static_publisher = node.create_publisher::<TFMessage>("/tf_static", tf_static_qos)?;
+        _tf_static_publisher.publish(imu_transform(&mounting))?;
+
+        // Sampling loop timing
+        let loop_rate = node
+            .declare_parameter("scheduler.rate_hz")
+            .default(1000.0)
+            .mandatory()?;
+        let realtime_priority = node
+            .declare_parameter("scheduler.realtime_priority") // SCHED_FIFO priority, 0 to disable
+            .default(0_i64)
+            .mandatory()?;
+        let cpu = node
+            .declare_parameter("scheduler.cpu") // Core to pin the loop to, -1 to let the kernel choose
+            .default(-1_i64)
+            .mandatory()?;
+        let scheduler_config = SchedulerConfig {
+            realtime_priority: Some(realtime_priority.get() as i32).filter(|priority| *priority > 0),
+            cpu: usize::try_from(cpu.get()).ok(),
+            ..SchedulerConfig::from_rate(loop_rate.get().max(1.0))
+        };
+
+        // Handling of failed sensor reads
+        let defaults = FaultPolicy::default();
+        let max_retries = node
+            .declare_parameter("fault.max_retries") // Immediate retries of a failed read
+            .default(defaults.max_retries as i64)
+            .mandatory()?;
+        let reinit_after = node
+            .declare_parameter("fault.reinit_after") // Failed loops between reinitializations, 0 to disable
+            .default(defaults.reinit_after as i64)
+            .mandatory()?;
+        let unhealthy_after = node
+            .declare_parameter("fault.unhealthy_after") // Failed loops in a row before the IMU is unhealthy
+            .default(defaults.unhealthy_after as i64)
+            .mandatory()?;
+        let health = SensorHealth::new(FaultPolicy {
+            max_retries: max_retries.get().clamp(0, u32::MAX as i64) as u32,
+            reinit_after: reinit_after.get().clamp(0, u32::MAX as i64) as u32,
+            unhealthy_after: unhealthy_after.get().clamp(1, u32::MAX as i64) as u32,
+        });
+
+        // Butterworth low-pass filter settings, can be changed while running
+        let filter_parameters = FilterParameters {
+            sample_rate_hz: node
+                .declare_parameter("filter.sample_rate_hz") // Must match the IMU's output data rate
+                .default(sensor_config.sample_rate_hz())
+                .mandatory()?,
+            sensor_rate_hz: sensor_config.sample_rate_hz() as f32,
+            cutoff_hz: node
+                .declare_parameter("filter.cutoff_hz")
+                .default(80.0)
+                .mandatory()?,
+            order: node
+                .declare_parameter("filter.order") // Cascaded biquads, odd orders add a first-order section
+                .default(2_i64)
+                .mandatory()?,
+            accel_enabled: node
+                .declare_parameter("filter.accel_enabled")
+                .default(true)
+                .mandatory()?,
+            gyro_enabled: node
+                .declare_parameter("filter.gyro_enabled")
+                .default(false)
+                .mandatory()?,
+        };
+        let filter_settings = filter_parameters.settings();
+        let (accel_filter, gyro_filter) = filter_parameters.filters(&filter_settings)?;
+
+        // Dynamic notch filters on the gyro, following motor vibration found by FFT
+        let vibration_parameters = VibrationParameters {
+            enabled: node
+                .declare_parameter("notch.enabled")
+                .default(true)
+                .mandatory()?,
+            count: node
+                .declare_parameter("notch.count") // Vibration peaks tracked, one notch each
+                .default(2_i64)
+                .mandatory()?,
+            q: node
+                .declare_parameter("notch.q")
+                .default(3.0)
+                .mandatory()?,
+            min_hz: node
+                .declare_parameter("notch.min_hz")
+                .default(80.0)
+                .mandatory()?,
+            max_hz: node
+                .declare_parameter("notch.max_hz")
+                .default(450.0)
+                .mandatory()?,
+            window: node
+                .declare_parameter("notch.window") // FFT length in samples
+                .default(256_i64)
+                .mandatory()?,
+            min_snr: node
+                .declare_parameter("notch.min_snr") // Peak power over the noise floor
+                .default(10.0)
+                .mandatory()?,
+        };
+        let vibration_settings = vibration_parameters.settings(filter_parameters.sensor_rate_hz);
+        let vibration = match vibration_settings {
+            Some(settings) => vibration_filters(settings)?,
+            None => None,
+        };
+
+        // Published rate, the sensor keeps sampling at its full rate and is decimated down to it
+        let output_rate = node
+            .declare_parameter("output.rate_hz") // Rounded to the sensor rate divided by a whole number
+            .default(220.0)
+            .mandatory()?;
+        let output_mode = node
+            .declare_parameter("output.mode") // "rate", or "delta" for coning/sculling corrected increments
+            .default(Arc::<str>::from("rate"))
+            .mandatory()?;
+        let output_mode = OutputMode::from_name(&output_mode.get())?;
+        let decimator = Decimator::new(sensor_config.sample_rate_hz(), output_rate.get())?;
+        println!(
+            "Publishing {:?} at {:.1} Hz, every {} samples",
+            output_mode,
+            decimator.output_rate_hz(),
+            decimator.factor()
+        );
+
+        // Gyro calibration settings
+        let calibration_dir = node
+            .declare_parameter("calibration_dir")
+            .default(Arc::<str>::from(calibration::default_calibration_dir().to_string_lossy().as_ref()))
+            .mandatory()?;
+        let calibration_samples = node
+            .declare_parameter("gyro_calibration.samples")
+            .default(2000_i64)
+            .mandatory()?;
+        let motion_threshold = node
+            .declare_parameter("gyro_calibration.motion_threshold") // rad/s
+            .default(0.05)
+            .mandatory()?;
+        let accel_threshold = node
+            .declare_parameter("gyro_calibration.accel_threshold") // m/s^2
+            .default(0.3)
+            .mandatory()?;
+
+        // Reload the biases saved on a previous boot so they're used until the startup calibration finishes
+        let calibration_path = calibration::calibration_path(Path::new(&*calibration_dir.get()), &source.sensor_id());
+        let calibration = match CalibrationFile::load(&calibration_path) {
+            Ok(Some(file)) => {
+                println!("Loaded IMU calibration from {}", calibration_path.display());
+                file
+            }
+            Ok(None) => CalibrationFile::default(),
+            Err(err) => {
+                eprintln!("Ignoring IMU calibration: {}", err);
+                CalibrationFile::default()
+            }
+        };
+        if calibration.gyro_thermal.is_some() {
+            println!("Compensating gyro bias for temperature");
+        }
+        let gyro_bias = calibration.gyro_bias(None);
+
+        // Noise estimate for the published covariances, starting from the one saved with the calibration
+        let noise_window = node
+            .declare_parameter("noise.window") // Published samples per variance estimate
+            .default(decimator.output_rate_hz().round() as i64)
+            .mandatory()?;
+        let noise_max_gyro_std = node
+            .declare_parameter("noise.max_gyro_std") // rad/s, above this a window has motion in it
+            .default(0.02)
+            .mandatory()?;
+        let noise_max_accel_std = node
+            .declare_parameter("noise.max_accel_std") // m/s^2
+            .default(0.2)
+            .mandatory()?;
+        let noise = NoiseEstimator::new(
+            noise_window.get().max(0) as usize,
+            noise_max_gyro_std.get() as f32,
+            noise_max_accel_std.get() as f32,
+            calibration.noise.unwrap_or_else(|| {
+                // Whichever of the on-chip filter and the anti-aliasing filter is narrower limits the noise
+                let limit = decimator.bandwidth_hz().unwrap_or(f32::MAX);
+                noise::datasheet_noise(
+                    sensor_config.accel_bandwidth_hz().min(limit),
+                    sensor_config.gyro_bandwidth_hz().min(limit),
+                )
+            }),
+        );
+
+        // Calibrate on startup, and again whenever the service is called. The service answers once
+        // the sampling loop has finished the calibration, so the caller learns whether it took.
+        let calibration_request = Arc::new(AtomicBool::new(true));
+        let calibration_replies = CalibrationReplies::default();
+        let calibration_request_service = Arc::clone(&calibration_request);
+        let calibration_replies_service = Arc::clone(&calibration_replies);
+        let calibration_timeout = Duration::from_secs_f64(
+            calibration_samples.get().max(1) as f64 / sensor_config.sample_rate_hz(),
+        ) + CALIBRATION_REPLY_MARGIN;
+        let _calibration_service = node.create_service::<Trigger, _>(
+            "~/calibrate_gyro",
+            move |_request_header: &rclrs::rmw_request_id_t, _request: Trigger_Request| {
+                let (reply, outcome) = mpsc::channel();
+                if let Ok(mut replies) = calibration_replies_service.lock() {
+                    replies.push(reply);
+                }
+                calibration_request_service.store(true, Ordering::SeqCst);
+                let (success, message) = match outcome.recv_timeout(calibration_timeout) {
+                    Ok(Ok(message)) => (true, message),
+                    Ok(Err(message)) => (false, message),
+                    Err(_) => (false, "Gyro calibration didn't finish, is the IMU being read?".to_string()),
+                };
+                Trigger_Response { success, message }
+            },
+        )?;
 
         Ok(Self {
-            node: Arc::clone(&node),
-            publisher: Arc::clone(&publisher),
-            imu,
-            accel,
-            gyro,
-            filter_x,
-            filter_y,
-            filter_z,
+            node,
+            publisher,
+            mag_publisher,
+            peaks_publisher,
+            diagnostics_publisher,
+            temperature_publisher,
+            delta_angle_publisher,
+            delta_velocity_publisher,
+            _tf_static_publisher,
+            mounting,
+            scheduler_config,
+            source,
+            health,
+            mag_reads: SecondaryReads::default(),
+            temperature_reads: SecondaryReads::default(),
+            accel_filter,
+            gyro_filter,
+            filter_settings,
+            filter_parameters,
+            vibration,
+            vibration_settings,
+            vibration_parameters,
+            decimator,
+            output_mode,
+            calibration,
+            calibration_path,
+            gyro_bias,
+            temperature: None,
+            last_temperature_read: None,
+            gyro_calibrator: None,
+            gyro_calibration_settings: (
+                calibration_samples.get().max(1) as usize,
+                motion_threshold.get() as f32,
+                accel_threshold.get() as f32,
+            ),
+            noise,
+            calibration_request,
+            calibration_replies,
+            _calibration_service,
         })
     }
 
-    /// Initialize the IMU explicitly
-    fn initialize_imu(&mut self) -> Result<(), String> {
-        self.imu.initialize().map_err(|e| format!("IMU initialization failed: {:?}", e))?;
-        Ok(())
+    /// Feed a raw sample to the running gyro calibration, starting one first if it was requested
+    fn update_gyro_calibration(&mut self, accel_data: [f32; 3], gyro_data: [f32; 3]) {
+        if self.calibration_request.swap(false, Ordering::SeqCst) {
+            println!("Gyro calibration started, keep the IMU still");
+            let (samples, motion_threshold, accel_threshold) = self.gyro_calibration_settings;
+            self.gyro_calibrator = Some(GyroCalibrator::new(samples, motion_threshold, accel_threshold));
+        }
+
+        let status = match self.gyro_calibrator.as_mut() {
+            Some(calibrator) => calibrator.add_sample(accel_data, gyro_data),
+            None => return,
+        };
+
+        let outcome = match status {
+            CalibrationStatus::InProgress => return,
+            CalibrationStatus::Done(gyro) => match self.save_gyro_calibration(gyro) {
+                Ok(()) => Ok(format!("Gyro calibration complete, bias {:?} rad/s", self.gyro_bias)),
+                Err(err) => {
+                    eprintln!("Failed to save gyro calibration: {}", err);
+                    Err(format!("Gyro bias applied but not saved: {}", err))
+                }
+            },
+            CalibrationStatus::Rejected(reason) => {
+                eprintln!("Gyro calibration rejected, motion detected ({}). Keeping bias {:?}", reason, self.gyro_bias);
+                Err(format!("Gyro calibration rejected, motion detected ({})", reason))
+            }
+        };
+        self.gyro_calibrator = None;
+
+        // Answer every service call waiting on this calibration
+        if let Ok(mut replies) = self.calibration_replies.lock() {
+            for reply in replies.drain(..) {
+                let _ = reply.send(outcome.clone()); // The caller may have timed out already
+            }
+        }
+    }
+
+    /// Start using a new gyro bias and persist it for the next boot
+    fn save_gyro_calibration(&mut self, mut gyro: GyroCalibration) -> Result<(), ImuError> {
+        println!("Gyro calibration complete, bias: {:?}", gyro.bias);
+        gyro.temperature = self.temperature; // Where the thermal model picks up from
+        self.calibration.gyro = Some(gyro);
+        self.gyro_bias = self.calibration.gyro_bias(self.temperature);
+
+        // The vehicle was just still, so the noise measured meanwhile is worth keeping too
+        if self.noise.still_windows() > 0 {
+            self.calibration.noise = Some(self.noise.estimate());
+        }
+        self.calibration.save(&self.calibration_path)
+    }
+
+    /// Rebuild the low-pass and notch filters if their parameters changed since the last sample
+    fn update_filters(&mut self) {
+        let settings = self.filter_parameters.settings();
+        if settings != self.filter_settings {
+            self.filter_settings = settings;
+
+            // New coefficients with fresh state, old state doesn't mean anything under new coefficients
+            match self.filter_parameters.filters(&settings) {
+                Ok((accel_filter, gyro_filter)) => {
+                    println!("Low-pass filter reconfigured: {:?}", settings);
+                    self.accel_filter = accel_filter;
+                    self.gyro_filter = gyro_filter;
+                }
+                Err(err) => {
+                    eprintln!("Keeping the previous low-pass filter: {}", err);
+                }
+            }
+        }
+
+        let vibration_settings = self.vibration_parameters.settings(self.filter_parameters.sensor_rate_hz);
+        if vibration_settings != self.vibration_settings {
+            self.vibration_settings = vibration_settings;
+            match vibration_settings {
+                Some(settings) => match vibration_filters(settings) {
+                    Ok(vibration) => {
+                        println!("Notch filters reconfigured: {:?}", settings);
+                        self.vibration = vibration;
+                    }
+                    Err(err) => eprintln!("Keeping the previous notch filters: {}", err),
+                },
+                None => {
+                    println!("Notch filters disabled");
+                    self.vibration = None;
+                }
+            }
+        }
+    }
+
+    /// Track motor vibration and notch it out of a bias-corrected gyro sample
+    fn notch_gyro(&mut self, gyro_data: [f32; 3]) -> [f32; 3] {
+        let Some((analyzer, notches)) = self.vibration.as_mut() else {
+            return gyro_data;
+        };
+
+        // The analyzer sees the signal before the notches, otherwise it would lose the peaks it removed
+        if let Some(peaks) = analyzer.push(gyro_data) {
+            notches.steer(&peaks);
+            let peaks_msg = Float32MultiArray {
+                data: peaks,
+                ..Default::default()
+            };
+            if let Err(err) = self.peaks_publisher.publish(peaks_msg) {
+                eprintln!("Failed to publish vibration peaks: {:?}", err);
+            }
+        }
+        notches.run(gyro_data)
+    }
+
+    /// Initialize the IMU explicitly, retrying transient faults
+    fn initialize_imu(&mut self) -> Result<(), ImuError> {
+        let mut attempt = 0;
+        loop {
+            match self.source.initialize() {
+                Ok(()) => return Ok(()),
+                Err(err) if self.health.should_retry(&err, attempt) => {
+                    eprintln!("IMU initialization failed, retrying: {}", err);
+                    attempt += 1;
+                    std::thread::sleep(Duration::from_millis(100));
+                }
+                Err(err) => return Err(err),
+            }
+        }
+    }
+
+    /// Read the frames since the last loop, retrying transient faults and reinitializing the sensor
+    /// if they persist. None if nothing could be read, in which case nothing is published this loop.
+    fn read_frames(&mut self) -> Option<Vec<ImuFrame>> {
+        let previous_state = self.health.state();
+        let source = &mut self.source;
+        let (err, action) = match self.health.read(|| source.read_frames()) {
+            ReadOutcome::Read(frames) => {
+                if previous_state != HealthState::Healthy {
+                    println!("IMU reads recovered");
+                    self.reset_filters();
+                }
+                return Some(frames);
+            }
+            ReadOutcome::Overflow => {
+                // The frames after the gap don't follow on from the filters' state
+                eprintln!("IMU FIFO overflowed, samples lost ({} overflows so far)", self.health.overflows());
+                self.reset_filters();
+                return None;
+            }
+            ReadOutcome::Failed(err, action) => (err, action),
+        };
+
+        let state = self.health.state();
+        if previous_state == HealthState::Healthy {
+            eprintln!("Failed to read IMU: {}", err);
+        }
+        if state == HealthState::Unhealthy && previous_state != HealthState::Unhealthy {
+            eprintln!("IMU unhealthy after {} failed reads in a row: {}", self.health.consecutive_failures(), err);
+        }
+        if state != previous_state {
+            if let Err(err) = self.publish_diagnostics(None) {
+                eprintln!("Error publishing IMU diagnostics: {:?}", err);
+            }
+        }
+
+        if action == FaultAction::Reinitialize {
+            eprintln!("Reinitializing IMU (attempt {})", self.health.reinits());
+            if let Err(err) = self.source.initialize() {
+                eprintln!("IMU reinitialization failed: {}", err);
+            }
+        }
+        None
+    }
+
+    /// Restart the filters and the decimation interval from the next sample, after a gap their
+    /// state belongs to old data
+    fn reset_filters(&mut self) {
+        self.accel_filter.reset();
+        self.gyro_filter.reset();
+        self.decimator.reset();
+        if let Some(settings) = self.vibration_settings.and_then(VibrationSettings::within_nyquist) {
+            if let Ok(analyzer) = VibrationAnalyzer::new(settings) {
+                self.vibration = Some((analyzer, NotchBank::new(&settings)));
+            }
+        }
     }
 
     /// Publish IMU data to the ROS2 topic
     fn publish_data(&mut self) -> Result<(), RclrsError> {
-        let mut imu_msg = ImuMsg::default();
+        self.update_filters();
 
+        // Every accel/gyro sample the sensor produced since the last loop, so none are dropped or duplicated.
+        // A failed read publishes nothing rather than a made-up sample
+        if let Some(frames) = self.read_frames() {
+            for frame in frames {
+                self.process_frame(frame)?;
+            }
+        }
 
-        // === Timestamp ===
-        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap();
-        imu_msg.header = std_msgs::msg::Header {
-            stamp: builtin_interfaces::msg::Time {
-                sec: now.as_secs() as i32,
-                nanosec: now.subsec_nanos(),
-            },
-            frame_id: "imu_link".to_string(),
-            ..Default::default()
-        };        
-
-        // Read accelerometer data using read method from the driver
-        if let Ok(accel_data) = self.accel.read() {
-            // Filter accelerometer data
-            let filtered_x = self.filter_x.lock().unwrap().run(accel_data[0] as f32);
-            let filtered_y = self.filter_y.lock().unwrap().run(accel_data[1] as f32);
-            let filtered_z = self.filter_z.lock().unwrap().run(accel_data[2] as f32);
-
-            imu_msg.linear_acceleration.x = filtered_x as f64;
-            imu_msg.linear_acceleration.y = filtered_y as f64;
-            imu_msg.linear_acceleration.z = filtered_z as f64;
-/*
-            println!(
-                "Accel -> x: {:.3}, y: {:.3}, z: {:.3}",
-                imu_msg.linear_acceleration.x,
-                imu_msg.linear_acceleration.y,
-                imu_msg.linear_acceleration.z
-            );
-*/
+        // The magnetometer updates at 100 Hz, so most loops have no new sample
+        match self.source.read_mag() {
+            Ok(Some(sample)) => self.publish_mag(sample)?,
+            Ok(None) => {}
+            Err(err) => {
+                if self.mag_reads.record_failure(&err, Instant::now()) {
+                    eprintln!("Failed to read magnetometer ({} failures so far): {}", self.mag_reads.failures(), err);
+                }
+            }
+        }
+
+        self.update_temperature()
+    }
+
+    /// Read and publish the die temperature if it's due, and move the gyro bias along the thermal model
+    fn update_temperature(&mut self) -> Result<(), RclrsError> {
+        if self.last_temperature_read.is_some_and(|last| last.elapsed() < TEMPERATURE_PERIOD) {
+            return Ok(());
+        }
+        self.last_temperature_read = Some(Instant::now());
+
+        let temperature = match self.source.read_temperature() {
+            Ok(Some(temperature)) => temperature,
+            Ok(None) => return Ok(()),
+            Err(err) => {
+                if self.temperature_reads.record_failure(&err, Instant::now()) {
+                    eprintln!(
+                        "Failed to read IMU temperature ({} failures so far): {}",
+                        self.temperature_reads.failures(),
+                        err
+                    );
+                }
+                return Ok(());
+            }
+        };
+        self.temperature = Some(temperature);
+        self.gyro_bias = self.calibration.gyro_bias(self.temperature);
+
+        let temperature_msg = Temperature {
+            header: imu_header(),
+            temperature: temperature as f64,
+            variance: 0.0, // Unknown
+        };
+        self.temperature_publisher.publish(temperature_msg)
+    }
+
+    /// Correct and filter one synchronized accel/gyro sample, and publish if it completes an output interval
+    fn process_frame(&mut self, frame: ImuFrame) -> Result<(), RclrsError> {
+        // Gyro calibration works on the raw, unfiltered samples
+        self.update_gyro_calibration(frame.accel, frame.gyro);
+
+        // Correct offset, scale and misalignment from the six-position calibration before filtering
+        let accel_data = match &self.calibration.accel {
+            Some(accel_calibration) => accel_calibration.apply(frame.accel),
+            None => frame.accel,
+        };
+
+        // Filter accelerometer data
+        let accel_data = if self.filter_parameters.accel_enabled.get() {
+            self.accel_filter.run(accel_data)
         } else {
-            println!("publish_data: Failed to read accelerometer");
-        }
-
-        // Read gyroscope data
-        if let Ok(gyro_data) = self.gyro.read() {
-
-            // Gyroscope Calibration
-            // Run the gyroscope for X iterations with the gyroscope completely at rest. take the average
-            // reading and subtract from all gyroscope readings to get a calibrated reading
-            imu_msg.angular_velocity.x = (gyro_data[0] - (-0.0007)) as f64; // X Bias -0.0007
-            imu_msg.angular_velocity.y = (gyro_data[1] - (0.013)) as f64; // Y Bias
-            imu_msg.angular_velocity.z = (gyro_data[2] - (0.006)) as f64; // Z Bias
-/*
-            println!(
-                "Gyro -> x: {:.3}, y: {:.3}, z: {:.3}",
-                imu_msg.angular_velocity.x,
-                imu_msg.angular_velocity.y,
-                imu_msg.angular_velocity.z
-            );
-*/
+            self.accel_filter.reset(); // Start from the current sample when re-enabled
+            accel_data
+        };
+
+        // Sensor axes to body axes
+        let accel_data = self.mounting.to_body(accel_data);
+
+        // Gyroscope Calibration
+        // Bias is the average at-rest reading, measured at startup or loaded from the calibration file
+        let gyro_data = [
+            frame.gyro[0] - self.gyro_bias[0],
+            frame.gyro[1] - self.gyro_bias[1],
+            frame.gyro[2] - self.gyro_bias[2],
+        ];
+
+        // Notch out motor vibration, then low-pass filter
+        let gyro_data = self.notch_gyro(gyro_data);
+        let gyro_data = if self.filter_parameters.gyro_enabled.get() {
+            self.gyro_filter.run(gyro_data)
         } else {
-            println!("publish_data: Failed to read gyroscope");
+            self.gyro_filter.reset();
+            gyro_data
+        };
+
+        let gyro_data = self.mounting.to_body(gyro_data);
+
+        match self.decimator.push(accel_data, gyro_data, frame.timestamp) {
+            Some(sample) => self.publish_sample(sample),
+            None => Ok(()),
         }
+    }
+
+    /// Publish one decimated sample on /raw_imu, and its increments in delta mode
+    fn publish_sample(&mut self, sample: DecimatedSample) -> Result<(), RclrsError> {
+        // === Timestamp ===
+        // When the sensor took the last sample of the interval, not when we got round to reading it
+        let header = imu_header_at(sample.timestamp);
+        let mut imu_msg = ImuMsg {
+            header: header.clone(),
+            ..Default::default()
+        };
+
+        let (accel_data, gyro_data) = match self.output_mode {
+            OutputMode::Rate => (sample.accel, sample.gyro),
+            OutputMode::Delta => sample.mean_rates(),
+        };
+
+        imu_msg.linear_acceleration.x = accel_data[0] as f64;
+        imu_msg.linear_acceleration.y = accel_data[1] as f64;
+        imu_msg.linear_acceleration.z = accel_data[2] as f64;
+
+        imu_msg.angular_velocity.x = gyro_data[0] as f64;
+        imu_msg.angular_velocity.y = gyro_data[1] as f64;
+        imu_msg.angular_velocity.z = gyro_data[2] as f64;
+
+        // === Covariances ===
+        // Noise measured on the published samples, and no orientation at all from a raw IMU (REP-145)
+        self.noise.add_sample(accel_data, gyro_data);
+        let noise = self.noise.estimate();
+        imu_msg.linear_acceleration_covariance = noise::diagonal_covariance(noise.accel_variance);
+        imu_msg.angular_velocity_covariance = noise::diagonal_covariance(noise.gyro_variance);
+        imu_msg.orientation_covariance[0] = -1.0;
 
         // Publish the message
-        self.publisher.publish(imu_msg).unwrap();
+        self.publisher.publish(imu_msg)?;
 
+        if self.output_mode == OutputMode::Delta {
+            let vector = |v: [f32; 3]| Vector3 {
+                x: v[0] as f64,
+                y: v[1] as f64,
+                z: v[2] as f64,
+            };
+            self.delta_angle_publisher.publish(Vector3Stamped {
+                header: header.clone(),
+                vector: vector(sample.delta_angle),
+            })?;
+            self.delta_velocity_publisher.publish(Vector3Stamped {
+                header,
+                vector: vector(sample.delta_velocity),
+            })?;
+        }
         Ok(())
     }
+
+    /// Publish the sensor's health, and the sampling loop's timing statistics if given, on /diagnostics
+    fn publish_diagnostics(&self, stats: Option<&SchedulerStats>) -> Result<(), RclrsError> {
+        let hardware_id = self.source.sensor_id();
+        let mut status = vec![self.health_status(&hardware_id)];
+        if let Some(stats) = stats {
+            status.push(stats.to_diagnostic_status("imu_publisher: sampling loop", &hardware_id));
+        }
+        let diagnostics = DiagnosticArray {
+            header: imu_header(),
+            status,
+        };
+        self.diagnostics_publisher.publish(diagnostics)
+    }
+
+    /// Sensor health as a diagnostic status, an error once it's unhealthy
+    fn health_status(&self, hardware_id: &str) -> DiagnosticStatus {
+        let (level, message) = match self.health.state() {
+            HealthState::Healthy => (DiagnosticStatus::OK, "OK".to_string()),
+            HealthState::Degraded => (DiagnosticStatus::WARN, "Reads failing".to_string()),
+            HealthState::Unhealthy => (DiagnosticStatus::ERROR, "Unhealthy, no data published".to_string()),
+        };
+        let value = |key: &str, value: String| KeyValue {
+            key: key.to_string(),
+            value,
+        };
+        DiagnosticStatus {
+            level,
+            name: "imu_publisher: sensor".to_string(),
+            message,
+            hardware_id: hardware_id.to_string(),
+            values: vec![
+                value("consecutive_failures", self.health.consecutive_failures().to_string()),
+                value("total_failures", self.health.total_failures().to_string()),
+                value("reinitializations", self.health.reinits().to_string()),
+                value("fifo_overflows", self.health.overflows().to_string()),
+                value("last_error", self.health.last_error().unwrap_or("").to_string()),
+                value("mag_read_failures", self.mag_reads.failures().to_string()),
+                value("last_mag_error", self.mag_reads.last_error().unwrap_or("").to_string()),
+                value("temperature_read_failures", self.temperature_reads.failures().to_string()),
+                value("last_temperature_error", self.temperature_reads.last_error().unwrap_or("").to_string()),
+            ],
+        }
+    }
+
+    /// Publish a magnetometer sample, hard/soft iron corrected if calibrated, stamped when it was read
+    fn publish_mag(&self, sample: MagSample) -> Result<(), RclrsError> {
+        let header = imu_header_at(sample.timestamp);
+
+        let mag = match &self.calibration.mag {
+            Some(mag_calibration) => mag_calibration.apply(sample.field),
+            None => sample.field,
+        };
+        let mag = self.mounting.to_body(mag);
+
+        // sensor_msgs/MagneticField is in tesla
+        let mag_msg = MagneticField {
+            header,
+            magnetic_field: Vector3 {
+                x: mag[0] as f64 * 1e-6,
+                y: mag[1] as f64 * 1e-6,
+                z: mag[2] as f64 * 1e-6,
+            },
+            magnetic_field_covariance: [0.0; 9], // Unknown
+        };
+        self.mag_publisher.publish(mag_msg)
+    }
+}
+
+/// Low-pass filter parameters, read on every sample so `ros2 param set` applies without a restart
+struct FilterParameters {
+    sample_rate_hz: MandatoryParameter<f64>,
+    sensor_rate_hz: f32, // The IMU's output data rate, which sample_rate_hz is checked against
+    cutoff_hz: MandatoryParameter<f64>,
+    order: MandatoryParameter<i64>,
+    accel_enabled: MandatoryParameter<bool>,
+    gyro_enabled: MandatoryParameter<bool>,
+}
+
+impl FilterParameters {
+    fn settings(&self) -> FilterSettings {
+        FilterSettings {
+            sample_rate_hz: self.sample_rate_hz.get() as f32,
+            cutoff_hz: self.cutoff_hz.get() as f32,
+            order: self.order.get().max(0) as usize,
+        }
+    }
+
+    /// Fresh accel and gyro filters for `settings`, if they're designed for the sensor's rate
+    fn filters(&self, settings: &FilterSettings) -> Result<(LowPassFilter, LowPassFilter), ImuError> {
+        settings.check_sample_rate(self.sensor_rate_hz)?;
+        Ok((LowPassFilter::new(settings)?, LowPassFilter::new(settings)?))
+    }
+}
+
+/// Dynamic notch filter parameters, read on every sample like the low-pass filter's
+struct VibrationParameters {
+    enabled: MandatoryParameter<bool>,
+    count: MandatoryParameter<i64>,
+    q: MandatoryParameter<f64>,
+    min_hz: MandatoryParameter<f64>,
+    max_hz: MandatoryParameter<f64>,
+    window: MandatoryParameter<i64>,
+    min_snr: MandatoryParameter<f64>,
 }
 
-fn main() -> Result<(), Box<dyn std::error::Error>> {
-    let context = Context::new(std::env::args()).unwrap();
-    let mut publisher_node = IMUPublisherNode::new(&context).unwrap();
+impl VibrationParameters {
+    /// Settings for the analyzer and notches, None if they're disabled
+    fn settings(&self, sample_rate_hz: f32) -> Option<VibrationSettings> {
+        if !self.enabled.get() {
+            return None;
+        }
+        let window = self.window.get().max(0) as usize;
+        Some(VibrationSettings {
+            sample_rate_hz,
+            window,
+            hop: window / 4, // Re-analyze four times per window
+            min_hz: self.min_hz.get() as f32,
+            max_hz: self.max_hz.get() as f32,
+            peaks: self.count.get().max(0) as usize,
+            min_snr: self.min_snr.get() as f32,
+            notch_q: self.q.get() as f32,
+        })
+    }
+}
+
+/// Analyzer and notches for `settings`, searching no closer to the Nyquist frequency than they can
+/// follow. None, with a warning, if the sample rate is too low to track anything above `min_hz`.
+fn vibration_filters(settings: VibrationSettings) -> Result<Option<(VibrationAnalyzer, NotchBank)>, ImuError> {
+    let Some(limited) = settings.within_nyquist() else {
+        eprintln!(
+            "Notch filters disabled: nothing above {} Hz can be tracked at {:.1} Hz",
+            settings.min_hz, settings.sample_rate_hz
+        );
+        return Ok(None);
+    };
+    if limited.max_hz < settings.max_hz {
+        eprintln!(
+            "Notch filters limited to {:.1} Hz, below the {:.1} Hz Nyquist frequency",
+            limited.max_hz,
+            settings.sample_rate_hz / 2.0
+        );
+    }
+    Ok(Some((VibrationAnalyzer::new(limited)?, NotchBank::new(&limited))))
+}
+
+/// Static transform from base_link to imu_link. The samples are already rotated into body axes, so
+/// only the IMU's position is left for the transform to carry.
+fn imu_transform(mounting: &Mounting) -> TFMessage {
+    let [x, y, z] = mounting.translation;
+    let mut header = imu_header();
+    header.frame_id = "base_link".to_string();
+    TFMessage {
+        transforms: vec![TransformStamped {
+            header,
+            child_frame_id: "imu_link".to_string(),
+            transform: Transform {
+                translation: Vector3 { x, y, z },
+                rotation: Quaternion {
+                    x: 0.0,
+                    y: 0.0,
+                    z: 0.0,
+                    w: 1.0,
+                },
+            },
+        }],
+    }
+}
+
+/// Header stamped with the current time in the IMU frame
+fn imu_header() -> std_msgs::msg::Header {
+    imu_header_at(SystemTime::now().duration_since(UNIX_EPOCH).unwrap())
+}
+
+/// Header in the IMU frame stamped with `stamp` since the UNIX epoch
+fn imu_header_at(stamp: Duration) -> std_msgs::msg::Header {
+    std_msgs::msg::Header {
+        stamp: builtin_interfaces::msg::Time {
+            sec: stamp.as_secs() as i32,
+            nanosec: stamp.subsec_nanos(),
+        },
+        frame_id: "imu_link".to_string(),
+    }
+}
+
+fn main() -> Result<(), ImuError> {
+    let context = Context::new(std::env::args())?;
+    let mut publisher_node = IMUPublisherNode::new(&context)?;
 
     // Initialize the IMU explicitly
     if let Err(err) = publisher_node.initialize_imu() {
         eprintln!("Failed to initialize IMU: {}", err);
-        return Err(Box::new(std::io::Error::new(std::io::ErrorKind::Other, err)));
+        return Err(err);
     }
 
     let node_handle = publisher_node.node.clone(); // Clone the ROS2 node for spinning
+    let scheduler_config = publisher_node.scheduler_config.clone();
     let publisher_node = Arc::new(Mutex::new(publisher_node));
     let publisher_node_thread = Arc::clone(&publisher_node);
 
     // Spawn a thread for publishing data
     std::thread::spawn(move || {
-        let mut last_time = std::time::Instant::now();
+        // Priority and CPU pinning apply to the calling thread, so the scheduler is created here
+        let mut scheduler = PeriodicScheduler::new(scheduler_config);
         loop {
-            // Set up loop to publish as closely to X Hz as possible
-
-
-            // Calculate elapsed time since the last loop
-            let elapsed = last_time.elapsed();
-            let elapsed_ms = elapsed.as_millis() as u64;
+            // Sleep until the next absolute deadline, so the rate doesn't drift with the loop's run time
+            scheduler.wait();
 
-            // Check if we've exceeded 5 ms
-            if elapsed_ms >= 1 {
-                // Update the timestamp for the next cycle
-                last_time = std::time::Instant::now();
-
-                let start = std::time::Instant::now();
+            // Call the publish data method
+            if let Ok(mut node) = publisher_node_thread.lock() {
+                if let Err(err) = node.publish_data() {
+                    eprintln!("Error publishing IMU data: {:?}", err);
+                }
 
-                // Call the publish data method
-                if let Ok(mut node) = publisher_node_thread.lock() {
-                    if let Err(err) = node.publish_data() {
-                        eprintln!("Error publishing IMU data: {:?}", err);
+                if let Some(stats) = scheduler.take_report() {
+                    if let Err(err) = node.publish_diagnostics(Some(&stats)) {
+                        eprintln!("Error publishing IMU diagnostics: {:?}", err);
                     }
-                } else {
-                    eprintln!("Failed to lock publisher node.");
                 }
-
-                let duration = start.elapsed();
-                //println!("IMU publish loop execution time: {} µs", duration.as_micros());
-
             } else {
-                // Sleep for the remaining time in the Y ms window
-                std::thread::sleep(Duration::from_micros((1000 - elapsed.as_micros() as u64) as u64));
+                eprintln!("Failed to lock publisher node.");
             }
         }
     });
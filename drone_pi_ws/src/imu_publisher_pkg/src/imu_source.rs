@@ -0,0 +1,671 @@
+use icm20948_driver_rust::imu::IMU; // Custom Rust driver, https://github.com/OrlandoQuintana/icm20948-driver-rust
+use icm20948_driver_rust::spi_core::SpiCore;
+use crate::error::ImuError;
+use crate::fifo::Fifo;
+use crate::magnetometer::Ak09916;
+use crate::mock_spi::MockIcm20948;
+use crate::registers::{Register, Registers, SharedSpi, ACCEL_XOUT_H, GYRO_XOUT_H, TEMP_OUT_H};
+use crate::sensor_config::SensorConfig;
+use embedded_hal::spi::SpiBus;
+use linux_embedded_hal::spidev::{Spidev, SpidevOptions, SpiModeFlags};
+use linux_embedded_hal::SpidevBus;
+use rand::rngs::StdRng;
+use rand::SeedableRng;
+use rand_distr::{Distribution, Normal};
+use std::f64::consts::PI;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+/// Standard gravity in m/s^2
+pub const GRAVITY: f64 = 9.80665;
+
+/// ICM-20948 temperature sensor sensitivity, LSB per degree C
+const TEMPERATURE_SENSITIVITY: f32 = 333.87;
+
+/// Temperature at which the ICM-20948's temperature sensor reads 0, degrees C
+const TEMPERATURE_OFFSET: f32 = 21.0;
+
+/// Rate of the AK09916's continuous measurement mode, which the synthetic magnetometer matches
+const MAG_SAMPLE_RATE_HZ: f64 = 100.0;
+
+/// Accelerometer and gyroscope samples taken at the same instant
+#[derive(Clone, Copy, Debug)]
+pub struct ImuFrame {
+    pub accel: [f32; 3],
+    pub gyro: [f32; 3],
+    pub timestamp: Duration, // Since the UNIX epoch
+}
+
+/// Magnetometer sample and when it was read
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct MagSample {
+    pub field: [f32; 3],     // uT
+    pub timestamp: Duration, // Since the UNIX epoch
+}
+
+/// Anything that can produce accelerometer and gyroscope samples for the IMU publisher.
+///
+/// Accelerometer samples are in m/s^2, gyroscope samples in rad/s and magnetometer samples in micro
+/// tesla, all in the accelerometer's sensor frame.
+pub trait ImuSource: Send {
+    /// Bring the sensor into a state where reads return valid data
+    fn initialize(&mut self) -> Result<(), ImuError>;
+
+    /// Read one accelerometer sample [x, y, z]
+    fn read_accel(&mut self) -> Result<[f32; 3], ImuError>;
+
+    /// Read one gyroscope sample [x, y, z]
+    fn read_gyro(&mut self) -> Result<[f32; 3], ImuError>;
+
+    /// Read every accel/gyro sample produced since the last call, oldest first.
+    ///
+    /// Sources without a sample buffer read one of each and stamp it with the current time.
+    fn read_frames(&mut self) -> Result<Vec<ImuFrame>, ImuError> {
+        let accel = self.read_accel()?;
+        let gyro = self.read_gyro()?;
+        Ok(vec![ImuFrame {
+            accel,
+            gyro,
+            timestamp: SystemTime::now().duration_since(UNIX_EPOCH).unwrap(),
+        }])
+    }
+
+    /// Read the magnetometer, None if there's no new sample since the last read or no magnetometer
+    fn read_mag(&mut self) -> Result<Option<MagSample>, ImuError> {
+        Ok(None)
+    }
+
+    /// Read the die temperature in degrees C, None if the sensor has no thermometer
+    fn read_temperature(&mut self) -> Result<Option<f32>, ImuError> {
+        Ok(None)
+    }
+
+    /// Stable name for this physical sensor, used to key its calibration file
+    fn sensor_id(&self) -> String;
+}
+
+/// Open the IMU backend named `kind`: "icm20948" on `device`, "synthetic", or "mock", sampling at the
+/// rate and ranges in `config`
+pub fn open_source(kind: &str, device: &str, config: SensorConfig) -> Result<Box<dyn ImuSource>, ImuError> {
+    match kind {
+        "icm20948" => Ok(Box::new(Icm20948Source::open(device, config)?)),
+        "synthetic" => Ok(Box::new(SyntheticSource::new(SyntheticConfig {
+            sample_rate_hz: config.sample_rate_hz(),
+            ..SyntheticConfig::default()
+        })?)),
+        "mock" => Ok(Box::new(MockIcm20948Source::new(SyntheticConfig::default(), config)?)),
+        other => Err(ImuError::Config(format!(
+            "Unknown IMU source '{}', expected 'icm20948', 'synthetic' or 'mock'",
+            other
+        ))),
+    }
+}
+
+/// ICM-20948 connected over SPI on the Raspberry Pi
+pub struct Icm20948Source {
+    path: String,
+    imu: IMU<SharedSpi<SpidevBus>>,
+    registers: Registers<SpidevBus>,
+    config: SensorConfig,
+    fifo: Option<Fifo>,   // None until initialized
+    mag: Option<Ak09916>, // None until initialized, or if the magnetometer didn't respond
+}
+
+impl Icm20948Source {
+    /// Open and configure the SPI device (e.g. `/dev/spidev0.0`) the IMU is wired to
+    pub fn open(path: &str, config: SensorConfig) -> Result<Self, ImuError> {
+        let open_error = |source| ImuError::Open {
+            path: path.to_string(),
+            source,
+        };
+        let mut spidev = Spidev::open(path).map_err(open_error)?;
+        spidev
+            .configure(
+                &SpidevOptions::new()
+                    .bits_per_word(8)
+                    .max_speed_hz(7_000_000)
+                    .mode(SpiModeFlags::SPI_MODE_0)
+                    .build(),
+            )
+            .map_err(open_error)?;
+
+        // The driver and our own register access share the bus
+        let bus = Arc::new(Mutex::new(SpidevBus(spidev)));
+        let spi = Arc::new(Mutex::new(SpiCore::new(SharedSpi(Arc::clone(&bus)))));
+
+        Ok(Self {
+            path: path.to_string(),
+            imu: IMU::new(spi),
+            registers: Registers::new(bus),
+            config,
+            fifo: None,
+            mag: None,
+        })
+    }
+}
+
+impl ImuSource for Icm20948Source {
+    fn initialize(&mut self) -> Result<(), ImuError> {
+        self.imu
+            .initialize()
+            .map_err(|e| ImuError::Driver(format!("initialization failed: {:?}", e)))?;
+
+        // Replace the driver's ranges and rates with ours before streaming into the FIFO
+        self.config.apply(&self.registers)?;
+        self.fifo = Some(Fifo::initialize(&self.registers, self.config.fifo_config())?);
+
+        // Accel and gyro are still usable without the magnetometer
+        self.mag = match Ak09916::initialize(&self.registers) {
+            Ok(mag) => Some(mag),
+            Err(err) => {
+                eprintln!("Magnetometer initialization failed, continuing without it: {}", err);
+                None
+            }
+        };
+        Ok(())
+    }
+
+    fn read_accel(&mut self) -> Result<[f32; 3], ImuError> {
+        read_vector(&self.registers, ACCEL_XOUT_H, self.config.accel_range.scale())
+    }
+
+    fn read_gyro(&mut self) -> Result<[f32; 3], ImuError> {
+        read_vector(&self.registers, GYRO_XOUT_H, self.config.gyro_range.scale())
+    }
+
+    fn read_frames(&mut self) -> Result<Vec<ImuFrame>, ImuError> {
+        match self.fifo.as_mut() {
+            Some(fifo) => fifo.read(&self.registers),
+            None => Err(ImuError::NotInitialized),
+        }
+    }
+
+    fn read_mag(&mut self) -> Result<Option<MagSample>, ImuError> {
+        let Some(mag) = self.mag.as_mut() else {
+            return Ok(None);
+        };
+        let timestamp = SystemTime::now().duration_since(UNIX_EPOCH).unwrap();
+        Ok(mag.read(&self.registers)?.map(|field| MagSample { field, timestamp }))
+    }
+
+    fn read_temperature(&mut self) -> Result<Option<f32>, ImuError> {
+        let mut bytes = [0u8; 2];
+        self.registers.read_burst(TEMP_OUT_H, &mut bytes)?;
+        Ok(Some(i16::from_be_bytes(bytes) as f32 / TEMPERATURE_SENSITIVITY + TEMPERATURE_OFFSET))
+    }
+
+    fn sensor_id(&self) -> String {
+        // e.g. icm20948_spidev0.0
+        let device = self.path.rsplit('/').next().unwrap_or(&self.path);
+        format!("icm20948_{}", device)
+    }
+}
+
+/// Read three consecutive big-endian axis registers starting at `register` and scale them to SI units
+fn read_vector<B: SpiBus>(registers: &Registers<B>, register: Register, scale: f32) -> Result<[f32; 3], ImuError> {
+    let mut bytes = [0u8; 6];
+    registers.read_burst(register, &mut bytes)?;
+    Ok([0, 1, 2].map(|i| i16::from_be_bytes([bytes[2 * i], bytes[2 * i + 1]]) as f32 * scale))
+}
+
+/// Motion the synthetic IMU goes through
+#[derive(Clone, Debug)]
+pub enum RotationProfile {
+    /// Sitting level and still on the bench
+    Stationary,
+    /// Constant body rate [x, y, z] in rad/s
+    ConstantRate([f64; 3]),
+    /// Sinusoidal rocking about each axis, peak angle in rad
+    Oscillate { amplitude: [f64; 3], frequency_hz: f64 },
+}
+
+/// Settings for the synthetic IMU
+#[derive(Clone, Debug)]
+pub struct SyntheticConfig {
+    pub sample_rate_hz: f64,
+    pub accel_noise_std: f64,      // m/s^2
+    pub gyro_noise_std: f64,       // rad/s
+    pub accel_bias: [f64; 3],      // m/s^2
+    pub gyro_bias: [f64; 3],       // rad/s, at the startup temperature
+    pub mag_noise_std: f64,        // uT
+    pub earth_field: [f64; 3],     // uT, world frame (x east, y north, z up)
+    pub hard_iron: [f64; 3],       // uT
+    pub temperature: f64,          // Degrees C at startup
+    pub warmup: f64,               // Degrees C the sensor warms up by after startup
+    pub warmup_time_constant: f64, // s
+    pub gyro_bias_drift: [f64; 3], // rad/s per degree C of warm-up
+    pub rotation: RotationProfile,
+    pub seed: u64,
+}
+
+impl Default for SyntheticConfig {
+    fn default() -> Self {
+        // Noise and bias roughly in line with what the ICM-20948 shows on the bench
+        Self {
+            sample_rate_hz: 1100.0, // The ICM-20948's FIFO rate, set by the gyro
+            accel_noise_std: 0.02,
+            gyro_noise_std: 0.002,
+            accel_bias: [0.0; 3],
+            gyro_bias: [-0.0007, 0.013, 0.006],
+            mag_noise_std: 0.3,
+            earth_field: [0.0, 22.0, -42.0],
+            hard_iron: [0.0; 3],
+            temperature: 25.0,
+            warmup: 10.0,
+            warmup_time_constant: 120.0,
+            gyro_bias_drift: [1.0e-4, -1.5e-4, 0.5e-4],
+            rotation: RotationProfile::Stationary,
+            seed: 0,
+        }
+    }
+}
+
+/// Simulated IMU for running the publisher without hardware.
+///
+/// Each accel/gyro read pair is one simulation step of `1 / sample_rate_hz` seconds; the attitude
+/// advances when the gyroscope is read. `read_frames` steps the simulation in real time, producing
+/// however many samples the rate calls for since the last read like a sensor FIFO would. The
+/// magnetometer has a new sample every 10 ms of simulation time, like the AK09916.
+pub struct SyntheticSource {
+    config: SyntheticConfig,
+    attitude: [f64; 4], // Body to world quaternion [w, x, y, z]
+    time: f64,
+    next_mag: f64, // Simulation time of the next magnetometer sample
+    started: Option<(Instant, Duration)>, // Real time the simulation started, and as a UNIX timestamp
+    steps: u64,                           // Samples produced by read_frames
+    rng: StdRng,
+    accel_noise: Normal<f64>,
+    gyro_noise: Normal<f64>,
+    mag_noise: Normal<f64>,
+}
+
+impl SyntheticSource {
+    pub fn new(config: SyntheticConfig) -> Result<Self, ImuError> {
+        let accel_noise = Normal::new(0.0, config.accel_noise_std).map_err(|e| ImuError::Config(format!("Invalid accel noise: {}", e)))?;
+        let gyro_noise = Normal::new(0.0, config.gyro_noise_std).map_err(|e| ImuError::Config(format!("Invalid gyro noise: {}", e)))?;
+        let mag_noise = Normal::new(0.0, config.mag_noise_std).map_err(|e| ImuError::Config(format!("Invalid mag noise: {}", e)))?;
+        if config.sample_rate_hz <= 0.0 {
+            return Err(ImuError::Config(format!("Invalid sample rate: {}", config.sample_rate_hz)));
+        }
+
+        Ok(Self {
+            rng: StdRng::seed_from_u64(config.seed),
+            config,
+            attitude: [1.0, 0.0, 0.0, 0.0],
+            time: 0.0,
+            next_mag: 0.0,
+            started: None,
+            steps: 0,
+            accel_noise,
+            gyro_noise,
+            mag_noise,
+        })
+    }
+
+    /// Die temperature at the current simulation time, warming up exponentially
+    fn temperature(&self) -> f64 {
+        let warmup = self.config.warmup * (1.0 - (-self.time / self.config.warmup_time_constant).exp());
+        self.config.temperature + warmup
+    }
+
+    /// UNIX timestamp of simulation time `time`, on the timeline `read_frames` stamps samples with
+    fn stamp(&self, time: f64) -> Duration {
+        match self.started {
+            Some((_, start_stamp)) => start_stamp + Duration::from_secs_f64(time),
+            None => SystemTime::now().duration_since(UNIX_EPOCH).unwrap(), // Read before any frames
+        }
+    }
+
+    /// True body rate at the current simulation time
+    fn body_rate(&self) -> [f64; 3] {
+        match &self.config.rotation {
+            RotationProfile::Stationary => [0.0; 3],
+            RotationProfile::ConstantRate(rate) => *rate,
+            RotationProfile::Oscillate { amplitude, frequency_hz } => {
+                // Derivative of amplitude * sin(2 pi f t)
+                let w = 2.0 * PI * frequency_hz;
+                let c = (w * self.time).cos();
+                [amplitude[0] * w * c, amplitude[1] * w * c, amplitude[2] * w * c]
+            }
+        }
+    }
+}
+
+impl ImuSource for SyntheticSource {
+    fn initialize(&mut self) -> Result<(), ImuError> {
+        self.attitude = [1.0, 0.0, 0.0, 0.0];
+        self.time = 0.0;
+        self.next_mag = 0.0;
+        self.started = None;
+        self.steps = 0;
+        Ok(())
+    }
+
+    fn read_frames(&mut self) -> Result<Vec<ImuFrame>, ImuError> {
+        let (start, start_stamp) = *self
+            .started
+            .get_or_insert_with(|| (Instant::now(), SystemTime::now().duration_since(UNIX_EPOCH).unwrap()));
+
+        let due = (start.elapsed().as_secs_f64() * self.config.sample_rate_hz) as u64;
+        let mut frames = Vec::new();
+        while self.steps < due {
+            let timestamp = start_stamp + Duration::from_secs_f64(self.steps as f64 / self.config.sample_rate_hz);
+            let accel = self.read_accel()?;
+            let gyro = self.read_gyro()?;
+            frames.push(ImuFrame { accel, gyro, timestamp });
+            self.steps += 1;
+        }
+        Ok(frames)
+    }
+
+    fn read_accel(&mut self) -> Result<[f32; 3], ImuError> {
+        // A resting accelerometer measures the reaction to gravity, +g along world Z, seen in the body frame
+        let specific_force = rotate_world_to_body(self.attitude, [0.0, 0.0, GRAVITY]);
+
+        let mut sample = [0.0; 3];
+        for (i, value) in sample.iter_mut().enumerate() {
+            let noise = self.accel_noise.sample(&mut self.rng);
+            *value = (specific_force[i] + self.config.accel_bias[i] + noise) as f32;
+        }
+        Ok(sample)
+    }
+
+    fn read_gyro(&mut self) -> Result<[f32; 3], ImuError> {
+        let rate = self.body_rate();
+        let warmup = self.temperature() - self.config.temperature;
+
+        let mut sample = [0.0; 3];
+        for (i, value) in sample.iter_mut().enumerate() {
+            let noise = self.gyro_noise.sample(&mut self.rng);
+            let bias = self.config.gyro_bias[i] + self.config.gyro_bias_drift[i] * warmup;
+            *value = (rate[i] + bias + noise) as f32;
+        }
+
+        // Advance the simulation by one sample period
+        let dt = 1.0 / self.config.sample_rate_hz;
+        self.attitude = integrate_rate(self.attitude, rate, dt);
+        self.time += dt;
+
+        Ok(sample)
+    }
+
+    fn read_mag(&mut self) -> Result<Option<MagSample>, ImuError> {
+        if self.time < self.next_mag {
+            return Ok(None);
+        }
+        self.next_mag = ((self.time * MAG_SAMPLE_RATE_HZ).floor() + 1.0) / MAG_SAMPLE_RATE_HZ;
+
+        let field = rotate_world_to_body(self.attitude, self.config.earth_field);
+
+        let mut sample = [0.0; 3];
+        for (i, value) in sample.iter_mut().enumerate() {
+            let noise = self.mag_noise.sample(&mut self.rng);
+            *value = (field[i] + self.config.hard_iron[i] + noise) as f32;
+        }
+        Ok(Some(MagSample {
+            field: sample,
+            timestamp: self.stamp(self.time),
+        }))
+    }
+
+    fn read_temperature(&mut self) -> Result<Option<f32>, ImuError> {
+        Ok(Some(self.temperature() as f32))
+    }
+
+    fn sensor_id(&self) -> String {
+        "synthetic".to_string()
+    }
+}
+
+/// The synthetic IMU behind the register-level mock sensor.
+///
+/// Samples are converted to raw counts and written into `MockIcm20948`'s FIFO in real time, then
+/// read back out through the same `Fifo` code as the real sensor, so that path can be exercised
+/// without hardware.
+pub struct MockIcm20948Source {
+    simulation: SyntheticSource,
+    device: Arc<Mutex<MockIcm20948>>,
+    registers: Registers<MockIcm20948>,
+    sensor_config: SensorConfig,
+    fifo: Option<Fifo>, // None until initialized
+}
+
+impl MockIcm20948Source {
+    pub fn new(config: SyntheticConfig, sensor_config: SensorConfig) -> Result<Self, ImuError> {
+        // The simulation produces samples at the rate the mock sensor is configured for
+        let config = SyntheticConfig {
+            sample_rate_hz: sensor_config.sample_rate_hz(),
+            ..config
+        };
+        let device = Arc::new(Mutex::new(MockIcm20948::new()));
+        Ok(Self {
+            simulation: SyntheticSource::new(config)?,
+            registers: Registers::new(Arc::clone(&device)),
+            device,
+            sensor_config,
+            fifo: None,
+        })
+    }
+
+    /// Convert a sample to raw counts, saturating like the sensor does at full scale
+    fn to_counts(sample: [f32; 3], scale: f32) -> [i16; 3] {
+        sample.map(|value| (value / scale).round().clamp(i16::MIN as f32, i16::MAX as f32) as i16)
+    }
+}
+
+impl ImuSource for MockIcm20948Source {
+    fn initialize(&mut self) -> Result<(), ImuError> {
+        self.simulation.initialize()?;
+        self.sensor_config.apply(&self.registers)?;
+        self.fifo = Some(Fifo::initialize(&self.registers, self.sensor_config.fifo_config())?);
+        Ok(())
+    }
+
+    fn read_accel(&mut self) -> Result<[f32; 3], ImuError> {
+        self.simulation.read_accel()
+    }
+
+    fn read_gyro(&mut self) -> Result<[f32; 3], ImuError> {
+        self.simulation.read_gyro()
+    }
+
+    fn read_frames(&mut self) -> Result<Vec<ImuFrame>, ImuError> {
+        let fifo = self.fifo.as_mut().ok_or(ImuError::NotInitialized)?;
+
+        // Whatever the sensor would have sampled since the last read goes into its FIFO
+        {
+            let mut device = self.device.lock().unwrap();
+            for frame in self.simulation.read_frames()? {
+                device.push_frame(
+                    Self::to_counts(frame.accel, self.sensor_config.accel_range.scale()),
+                    Self::to_counts(frame.gyro, self.sensor_config.gyro_range.scale()),
+                );
+            }
+        }
+
+        fifo.read(&self.registers)
+    }
+
+    fn read_mag(&mut self) -> Result<Option<MagSample>, ImuError> {
+        self.simulation.read_mag()
+    }
+
+    fn read_temperature(&mut self) -> Result<Option<f32>, ImuError> {
+        self.simulation.read_temperature()
+    }
+
+    fn sensor_id(&self) -> String {
+        "mock_icm20948".to_string()
+    }
+}
+
+/// Rotate q by a body rate held constant over dt
+fn integrate_rate(q: [f64; 4], rate: [f64; 3], dt: f64) -> [f64; 4] {
+    let angle = (rate[0] * rate[0] + rate[1] * rate[1] + rate[2] * rate[2]).sqrt() * dt;
+    if angle < 1e-12 {
+        return q;
+    }
+    let half_sin = (angle / 2.0).sin() / (angle / dt);
+    let dq = [(angle / 2.0).cos(), rate[0] * half_sin, rate[1] * half_sin, rate[2] * half_sin];
+
+    let [w1, x1, y1, z1] = q;
+    let [w2, x2, y2, z2] = dq;
+    let out = [
+        w1 * w2 - x1 * x2 - y1 * y2 - z1 * z2,
+        w1 * x2 + x1 * w2 + y1 * z2 - z1 * y2,
+        w1 * y2 - x1 * z2 + y1 * w2 + z1 * x2,
+        w1 * z2 + x1 * y2 - y1 * x2 + z1 * w2,
+    ];
+    let norm = out.iter().map(|c| c * c).sum::<f64>().sqrt();
+    [out[0] / norm, out[1] / norm, out[2] / norm, out[3] / norm]
+}
+
+/// Express a world frame vector in the body frame of the body to world quaternion q
+fn rotate_world_to_body(q: [f64; 4], v: [f64; 3]) -> [f64; 3] {
+    let [w, x, y, z] = q;
+    // Transpose of the body to world rotation matrix
+    [
+        (1.0 - 2.0 * (y * y + z * z)) * v[0] + 2.0 * (x * y + w * z) * v[1] + 2.0 * (x * z - w * y) * v[2],
+        2.0 * (x * y - w * z) * v[0] + (1.0 - 2.0 * (x * x + z * z)) * v[1] + 2.0 * (y * z + w * x) * v[2],
+        2.0 * (x * z + w * y) * v[0] + 2.0 * (y * z - w * x) * v[1] + (1.0 - 2.0 * (x * x + y * y)) * v[2],
+    ]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Still, noise free and at a constant temperature, so only what a test sets shows up
+    fn quiet_config() -> SyntheticConfig {
+        SyntheticConfig {
+            accel_noise_std: 0.0,
+            gyro_noise_std: 0.0,
+            gyro_bias: [0.0; 3],
+            mag_noise_std: 0.0,
+            warmup: 0.0,
+            ..SyntheticConfig::default()
+        }
+    }
+
+    /// Mean and standard deviation of each axis over `count` reads
+    fn statistics(mut read: impl FnMut() -> [f32; 3], count: usize) -> ([f64; 3], [f64; 3]) {
+        let mut sum = [0.0; 3];
+        let mut square_sum = [0.0; 3];
+        for _ in 0..count {
+            let sample = read();
+            for axis in 0..3 {
+                sum[axis] += sample[axis] as f64;
+                square_sum[axis] += (sample[axis] as f64).powi(2);
+            }
+        }
+        let n = count as f64;
+        let mean = sum.map(|s| s / n);
+        let std = [0, 1, 2].map(|axis| (square_sum[axis] / n - mean[axis] * mean[axis]).max(0.0).sqrt());
+        (mean, std)
+    }
+
+    /// Step the simulation by `seconds` of gyro reads
+    fn run(source: &mut SyntheticSource, seconds: f64) {
+        for _ in 0..(seconds * source.config.sample_rate_hz).round() as usize {
+            source.read_gyro().unwrap();
+        }
+    }
+
+    #[test]
+    fn bias_and_noise_show_up_in_the_samples() {
+        let config = SyntheticConfig {
+            accel_noise_std: 0.05,
+            gyro_noise_std: 0.004,
+            accel_bias: [0.1, -0.2, 0.3],
+            gyro_bias: [0.01, -0.02, 0.03],
+            warmup: 0.0,
+            ..SyntheticConfig::default()
+        };
+        let mut source = SyntheticSource::new(config).unwrap();
+        let count = 20_000;
+
+        // Means within 5 standard errors, standard deviations within 5 %
+        let (mean, std) = statistics(|| source.read_accel().unwrap(), count);
+        let expected = [0.1, -0.2, GRAVITY + 0.3];
+        for axis in 0..3 {
+            assert!((mean[axis] - expected[axis]).abs() < 5.0 * 0.05 / (count as f64).sqrt(), "{:?}", mean);
+            assert!((std[axis] / 0.05 - 1.0).abs() < 0.05, "{:?}", std);
+        }
+
+        let (mean, std) = statistics(|| source.read_gyro().unwrap(), count);
+        for axis in 0..3 {
+            assert!((mean[axis] - [0.01, -0.02, 0.03][axis]).abs() < 5.0 * 0.004 / (count as f64).sqrt(), "{:?}", mean);
+            assert!((std[axis] / 0.004 - 1.0).abs() < 0.05, "{:?}", std);
+        }
+    }
+
+    #[test]
+    fn constant_rate_integrates_to_the_expected_attitude() {
+        let mut source = SyntheticSource::new(SyntheticConfig {
+            rotation: RotationProfile::ConstantRate([0.5, 0.0, 0.0]),
+            ..quiet_config()
+        })
+        .unwrap();
+        run(&mut source, 1.0);
+
+        // 0.5 rad about body X
+        let expected = [0.25f64.cos(), 0.25f64.sin(), 0.0, 0.0];
+        for (actual, expected) in source.attitude.iter().zip(expected) {
+            assert!((actual - expected).abs() < 1e-9, "{:?}", source.attitude);
+        }
+        let accel = source.read_accel().unwrap();
+        let expected = [0.0, GRAVITY * 0.5f64.sin(), GRAVITY * 0.5f64.cos()];
+        for axis in 0..3 {
+            assert!((accel[axis] as f64 - expected[axis]).abs() < 1e-5, "{:?}", accel);
+        }
+    }
+
+    #[test]
+    fn oscillation_reaches_its_amplitude_and_comes_back() {
+        let mut source = SyntheticSource::new(SyntheticConfig {
+            rotation: RotationProfile::Oscillate {
+                amplitude: [0.0, 0.3, 0.0],
+                frequency_hz: 1.0,
+            },
+            ..quiet_config()
+        })
+        .unwrap();
+
+        // A quarter period in, pitched to the peak; a full period in, level again. The rate is held
+        // over each step, so the angle is off by up to about a step's worth of rotation
+        run(&mut source, 0.25);
+        let pitch = 2.0 * source.attitude[2].atan2(source.attitude[0]);
+        assert!((pitch - 0.3).abs() < 2e-3, "{}", pitch);
+        run(&mut source, 0.75);
+        let pitch = 2.0 * source.attitude[2].atan2(source.attitude[0]);
+        assert!(pitch.abs() < 2e-3, "{}", pitch);
+    }
+
+    #[test]
+    fn magnetometer_updates_at_100_hz() {
+        let mut source = SyntheticSource::new(quiet_config()).unwrap();
+        let mut samples = 0;
+        // One second of samples at 1100 Hz
+        for _ in 0..1100 {
+            if let Some(mag) = source.read_mag().unwrap() {
+                assert_eq!(mag.field.map(|m| m as f64), [0.0, 22.0, -42.0]);
+                samples += 1;
+            }
+            source.read_gyro().unwrap();
+        }
+        assert_eq!(samples, 100);
+        // Just past 1 s the next sample is due, then nothing new until the simulation moves on
+        source.read_gyro().unwrap();
+        assert!(source.read_mag().unwrap().is_some());
+        assert_eq!(source.read_mag().unwrap(), None);
+    }
+
+    #[test]
+    fn unknown_backend_is_rejected() {
+        assert!(open_source("synthetic", "", SensorConfig::default()).is_ok());
+        assert!(matches!(
+            open_source("bmi088", "/dev/spidev0.0", SensorConfig::default()),
+            Err(ImuError::Config(_))
+        ));
+    }
+}
@@ -0,0 +1,17 @@
+//! Shared building blocks for the IMU publisher node and its tools.
+
+pub mod calibration;
+pub mod decimation;
+pub mod error;
+pub mod fifo;
+pub mod filter;
+pub mod fitting;
+pub mod health;
+pub mod imu_source;
+pub mod magnetometer;
+pub mod mock_spi;
+pub mod mounting;
+pub mod noise;
+pub mod registers;
+pub mod sensor_config;
+pub mod vibration;
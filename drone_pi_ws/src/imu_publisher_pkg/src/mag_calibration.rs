@@ -0,0 +1,84 @@
+//! Magnetometer hard/soft iron calibration.
+//!
+//! Collects magnetometer samples while the IMU is slowly rotated through as many orientations as
+//! possible, fits an ellipsoid to them, and writes the correction to the sensor's calibration file
+//! where `imu_publisher` picks it up on its next start. Calibrate with the IMU mounted in the frame,
+//! since the motors, battery and wiring are what distort the field.
+//!
+//! Usage: mag_calibration [--source icm20948|synthetic] [--device /dev/spidev0.0]
+//!                        [--duration 60] [--calibration-dir DIR]
+use imu_publisher_pkg::calibration::{self, CalibrationFile};
+use imu_publisher_pkg::fitting::fit_mag_calibration;
+use imu_publisher_pkg::imu_source;
+use imu_publisher_pkg::sensor_config::SensorConfig;
+use std::io::{self, BufRead, Write};
+use std::path::PathBuf;
+use std::thread;
+use std::time::{Duration, Instant};
+
+fn main() -> Result<(), Box<dyn std::error::Error>> {
+    let mut source_kind = "icm20948".to_string();
+    let mut device = "/dev/spidev0.0".to_string();
+    let mut duration = 60.0;
+    let mut calibration_dir = calibration::default_calibration_dir();
+
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        let mut value = || args.next().ok_or(format!("Missing value for {}", arg));
+        match arg.as_str() {
+            "--source" => source_kind = value()?,
+            "--device" => device = value()?,
+            "--duration" => duration = value()?.parse()?,
+            "--calibration-dir" => calibration_dir = PathBuf::from(value()?),
+            _ => return Err(format!("Unknown argument {}", arg).into()),
+        }
+    }
+
+    let mut source = imu_source::open_source(&source_kind, &device, SensorConfig::default())?;
+    source.initialize()?;
+
+    print!("Press Enter, then slowly rotate the IMU through every orientation for {} s ", duration);
+    io::stdout().flush()?;
+    io::stdin().lock().read_line(&mut String::new())?;
+
+    let mut samples = Vec::new();
+    let start = Instant::now();
+    let mut last_report = 0;
+    while start.elapsed().as_secs_f64() < duration {
+        if let Some(mag) = source.read_mag()? {
+            samples.push(mag.field);
+        }
+        let elapsed = start.elapsed().as_secs();
+        if elapsed > last_report {
+            last_report = elapsed;
+            println!("  {} s, {} samples", elapsed, samples.len());
+        }
+        thread::sleep(Duration::from_millis(5));
+    }
+
+    let mag = fit_mag_calibration(&samples)?;
+    println!("Hard iron:      {:?}", mag.hard_iron);
+    println!("Soft iron:      {:?}", mag.soft_iron);
+    println!("Field strength: {:.2} uT", mag.field_strength);
+
+    // How round the corrected samples are, as a sanity check of the fit and the coverage
+    let norms: Vec<f32> = samples
+        .iter()
+        .map(|sample| {
+            let corrected = mag.apply(*sample);
+            (corrected[0].powi(2) + corrected[1].powi(2) + corrected[2].powi(2)).sqrt()
+        })
+        .collect();
+    let mean = norms.iter().sum::<f32>() / norms.len() as f32;
+    let std = (norms.iter().map(|n| (n - mean).powi(2)).sum::<f32>() / norms.len() as f32).sqrt();
+    println!("Corrected magnitude: {:.2} +/- {:.2} uT", mean, std);
+
+    // Keep any other calibration already stored for this sensor
+    let path = calibration::calibration_path(&calibration_dir, &source.sensor_id());
+    let mut file = CalibrationFile::load(&path)?.unwrap_or_default();
+    file.mag = Some(mag);
+    file.save(&path)?;
+    println!("Saved magnetometer calibration to {}", path.display());
+
+    Ok(())
+}
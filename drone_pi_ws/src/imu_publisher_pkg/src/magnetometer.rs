@@ -0,0 +1,220 @@
+//! AK09916 magnetometer inside the ICM-20948.
+//!
+//! The AK09916 sits on the ICM-20948's auxiliary I2C bus. The ICM's I2C master is set up to copy the
+//! magnetometer's data registers into EXT_SLV_SENS_DATA on every cycle (slave 0), so a reading is a
+//! single SPI burst. One-off register accesses during setup go through slave 4.
+
+use crate::error::ImuError;
+use crate::registers::*;
+use embedded_hal::spi::SpiBus;
+use std::thread;
+use std::time::{Duration, Instant};
+
+const AK09916_I2C_ADDR: u8 = 0x0C;
+const AK09916_WIA2: u8 = 0x01;
+const AK09916_ST1: u8 = 0x10;
+const AK09916_CNTL2: u8 = 0x31;
+const AK09916_CNTL3: u8 = 0x32;
+
+const AK09916_DEVICE_ID: u8 = 0x09;
+const AK09916_MODE_CONTINUOUS_100HZ: u8 = 0x08;
+const AK09916_SOFT_RESET: u8 = 0x01;
+
+/// ST1 through ST2: status, six data bytes, a dummy register and the overflow status
+const MAG_FRAME_LEN: usize = 9;
+
+/// Field strength per LSB in micro tesla
+const MAG_SCALE_UT: f32 = 0.15;
+
+const USER_CTRL_I2C_MST_EN: u8 = 0x20;
+const I2C_MST_STATUS_SLV4_NACK: u8 = 0x10;
+const I2C_MST_STATUS_SLV4_DONE: u8 = 0x40;
+const I2C_SLV_EN: u8 = 0x80;
+const I2C_SLV_READ: u8 = 0x80;
+const ST1_DRDY: u8 = 0x01;
+const ST2_HOFL: u8 = 0x08;
+
+pub struct Ak09916 {
+    last_frame: [u8; MAG_FRAME_LEN],
+    idle_since_last: bool, // A copy without DRDY was seen since the last sample was reported
+}
+
+impl Ak09916 {
+    /// Enable the ICM's I2C master, check the AK09916 is there, and start continuous 100 Hz readout
+    pub fn initialize<B: SpiBus>(registers: &Registers<B>) -> Result<Self, ImuError> {
+        registers.modify(USER_CTRL, USER_CTRL_I2C_MST_EN, USER_CTRL_I2C_MST_EN)?;
+        registers.write(I2C_MST_CTRL, 0x07)?; // 345.6 kHz I2C clock
+        registers.write(I2C_MST_ODR_CONFIG, 0x03)?; // Poll slaves at 1.1 kHz / 2^3 = 137.5 Hz
+
+        write_ak09916(registers, AK09916_CNTL3, AK09916_SOFT_RESET)?;
+        thread::sleep(Duration::from_millis(10));
+
+        let id = read_ak09916(registers, AK09916_WIA2)?;
+        if id != AK09916_DEVICE_ID {
+            return Err(ImuError::WrongDevice {
+                device: "AK09916",
+                found: id,
+                expected: AK09916_DEVICE_ID,
+            });
+        }
+
+        write_ak09916(registers, AK09916_CNTL2, AK09916_MODE_CONTINUOUS_100HZ)?;
+
+        // Copy ST1..ST2 into EXT_SLV_SENS_DATA_00.. on every I2C master cycle
+        registers.write(I2C_SLV0_ADDR, I2C_SLV_READ | AK09916_I2C_ADDR)?;
+        registers.write(I2C_SLV0_REG, AK09916_ST1)?;
+        registers.write(I2C_SLV0_CTRL, I2C_SLV_EN | MAG_FRAME_LEN as u8)?;
+
+        Ok(Self::new())
+    }
+
+    fn new() -> Self {
+        Self {
+            last_frame: [0; MAG_FRAME_LEN],
+            idle_since_last: true,
+        }
+    }
+
+    /// Latest field in micro tesla, rotated into the accel/gyro axes, or None if there's no new sample
+    pub fn read<B: SpiBus>(&mut self, registers: &Registers<B>) -> Result<Option<[f32; 3]>, ImuError> {
+        let mut frame = [0u8; MAG_FRAME_LEN];
+        registers.read_burst(EXT_SLV_SENS_DATA_00, &mut frame)?;
+
+        // ST1 DRDY is set in the one I2C master cycle that picks up a new measurement, reading ST2
+        // clears it for the next. We poll faster than the master refreshes the copy, so a DRDY copy
+        // is only new if a cycle without DRDY came in between, or if a measurement was picked up on
+        // consecutive cycles and the data changed.
+        if frame[0] & ST1_DRDY == 0 {
+            self.idle_since_last = true;
+            return Ok(None);
+        }
+        if !self.idle_since_last && frame == self.last_frame {
+            return Ok(None);
+        }
+        self.idle_since_last = false;
+        self.last_frame = frame;
+
+        // Sensor overflow, the reading is invalid
+        if frame[8] & ST2_HOFL != 0 {
+            return Ok(None);
+        }
+
+        let x = i16::from_le_bytes([frame[1], frame[2]]) as f32 * MAG_SCALE_UT;
+        let y = i16::from_le_bytes([frame[3], frame[4]]) as f32 * MAG_SCALE_UT;
+        let z = i16::from_le_bytes([frame[5], frame[6]]) as f32 * MAG_SCALE_UT;
+
+        // The AK09916's Y and Z axes point opposite to the accelerometer and gyroscope
+        Ok(Some([x, -y, -z]))
+    }
+}
+
+/// Write one AK09916 register through I2C slave 4
+fn write_ak09916<B: SpiBus>(registers: &Registers<B>, reg: u8, value: u8) -> Result<(), ImuError> {
+    registers.write(I2C_SLV4_ADDR, AK09916_I2C_ADDR)?;
+    registers.write(I2C_SLV4_REG, reg)?;
+    registers.write(I2C_SLV4_DO, value)?;
+    registers.write(I2C_SLV4_CTRL, I2C_SLV_EN)?;
+    wait_slv4_done(registers)
+}
+
+/// Read one AK09916 register through I2C slave 4
+fn read_ak09916<B: SpiBus>(registers: &Registers<B>, reg: u8) -> Result<u8, ImuError> {
+    registers.write(I2C_SLV4_ADDR, I2C_SLV_READ | AK09916_I2C_ADDR)?;
+    registers.write(I2C_SLV4_REG, reg)?;
+    registers.write(I2C_SLV4_CTRL, I2C_SLV_EN)?;
+    wait_slv4_done(registers)?;
+    registers.read(I2C_SLV4_DI)
+}
+
+fn wait_slv4_done<B: SpiBus>(registers: &Registers<B>) -> Result<(), ImuError> {
+    let start = Instant::now();
+    while start.elapsed() < Duration::from_millis(50) {
+        let status = registers.read(I2C_MST_STATUS)?;
+        if status & I2C_MST_STATUS_SLV4_NACK != 0 {
+            return Err(ImuError::AuxI2c("AK09916 did not acknowledge".to_string()));
+        }
+        if status & I2C_MST_STATUS_SLV4_DONE != 0 {
+            return Ok(());
+        }
+        thread::sleep(Duration::from_micros(100));
+    }
+    Err(ImuError::AuxI2c("timed out waiting for the transfer".to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock_spi::MockIcm20948;
+    use std::sync::{Arc, Mutex};
+
+    const TOLERANCE: f32 = 1e-4;
+
+    /// Put a copy of ST1, the raw X, Y and Z counts and ST2 where the I2C master leaves it
+    fn copy<B: SpiBus>(registers: &Registers<B>, st1: u8, raw: [i16; 3], st2: u8) {
+        let mut frame = [0u8; MAG_FRAME_LEN];
+        frame[0] = st1;
+        for (axis, value) in raw.iter().enumerate() {
+            frame[1 + 2 * axis..3 + 2 * axis].copy_from_slice(&value.to_le_bytes());
+        }
+        frame[8] = st2;
+        for (offset, byte) in (0..).zip(frame) {
+            let register = Register {
+                bank: EXT_SLV_SENS_DATA_00.bank,
+                addr: EXT_SLV_SENS_DATA_00.addr + offset,
+            };
+            registers.write(register, byte).unwrap();
+        }
+    }
+
+    fn magnetometer() -> (Registers<MockIcm20948>, Ak09916) {
+        let registers = Registers::new(Arc::new(Mutex::new(MockIcm20948::new())));
+        (registers, Ak09916::new())
+    }
+
+    #[test]
+    fn field_is_scaled_and_remapped_into_the_accel_axes() {
+        let (registers, mut mag) = magnetometer();
+        copy(&registers, ST1_DRDY, [100, 200, -300], 0);
+        let field = mag.read(&registers).unwrap().unwrap();
+        for (value, expected) in field.iter().zip([15.0, -30.0, 45.0]) {
+            assert!((value - expected).abs() < TOLERANCE, "{:?}", field);
+        }
+    }
+
+    #[test]
+    fn each_measurement_is_reported_once() {
+        let (registers, mut mag) = magnetometer();
+        copy(&registers, 0, [0; 3], 0);
+        assert_eq!(mag.read(&registers).unwrap(), None);
+
+        // Polled several times before the master's next cycle
+        copy(&registers, ST1_DRDY, [100, 200, 300], 0);
+        assert!(mag.read(&registers).unwrap().is_some());
+        assert_eq!(mag.read(&registers).unwrap(), None);
+
+        // The next cycle has nothing new, then a measurement of an unchanged field comes in
+        copy(&registers, 0, [100, 200, 300], 0);
+        assert_eq!(mag.read(&registers).unwrap(), None);
+        copy(&registers, ST1_DRDY, [100, 200, 300], 0);
+        assert!(mag.read(&registers).unwrap().is_some());
+
+        // A measurement picked up on the very next cycle
+        copy(&registers, ST1_DRDY, [101, 200, 300], 0);
+        assert!(mag.read(&registers).unwrap().is_some());
+        assert_eq!(mag.read(&registers).unwrap(), None);
+    }
+
+    #[test]
+    fn overflowed_measurements_are_dropped() {
+        let (registers, mut mag) = magnetometer();
+        copy(&registers, ST1_DRDY, [32767, 0, 0], ST2_HOFL);
+        assert_eq!(mag.read(&registers).unwrap(), None);
+        // And not reported on the next poll either
+        assert_eq!(mag.read(&registers).unwrap(), None);
+
+        copy(&registers, 0, [0; 3], 0);
+        mag.read(&registers).unwrap();
+        copy(&registers, ST1_DRDY, [10, 0, 0], 0);
+        assert!(mag.read(&registers).unwrap().is_some());
+    }
+}
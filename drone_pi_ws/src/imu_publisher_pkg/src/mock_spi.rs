@@ -0,0 +1,232 @@
+//! Register-level stand-in for the ICM-20948 on the SPI bus.
+//!
+//! `MockIcm20948` implements `SpiBus` and answers register reads and writes the way the sensor does,
+//! including bank switching and the FIFO, so the register code (`Registers`, `Fifo`) can run without
+//! hardware. Samples are queued with `push_frame` and come back out of FIFO_R_W like real ones.
+
+use crate::fifo::FIFO_FRAME_LEN;
+use embedded_hal::spi::{ErrorKind, ErrorType, SpiBus};
+use std::collections::VecDeque;
+
+/// FIFO size in bytes
+pub const MOCK_FIFO_SIZE: usize = 512;
+
+const REG_BANK_SEL: u8 = 0x7F;
+const READ_FLAG: u8 = 0x80;
+const WHO_AM_I: u8 = 0x00;
+const USER_CTRL: u8 = 0x03;
+const INT_STATUS_2: u8 = 0x1B;
+const FIFO_EN_2: u8 = 0x67;
+const FIFO_RST: u8 = 0x68;
+const FIFO_COUNTH: u8 = 0x70;
+const FIFO_COUNTL: u8 = 0x71;
+const FIFO_R_W: u8 = 0x72;
+
+const DEVICE_ID: u8 = 0xEA;
+const USER_CTRL_FIFO_EN: u8 = 0x40;
+const FIFO_EN_2_ACCEL_GYRO: u8 = 0x1E;
+const FIFO_OVERFLOW: u8 = 0x1F;
+
+pub struct MockIcm20948 {
+    banks: [[u8; 128]; 4],
+    bank: usize,
+    fifo: VecDeque<u8>,
+}
+
+impl Default for MockIcm20948 {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl MockIcm20948 {
+    pub fn new() -> Self {
+        let mut banks = [[0u8; 128]; 4];
+        banks[0][WHO_AM_I as usize] = DEVICE_ID;
+        Self {
+            banks,
+            bank: 0,
+            fifo: VecDeque::with_capacity(MOCK_FIFO_SIZE),
+        }
+    }
+
+    /// Write one accel/gyro sample set in raw counts to the FIFO, if streaming to it is enabled.
+    /// Like the real FIFO in stream mode, the oldest bytes are dropped and the overflow flag set
+    /// when it's full.
+    pub fn push_frame(&mut self, accel: [i16; 3], gyro: [i16; 3]) {
+        let fifo_enabled = self.banks[0][USER_CTRL as usize] & USER_CTRL_FIFO_EN != 0;
+        let streaming = self.banks[0][FIFO_EN_2 as usize] & FIFO_EN_2_ACCEL_GYRO == FIFO_EN_2_ACCEL_GYRO;
+        if !fifo_enabled || !streaming {
+            return;
+        }
+
+        for value in accel.iter().chain(gyro.iter()) {
+            self.fifo.extend(value.to_be_bytes());
+        }
+        while self.fifo.len() > MOCK_FIFO_SIZE {
+            self.fifo.pop_front();
+            self.banks[0][INT_STATUS_2 as usize] |= FIFO_OVERFLOW;
+        }
+    }
+
+    /// Frames currently waiting in the FIFO
+    pub fn queued_frames(&self) -> usize {
+        self.fifo.len() / FIFO_FRAME_LEN
+    }
+
+    /// Register value as seen by the host, with the side effects of reading it
+    fn read_register(&mut self, addr: u8) -> u8 {
+        if self.bank == 0 {
+            match addr {
+                FIFO_COUNTH => return (self.fifo.len() >> 8) as u8 & 0x1F,
+                FIFO_COUNTL => return self.fifo.len() as u8,
+                FIFO_R_W => return self.fifo.pop_front().unwrap_or(0xFF),
+                INT_STATUS_2 => {
+                    // Cleared on read
+                    let status = self.banks[0][INT_STATUS_2 as usize];
+                    self.banks[0][INT_STATUS_2 as usize] = 0;
+                    return status;
+                }
+                _ => {}
+            }
+        }
+        self.banks[self.bank][(addr & 0x7F) as usize]
+    }
+
+    fn write_register(&mut self, addr: u8, value: u8) {
+        if addr == REG_BANK_SEL {
+            self.bank = ((value >> 4) & 0x03) as usize;
+            return;
+        }
+        if self.bank == 0 && addr == FIFO_RST && value != 0 {
+            self.fifo.clear();
+        }
+        self.banks[self.bank][(addr & 0x7F) as usize] = value;
+    }
+
+    /// One chip-select framed transfer: an address byte, then data in or out of consecutive
+    /// registers (FIFO_R_W doesn't advance, so bursts keep draining the FIFO)
+    fn transaction(&mut self, write: &[u8], read: &mut [u8]) {
+        let Some(&first) = write.first() else {
+            return;
+        };
+        let start = first & !READ_FLAG;
+        let mut addr = start;
+
+        if first & READ_FLAG != 0 {
+            for byte in read.iter_mut().skip(1) {
+                *byte = self.read_register(addr);
+                if addr != FIFO_R_W {
+                    addr = addr.wrapping_add(1);
+                }
+            }
+        } else {
+            for value in write.iter().skip(1) {
+                self.write_register(addr, *value);
+                if addr != FIFO_R_W {
+                    addr = addr.wrapping_add(1);
+                }
+            }
+        }
+    }
+}
+
+impl ErrorType for MockIcm20948 {
+    type Error = ErrorKind;
+}
+
+impl SpiBus for MockIcm20948 {
+    fn read(&mut self, words: &mut [u8]) -> Result<(), Self::Error> {
+        // Nothing was addressed, the sensor just clocks out its idle level
+        words.fill(0xFF);
+        Ok(())
+    }
+
+    fn write(&mut self, words: &[u8]) -> Result<(), Self::Error> {
+        self.transaction(words, &mut []);
+        Ok(())
+    }
+
+    fn transfer(&mut self, read: &mut [u8], write: &[u8]) -> Result<(), Self::Error> {
+        self.transaction(write, read);
+        Ok(())
+    }
+
+    fn transfer_in_place(&mut self, words: &mut [u8]) -> Result<(), Self::Error> {
+        let write = words.to_vec();
+        self.transaction(&write, words);
+        Ok(())
+    }
+
+    fn flush(&mut self) -> Result<(), Self::Error> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::registers::{self, Registers};
+    use std::sync::{Arc, Mutex};
+
+    fn mock() -> (Arc<Mutex<MockIcm20948>>, Registers<MockIcm20948>) {
+        let device = Arc::new(Mutex::new(MockIcm20948::new()));
+        (Arc::clone(&device), Registers::new(device))
+    }
+
+    fn start_streaming(registers: &Registers<MockIcm20948>) {
+        registers.write(registers::FIFO_EN_2, FIFO_EN_2_ACCEL_GYRO).unwrap();
+        registers.write(registers::USER_CTRL, USER_CTRL_FIFO_EN).unwrap();
+    }
+
+    fn fifo_count(registers: &Registers<MockIcm20948>) -> usize {
+        let mut count = [0u8; 2];
+        registers.read_burst(registers::FIFO_COUNTH, &mut count).unwrap();
+        u16::from_be_bytes(count) as usize
+    }
+
+    #[test]
+    fn answers_who_am_i_and_keeps_banks_apart() {
+        let (_, registers) = mock();
+        assert_eq!(registers.read(registers::WHO_AM_I).unwrap(), DEVICE_ID);
+        registers.write(registers::GYRO_SMPLRT_DIV, 9).unwrap();
+        assert_eq!(registers.read(registers::GYRO_SMPLRT_DIV).unwrap(), 9);
+        assert_eq!(registers.read(registers::WHO_AM_I).unwrap(), DEVICE_ID);
+    }
+
+    #[test]
+    fn frames_only_queue_while_streaming() {
+        let (device, registers) = mock();
+        device.lock().unwrap().push_frame([1, 2, 3], [4, 5, 6]);
+        assert_eq!(fifo_count(&registers), 0);
+
+        start_streaming(&registers);
+        device.lock().unwrap().push_frame([1, 2, 3], [-4, 5, 6]);
+        assert_eq!(fifo_count(&registers), FIFO_FRAME_LEN);
+
+        let mut frame = [0u8; FIFO_FRAME_LEN];
+        registers.read_burst(registers::FIFO_R_W, &mut frame).unwrap();
+        assert_eq!(&frame[..2], &1i16.to_be_bytes());
+        assert_eq!(&frame[6..8], &(-4i16).to_be_bytes());
+        assert_eq!(fifo_count(&registers), 0);
+    }
+
+    #[test]
+    fn full_fifo_drops_the_oldest_bytes_and_flags_it_once() {
+        let (device, registers) = mock();
+        start_streaming(&registers);
+        for _ in 0..MOCK_FIFO_SIZE / FIFO_FRAME_LEN {
+            device.lock().unwrap().push_frame([0; 3], [0; 3]);
+        }
+        assert_eq!(registers.read(registers::INT_STATUS_2).unwrap(), 0);
+
+        device.lock().unwrap().push_frame([0; 3], [0; 3]);
+        assert_eq!(fifo_count(&registers), MOCK_FIFO_SIZE);
+        assert_eq!(registers.read(registers::INT_STATUS_2).unwrap(), FIFO_OVERFLOW);
+        // Cleared by the read
+        assert_eq!(registers.read(registers::INT_STATUS_2).unwrap(), 0);
+
+        registers.write(registers::FIFO_RST, 0x1F).unwrap();
+        assert_eq!(fifo_count(&registers), 0);
+    }
+}
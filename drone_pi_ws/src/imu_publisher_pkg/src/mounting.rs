@@ -0,0 +1,109 @@
+//! How the IMU board sits in the airframe.
+//!
+//! The sensor's axes only line up with the vehicle's (REP-103 `base_link`: x forward, y left, z up)
+//! if the board is mounted that way round. `Mounting` holds the rotation from the sensor axes to the
+//! body axes, given as the board's roll, pitch and yaw relative to the body (ZYX, in degrees), and
+//! its position, so samples can be published in body axes and a matching `base_link` -> `imu_link`
+//! transform broadcast.
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Mounting {
+    rotation: [[f32; 3]; 3],   // Sensor to body, v_body = rotation * v_sensor
+    pub translation: [f64; 3], // IMU position in base_link, m
+}
+
+impl Default for Mounting {
+    /// Sensor axes aligned with the body, at the body origin
+    fn default() -> Self {
+        Self::new([0.0; 3], [0.0; 3])
+    }
+}
+
+impl Mounting {
+    /// Board orientation `[roll, pitch, yaw]` in degrees relative to the body, and position in m
+    pub fn new(rpy_deg: [f64; 3], translation: [f64; 3]) -> Self {
+        let [roll, pitch, yaw] = rpy_deg.map(f64::to_radians);
+        let (sr, cr) = roll.sin_cos();
+        let (sp, cp) = pitch.sin_cos();
+        let (sy, cy) = yaw.sin_cos();
+
+        // Rz(yaw) * Ry(pitch) * Rx(roll)
+        let rotation = [
+            [cy * cp, cy * sp * sr - sy * cr, cy * sp * cr + sy * sr],
+            [sy * cp, sy * sp * sr + cy * cr, sy * sp * cr - cy * sr],
+            [-sp, cp * sr, cp * cr],
+        ];
+
+        // Snap the near-zero terms of right angle mountings so the axes swap exactly
+        let rotation = rotation.map(|row| row.map(|value| if value.abs() < 1e-9 { 0.0 } else { value as f32 }));
+        Self { rotation, translation }
+    }
+
+    /// Express a sensor frame vector in body axes
+    pub fn to_body(&self, v: [f32; 3]) -> [f32; 3] {
+        let m = &self.rotation;
+        [
+            m[0][0] * v[0] + m[0][1] * v[1] + m[0][2] * v[2],
+            m[1][0] * v[0] + m[1][1] * v[1] + m[1][2] * v[2],
+            m[2][0] * v[0] + m[2][1] * v[1] + m[2][2] * v[2],
+        ]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TOLERANCE: f32 = 1e-6;
+
+    fn assert_close(actual: [f32; 3], expected: [f32; 3]) {
+        for (a, e) in actual.iter().zip(expected) {
+            assert!((a - e).abs() < TOLERANCE, "{:?} != {:?}", actual, expected);
+        }
+    }
+
+    #[test]
+    fn default_mounting_leaves_samples_alone() {
+        let v = [1.0, -2.0, 3.0];
+        assert_eq!(Mounting::default().to_body(v), v);
+    }
+
+    #[test]
+    fn upside_down_board_flips_y_and_z_on_every_sensor() {
+        let mounting = Mounting::new([180.0, 0.0, 0.0], [0.0; 3]);
+        // Level and at rest the accelerometer reads -1 g on its own Z, +1 g up in the body
+        assert_eq!(mounting.to_body([0.0, 0.0, -9.81]), [0.0, 0.0, 9.81]);
+        // A yaw rate to the left reads negative on the flipped gyro Z
+        assert_eq!(mounting.to_body([0.0, 0.0, -0.5]), [0.0, 0.0, 0.5]);
+        // The field's downward component reads upward on the flipped magnetometer
+        assert_eq!(mounting.to_body([22.0, 0.0, 42.0]), [22.0, 0.0, -42.0]);
+    }
+
+    #[test]
+    fn yawed_board_swaps_x_and_y_on_every_sensor() {
+        // The board's X axis points left, so its Y axis points backwards
+        let mounting = Mounting::new([0.0, 0.0, 90.0], [0.0; 3]);
+        // Accelerating forward
+        assert_eq!(mounting.to_body([0.0, -2.0, 9.81]), [2.0, 0.0, 9.81]);
+        // Rolling right, about the body's forward axis
+        assert_eq!(mounting.to_body([0.0, -0.3, 0.0]), [0.3, 0.0, 0.0]);
+        // Facing north, the field's horizontal component is forward
+        assert_eq!(mounting.to_body([0.0, -22.0, -42.0]), [22.0, 0.0, -42.0]);
+    }
+
+    #[test]
+    fn rotations_apply_roll_then_pitch_then_yaw() {
+        let combined = Mounting::new([30.0, -20.0, 75.0], [0.0; 3]);
+        let roll = Mounting::new([30.0, 0.0, 0.0], [0.0; 3]);
+        let pitch = Mounting::new([0.0, -20.0, 0.0], [0.0; 3]);
+        let yaw = Mounting::new([0.0, 0.0, 75.0], [0.0; 3]);
+
+        for v in [[1.0, 0.0, 0.0], [0.0, 1.0, 0.0], [0.0, 0.0, 1.0], [0.3, -1.2, 9.7]] {
+            let body = combined.to_body(v);
+            assert_close(body, yaw.to_body(pitch.to_body(roll.to_body(v))));
+            // A rotation, so lengths are kept
+            let length = |v: [f32; 3]| v.iter().map(|x| x * x).sum::<f32>().sqrt();
+            assert!((length(body) - length(v)).abs() < TOLERANCE);
+        }
+    }
+}
@@ -0,0 +1,236 @@
+//! Sensor noise estimation for the covariances published with each IMU sample.
+//!
+//! The noise is measured on the samples as published, after calibration and filtering, by splitting
+//! them into fixed windows and taking the per-axis variance of each window in which the IMU was
+//! still. A window with motion in it would measure the motion rather than the noise, so it's
+//! discarded. Until a still window has been seen the estimate is the one saved in the calibration
+//! file, or failing that one worked out from the datasheet noise densities.
+
+use crate::calibration::NoiseCalibration;
+use crate::imu_source::GRAVITY;
+
+/// ICM-20948 datasheet accelerometer noise density, ug/sqrt(Hz)
+const ACCEL_NOISE_DENSITY: f64 = 230.0;
+
+/// ICM-20948 datasheet gyroscope noise density, dps/sqrt(Hz)
+const GYRO_NOISE_DENSITY: f64 = 0.015;
+
+/// Fraction of the way the estimate moves towards each new still window's variance
+const ESTIMATE_SMOOTHING: f32 = 0.2;
+
+/// Noise variance expected from the datasheet with the sensor's low-pass filters at the given
+/// bandwidths, for before anything has been measured
+pub fn datasheet_noise(accel_bandwidth_hz: f32, gyro_bandwidth_hz: f32) -> NoiseCalibration {
+    let accel_std = ACCEL_NOISE_DENSITY * 1e-6 * GRAVITY * (accel_bandwidth_hz as f64).sqrt();
+    let gyro_std = (GYRO_NOISE_DENSITY * (gyro_bandwidth_hz as f64).sqrt()).to_radians();
+    NoiseCalibration {
+        accel_variance: [(accel_std * accel_std) as f32; 3],
+        gyro_variance: [(gyro_std * gyro_std) as f32; 3],
+    }
+}
+
+/// Diagonal 3x3 covariance matrix in row-major order, as used by `sensor_msgs`
+pub fn diagonal_covariance(variance: [f32; 3]) -> [f64; 9] {
+    let mut covariance = [0.0; 9];
+    for (axis, value) in variance.iter().enumerate() {
+        covariance[axis * 4] = *value as f64;
+    }
+    covariance
+}
+
+/// Per-axis variance over one window of samples
+struct WindowStats {
+    sum: [f64; 6],
+    sum_sq: [f64; 6],
+    count: usize,
+}
+
+impl WindowStats {
+    fn new() -> Self {
+        Self {
+            sum: [0.0; 6],
+            sum_sq: [0.0; 6],
+            count: 0,
+        }
+    }
+
+    fn add(&mut self, accel: [f32; 3], gyro: [f32; 3]) {
+        for (i, value) in accel.iter().chain(gyro.iter()).enumerate() {
+            let value = *value as f64;
+            self.sum[i] += value;
+            self.sum_sq[i] += value * value;
+        }
+        self.count += 1;
+    }
+
+    fn variance(&self, i: usize) -> f64 {
+        let mean = self.sum[i] / self.count.max(1) as f64;
+        (self.sum_sq[i] / self.count.max(1) as f64 - mean * mean).max(0.0)
+    }
+}
+
+/// Online noise estimate from the windows in which the IMU sat still.
+///
+/// A window counts as still if no gyro axis has a standard deviation above `max_gyro_std` rad/s and
+/// no accelerometer axis one above `max_accel_std` m/s^2.
+pub struct NoiseEstimator {
+    window: usize,
+    max_gyro_std: f32,
+    max_accel_std: f32,
+    stats: WindowStats,
+    estimate: NoiseCalibration,
+    still_windows: u64,
+}
+
+impl NoiseEstimator {
+    /// Start from a previously measured estimate, or the datasheet's (`datasheet_noise`)
+    pub fn new(window: usize, max_gyro_std: f32, max_accel_std: f32, initial: NoiseCalibration) -> Self {
+        Self {
+            window: window.max(2),
+            max_gyro_std,
+            max_accel_std,
+            stats: WindowStats::new(),
+            estimate: initial,
+            still_windows: 0,
+        }
+    }
+
+    /// Add a published accel/gyro sample, returning true if it completed a still window and the
+    /// estimate was updated
+    pub fn add_sample(&mut self, accel: [f32; 3], gyro: [f32; 3]) -> bool {
+        self.stats.add(accel, gyro);
+        if self.stats.count < self.window {
+            return false;
+        }
+
+        let stats = std::mem::replace(&mut self.stats, WindowStats::new());
+        let variance: [f32; 6] = std::array::from_fn(|i| stats.variance(i) as f32);
+
+        let accel_still = variance[..3].iter().all(|v| v.sqrt() <= self.max_accel_std);
+        let gyro_still = variance[3..].iter().all(|v| v.sqrt() <= self.max_gyro_std);
+        if !(accel_still && gyro_still) {
+            return false;
+        }
+
+        // The first still window replaces the initial estimate outright, that was from another boot or the datasheet
+        let smoothing = if self.still_windows == 0 { 1.0 } else { ESTIMATE_SMOOTHING };
+        for axis in 0..3 {
+            let accel = &mut self.estimate.accel_variance[axis];
+            *accel += smoothing * (variance[axis] - *accel);
+            let gyro = &mut self.estimate.gyro_variance[axis];
+            *gyro += smoothing * (variance[3 + axis] - *gyro);
+        }
+        self.still_windows += 1;
+        true
+    }
+
+    pub fn estimate(&self) -> NoiseCalibration {
+        self.estimate
+    }
+
+    /// Still windows measured so far, 0 if the estimate is still the initial one
+    pub fn still_windows(&self) -> u64 {
+        self.still_windows
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::rngs::StdRng;
+    use rand::SeedableRng;
+    use rand_distr::{Distribution, Normal};
+
+    const WINDOW: usize = 1000;
+    const ACCEL_STD: [f32; 3] = [0.01, 0.02, 0.04];
+    const GYRO_STD: [f32; 3] = [0.001, 0.002, 0.003];
+
+    /// Relative error allowed on a variance measured over one window, about three standard errors
+    const VARIANCE_TOLERANCE: f32 = 0.15;
+
+    fn estimator() -> NoiseEstimator {
+        NoiseEstimator::new(WINDOW, 0.01, 0.1, datasheet_noise(246.0, 196.6))
+    }
+
+    /// One window of an IMU lying flat, with `scale` times the test noise and `rotating` rad/s
+    /// ramping up on the gyro's Z axis over the window
+    fn window(estimator: &mut NoiseEstimator, rng: &mut StdRng, scale: f32, rotating: f32) -> Vec<bool> {
+        let noise = |std: f32| Normal::new(0.0, scale * std).unwrap();
+        let accel_noise = ACCEL_STD.map(noise);
+        let gyro_noise = GYRO_STD.map(noise);
+        (0..WINDOW)
+            .map(|i| {
+                let mut accel = [0.0, 0.0, GRAVITY as f32];
+                let mut gyro = [0.0, 0.0, rotating * i as f32 / WINDOW as f32];
+                for axis in 0..3 {
+                    accel[axis] += accel_noise[axis].sample(rng);
+                    gyro[axis] += gyro_noise[axis].sample(rng);
+                }
+                estimator.add_sample(accel, gyro)
+            })
+            .collect()
+    }
+
+    fn assert_variance(measured: [f32; 3], std: [f32; 3]) {
+        for (variance, std) in measured.iter().zip(std) {
+            assert!((variance / (std * std) - 1.0).abs() < VARIANCE_TOLERANCE, "{:?} {:?}", measured, std);
+        }
+    }
+
+    #[test]
+    fn still_window_measures_the_noise_variance() {
+        let mut estimator = estimator();
+        let mut rng = StdRng::seed_from_u64(1);
+
+        let updates = window(&mut estimator, &mut rng, 1.0, 0.0);
+        // Only the sample completing the window updates the estimate
+        assert_eq!(updates.iter().filter(|updated| **updated).count(), 1);
+        assert!(updates[WINDOW - 1]);
+        assert_eq!(estimator.still_windows(), 1);
+
+        assert_variance(estimator.estimate().accel_variance, ACCEL_STD);
+        assert_variance(estimator.estimate().gyro_variance, GYRO_STD);
+    }
+
+    #[test]
+    fn windows_with_motion_are_discarded() {
+        let mut estimator = estimator();
+        let mut rng = StdRng::seed_from_u64(2);
+        let initial = estimator.estimate();
+
+        // A slow turn is far above the gyro noise, and would be measured as noise if it counted
+        let updates = window(&mut estimator, &mut rng, 1.0, 0.5);
+        assert!(updates.iter().all(|updated| !updated));
+        assert_eq!(estimator.still_windows(), 0);
+        assert_eq!(estimator.estimate(), initial);
+
+        // The next window starts afresh rather than carrying the motion over
+        window(&mut estimator, &mut rng, 1.0, 0.0);
+        assert_eq!(estimator.still_windows(), 1);
+        assert_variance(estimator.estimate().gyro_variance, GYRO_STD);
+    }
+
+    #[test]
+    fn later_still_windows_are_smoothed_in() {
+        let mut estimator = estimator();
+        let mut rng = StdRng::seed_from_u64(3);
+        window(&mut estimator, &mut rng, 1.0, 0.0);
+        let first = estimator.estimate();
+
+        // Twice the noise is four times the variance, and the estimate moves a fifth of the way there
+        window(&mut estimator, &mut rng, 2.0, 0.0);
+        assert_eq!(estimator.still_windows(), 2);
+        let expected = first.gyro_variance.map(|variance| variance * (1.0 + 3.0 * ESTIMATE_SMOOTHING));
+        for (variance, expected) in estimator.estimate().gyro_variance.iter().zip(expected) {
+            assert!((variance / expected - 1.0).abs() < VARIANCE_TOLERANCE, "{} {}", variance, expected);
+        }
+    }
+
+    #[test]
+    fn covariance_has_the_variances_on_its_diagonal() {
+        assert_eq!(
+            diagonal_covariance([1.0, 2.0, 3.0]),
+            [1.0, 0.0, 0.0, 0.0, 2.0, 0.0, 0.0, 0.0, 3.0]
+        );
+    }
+}
@@ -0,0 +1,160 @@
+//! Raw ICM-20948 register access, for features the driver doesn't expose.
+//!
+//! The SPI bus is shared with the driver through `SharedSpi`, and every register operation holds the
+//! bus for its whole bank-select/transfer sequence and leaves the sensor on bank 0 afterwards, which
+//! is where the driver expects it.
+
+use crate::error::ImuError;
+use embedded_hal::spi::{ErrorType, SpiBus};
+use std::sync::{Arc, Mutex};
+
+/// A register address within one of the ICM-20948's four user banks
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Register {
+    pub bank: u8,
+    pub addr: u8,
+}
+
+const fn reg(bank: u8, addr: u8) -> Register {
+    Register { bank, addr }
+}
+
+/// Bank select, present at the same address in every bank
+const REG_BANK_SEL: u8 = 0x7F;
+const READ_FLAG: u8 = 0x80;
+
+// Bank 0
+pub const WHO_AM_I: Register = reg(0, 0x00);
+pub const USER_CTRL: Register = reg(0, 0x03);
+pub const I2C_MST_STATUS: Register = reg(0, 0x17);
+pub const INT_STATUS_2: Register = reg(0, 0x1B);
+pub const ACCEL_XOUT_H: Register = reg(0, 0x2D);
+pub const GYRO_XOUT_H: Register = reg(0, 0x33);
+pub const TEMP_OUT_H: Register = reg(0, 0x39);
+pub const EXT_SLV_SENS_DATA_00: Register = reg(0, 0x3B);
+pub const FIFO_EN_1: Register = reg(0, 0x66);
+pub const FIFO_EN_2: Register = reg(0, 0x67);
+pub const FIFO_RST: Register = reg(0, 0x68);
+pub const FIFO_MODE: Register = reg(0, 0x69);
+pub const FIFO_COUNTH: Register = reg(0, 0x70);
+pub const FIFO_R_W: Register = reg(0, 0x72);
+
+// Bank 2
+pub const GYRO_SMPLRT_DIV: Register = reg(2, 0x00);
+pub const GYRO_CONFIG_1: Register = reg(2, 0x01);
+pub const ACCEL_SMPLRT_DIV_1: Register = reg(2, 0x10);
+pub const ACCEL_SMPLRT_DIV_2: Register = reg(2, 0x11);
+pub const ACCEL_CONFIG: Register = reg(2, 0x14);
+
+// Bank 3
+pub const I2C_MST_ODR_CONFIG: Register = reg(3, 0x00);
+pub const I2C_MST_CTRL: Register = reg(3, 0x01);
+pub const I2C_SLV0_ADDR: Register = reg(3, 0x03);
+pub const I2C_SLV0_REG: Register = reg(3, 0x04);
+pub const I2C_SLV0_CTRL: Register = reg(3, 0x05);
+pub const I2C_SLV4_ADDR: Register = reg(3, 0x13);
+pub const I2C_SLV4_REG: Register = reg(3, 0x14);
+pub const I2C_SLV4_CTRL: Register = reg(3, 0x15);
+pub const I2C_SLV4_DO: Register = reg(3, 0x16);
+pub const I2C_SLV4_DI: Register = reg(3, 0x17);
+
+/// An SPI bus behind a mutex so the driver and `Registers` can both use it
+pub struct SharedSpi<B>(pub Arc<Mutex<B>>);
+
+impl<B: ErrorType> ErrorType for SharedSpi<B> {
+    type Error = B::Error;
+}
+
+impl<B: SpiBus> SpiBus for SharedSpi<B> {
+    fn read(&mut self, words: &mut [u8]) -> Result<(), Self::Error> {
+        self.0.lock().unwrap().read(words)
+    }
+
+    fn write(&mut self, words: &[u8]) -> Result<(), Self::Error> {
+        self.0.lock().unwrap().write(words)
+    }
+
+    fn transfer(&mut self, read: &mut [u8], write: &[u8]) -> Result<(), Self::Error> {
+        self.0.lock().unwrap().transfer(read, write)
+    }
+
+    fn transfer_in_place(&mut self, words: &mut [u8]) -> Result<(), Self::Error> {
+        self.0.lock().unwrap().transfer_in_place(words)
+    }
+
+    fn flush(&mut self) -> Result<(), Self::Error> {
+        self.0.lock().unwrap().flush()
+    }
+}
+
+/// Register reads and writes over the shared SPI bus
+pub struct Registers<B> {
+    bus: Arc<Mutex<B>>,
+}
+
+impl<B: SpiBus> Registers<B> {
+    pub fn new(bus: Arc<Mutex<B>>) -> Self {
+        Self { bus }
+    }
+
+    /// Read a single register
+    pub fn read(&self, register: Register) -> Result<u8, ImuError> {
+        let mut value = [0u8; 1];
+        self.read_burst(register, &mut value)?;
+        Ok(value[0])
+    }
+
+    /// Read consecutive registers starting at `register` (or the same FIFO register repeatedly)
+    pub fn read_burst(&self, register: Register, buffer: &mut [u8]) -> Result<(), ImuError> {
+        let mut bus = self.bus.lock().unwrap();
+        select_bank(&mut *bus, register.bank)?;
+
+        let mut frame = vec![0u8; buffer.len() + 1];
+        frame[0] = register.addr | READ_FLAG;
+        let result = bus
+            .transfer_in_place(&mut frame)
+            .map_err(|e| ImuError::Spi(format!("read of {:?} failed: {:?}", register, e)));
+
+        restore_bank(&mut *bus, register.bank)?;
+        result?;
+        buffer.copy_from_slice(&frame[1..]);
+        Ok(())
+    }
+
+    /// Write a single register
+    pub fn write(&self, register: Register, value: u8) -> Result<(), ImuError> {
+        let mut bus = self.bus.lock().unwrap();
+        select_bank(&mut *bus, register.bank)?;
+
+        let result = bus
+            .write(&[register.addr, value])
+            .map_err(|e| ImuError::Spi(format!("write of {:?} failed: {:?}", register, e)));
+
+        restore_bank(&mut *bus, register.bank)?;
+        result
+    }
+
+    /// Read-modify-write the bits in `mask` to `value`
+    pub fn modify(&self, register: Register, mask: u8, value: u8) -> Result<(), ImuError> {
+        let current = self.read(register)?;
+        self.write(register, (current & !mask) | (value & mask))
+    }
+}
+
+/// Switch banks, skipped for bank 0 since that's where the sensor is left between operations
+fn select_bank<B: SpiBus>(bus: &mut B, bank: u8) -> Result<(), ImuError> {
+    if bank == 0 {
+        return Ok(());
+    }
+    bus.write(&[REG_BANK_SEL, bank << 4])
+        .map_err(|e| ImuError::Spi(format!("bank select failed: {:?}", e)))
+}
+
+/// Return to bank 0 after an operation on another bank
+fn restore_bank<B: SpiBus>(bus: &mut B, bank: u8) -> Result<(), ImuError> {
+    if bank == 0 {
+        return Ok(());
+    }
+    bus.write(&[REG_BANK_SEL, 0])
+        .map_err(|e| ImuError::Spi(format!("bank select failed: {:?}", e)))
+}
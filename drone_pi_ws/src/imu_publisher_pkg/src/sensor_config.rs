@@ -0,0 +1,332 @@
+//! ICM-20948 full-scale ranges, digital low-pass filters and output data rate.
+//!
+//! The driver's `initialize` leaves the sensor at +-2 g and +-250 dps, which clips during aggressive
+//! flight. `SensorConfig` picks the ranges, the on-chip DLPF bandwidths and the ODR, is written to the
+//! sensor after the driver's initialization, and is read back to make sure the sensor took it. The
+//! scale factors that turn raw counts into SI units follow from the ranges.
+
+use crate::error::ImuError;
+use crate::fifo::{FifoConfig, ACCEL_BASE_SAMPLE_RATE_HZ, GYRO_BASE_SAMPLE_RATE_HZ};
+use crate::imu_source::GRAVITY;
+use crate::registers::*;
+use embedded_hal::spi::SpiBus;
+
+const FCHOICE: u8 = 0x01; // Enables the DLPF, without it the sample rate dividers are ignored
+const FS_SEL_SHIFT: u8 = 1;
+const DLPFCFG_SHIFT: u8 = 3;
+const MAX_ACCEL_RATE_DIVIDER: u16 = 0x0FFF; // 12 bits across ACCEL_SMPLRT_DIV_1 and _2
+
+/// Gyro DLPF 3 dB bandwidth in Hz for each DLPFCFG setting
+const GYRO_DLPF_HZ: [f32; 8] = [196.6, 151.8, 119.5, 51.2, 23.9, 11.6, 5.7, 361.4];
+
+/// Accel DLPF 3 dB bandwidth in Hz for each DLPFCFG setting
+const ACCEL_DLPF_HZ: [f32; 8] = [246.0, 246.0, 111.4, 50.4, 23.9, 11.5, 5.7, 473.0];
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum AccelRange {
+    G2 = 0,
+    G4 = 1,
+    G8 = 2,
+    G16 = 3,
+}
+
+impl AccelRange {
+    /// Range for a full scale of `g` (2, 4, 8 or 16)
+    pub fn from_g(g: i64) -> Result<Self, ImuError> {
+        match g {
+            2 => Ok(AccelRange::G2),
+            4 => Ok(AccelRange::G4),
+            8 => Ok(AccelRange::G8),
+            16 => Ok(AccelRange::G16),
+            other => Err(ImuError::Config(format!("Accel range must be 2, 4, 8 or 16 g, got {}", other))),
+        }
+    }
+
+    /// Full scale in g
+    pub fn g(self) -> f32 {
+        match self {
+            AccelRange::G2 => 2.0,
+            AccelRange::G4 => 4.0,
+            AccelRange::G8 => 8.0,
+            AccelRange::G16 => 16.0,
+        }
+    }
+
+    fn fs_sel(self) -> u8 {
+        self as u8
+    }
+
+    /// m/s^2 per LSB
+    pub fn scale(self) -> f32 {
+        (self.g() as f64 * GRAVITY / 32768.0) as f32
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum GyroRange {
+    Dps250 = 0,
+    Dps500 = 1,
+    Dps1000 = 2,
+    Dps2000 = 3,
+}
+
+impl GyroRange {
+    /// Range for a full scale of `dps` (250, 500, 1000 or 2000)
+    pub fn from_dps(dps: i64) -> Result<Self, ImuError> {
+        match dps {
+            250 => Ok(GyroRange::Dps250),
+            500 => Ok(GyroRange::Dps500),
+            1000 => Ok(GyroRange::Dps1000),
+            2000 => Ok(GyroRange::Dps2000),
+            other => Err(ImuError::Config(format!(
+                "Gyro range must be 250, 500, 1000 or 2000 dps, got {}",
+                other
+            ))),
+        }
+    }
+
+    /// Full scale in degrees per second
+    pub fn dps(self) -> f32 {
+        match self {
+            GyroRange::Dps250 => 250.0,
+            GyroRange::Dps500 => 500.0,
+            GyroRange::Dps1000 => 1000.0,
+            GyroRange::Dps2000 => 2000.0,
+        }
+    }
+
+    fn fs_sel(self) -> u8 {
+        self as u8
+    }
+
+    /// rad/s per LSB
+    pub fn scale(self) -> f32 {
+        (self.dps() as f64).to_radians() as f32 / 32768.0
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct SensorConfig {
+    pub accel_range: AccelRange,
+    pub gyro_range: GyroRange,
+    pub accel_dlpf: u8,          // ACCEL_DLPFCFG, 0-7
+    pub gyro_dlpf: u8,           // GYRO_DLPFCFG, 0-7
+    pub gyro_rate_divider: u8,   // Gyro ODR = 1100 Hz / (1 + divider)
+    pub accel_rate_divider: u16, // Accel ODR = 1125 Hz / (1 + divider), 12 bits
+}
+
+impl Default for SensorConfig {
+    /// The driver's ranges at the full rate
+    fn default() -> Self {
+        Self {
+            accel_range: AccelRange::G2,
+            gyro_range: GyroRange::Dps250,
+            accel_dlpf: 0,
+            gyro_dlpf: 0,
+            gyro_rate_divider: 0,
+            accel_rate_divider: 0,
+        }
+    }
+}
+
+impl SensorConfig {
+    /// Build a configuration from the ranges, DLPF bandwidths and ODR asked for. The bandwidths and
+    /// gyro ODR are rounded to the nearest the sensor supports, and the accel ODR to the nearest the
+    /// gyro's, since the two clocks have different bases.
+    pub fn new(
+        accel_range_g: i64,
+        gyro_range_dps: i64,
+        accel_dlpf_hz: f64,
+        gyro_dlpf_hz: f64,
+        odr_hz: f64,
+    ) -> Result<Self, ImuError> {
+        let min_odr_hz = GYRO_BASE_SAMPLE_RATE_HZ / (1.0 + u8::MAX as f64);
+        if !(odr_hz >= min_odr_hz && odr_hz <= GYRO_BASE_SAMPLE_RATE_HZ) {
+            return Err(ImuError::Config(format!(
+                "ODR must be between {:.2} and {} Hz, got {}",
+                min_odr_hz, GYRO_BASE_SAMPLE_RATE_HZ, odr_hz
+            )));
+        }
+        let gyro_rate_divider = rate_divider(GYRO_BASE_SAMPLE_RATE_HZ, odr_hz, u8::MAX as u16) as u8;
+        let gyro_odr_hz = GYRO_BASE_SAMPLE_RATE_HZ / (1.0 + gyro_rate_divider as f64);
+        let accel_rate_divider = rate_divider(ACCEL_BASE_SAMPLE_RATE_HZ, gyro_odr_hz, MAX_ACCEL_RATE_DIVIDER);
+
+        Ok(Self {
+            accel_range: AccelRange::from_g(accel_range_g)?,
+            gyro_range: GyroRange::from_dps(gyro_range_dps)?,
+            accel_dlpf: nearest_dlpf(&ACCEL_DLPF_HZ, accel_dlpf_hz as f32),
+            gyro_dlpf: nearest_dlpf(&GYRO_DLPF_HZ, gyro_dlpf_hz as f32),
+            gyro_rate_divider,
+            accel_rate_divider,
+        })
+    }
+
+    /// Rate FIFO frames arrive at in Hz, the gyro's ODR
+    pub fn sample_rate_hz(&self) -> f64 {
+        self.gyro_sample_rate_hz()
+    }
+
+    /// Gyro output data rate in Hz
+    pub fn gyro_sample_rate_hz(&self) -> f64 {
+        GYRO_BASE_SAMPLE_RATE_HZ / (1.0 + self.gyro_rate_divider as f64)
+    }
+
+    /// Accel output data rate in Hz
+    pub fn accel_sample_rate_hz(&self) -> f64 {
+        ACCEL_BASE_SAMPLE_RATE_HZ / (1.0 + self.accel_rate_divider as f64)
+    }
+
+    /// Accel DLPF 3 dB bandwidth in Hz
+    pub fn accel_bandwidth_hz(&self) -> f32 {
+        ACCEL_DLPF_HZ[self.accel_dlpf as usize & 0x07]
+    }
+
+    /// Gyro DLPF 3 dB bandwidth in Hz
+    pub fn gyro_bandwidth_hz(&self) -> f32 {
+        GYRO_DLPF_HZ[self.gyro_dlpf as usize & 0x07]
+    }
+
+    /// Rate and raw count scaling for reading the FIFO
+    pub fn fifo_config(&self) -> FifoConfig {
+        FifoConfig {
+            sample_rate_divider: self.gyro_rate_divider,
+            accel_scale: self.accel_range.scale(),
+            gyro_scale: self.gyro_range.scale(),
+        }
+    }
+
+    fn gyro_config_1(&self) -> u8 {
+        ((self.gyro_dlpf & 0x07) << DLPFCFG_SHIFT) | (self.gyro_range.fs_sel() << FS_SEL_SHIFT) | FCHOICE
+    }
+
+    fn accel_config(&self) -> u8 {
+        ((self.accel_dlpf & 0x07) << DLPFCFG_SHIFT) | (self.accel_range.fs_sel() << FS_SEL_SHIFT) | FCHOICE
+    }
+
+    /// Write the configuration to the sensor and read every register back to check it stuck
+    pub fn apply<B: SpiBus>(&self, registers: &Registers<B>) -> Result<(), ImuError> {
+        let writes = [
+            (GYRO_CONFIG_1, self.gyro_config_1()),
+            (ACCEL_CONFIG, self.accel_config()),
+            (GYRO_SMPLRT_DIV, self.gyro_rate_divider),
+            (ACCEL_SMPLRT_DIV_1, (self.accel_rate_divider >> 8) as u8),
+            (ACCEL_SMPLRT_DIV_2, self.accel_rate_divider as u8),
+        ];
+        for (register, value) in writes {
+            registers.write(register, value)?;
+        }
+        for (register, written) in writes {
+            let found = registers.read(register)?;
+            if found != written {
+                return Err(ImuError::Readback { register, written, found });
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Divider of `base_hz` giving the rate closest to `odr_hz`, at most `max`
+fn rate_divider(base_hz: f64, odr_hz: f64, max: u16) -> u16 {
+    (base_hz / odr_hz - 1.0).round().clamp(0.0, max as f64) as u16
+}
+
+/// DLPFCFG setting whose bandwidth is closest to `hz`
+fn nearest_dlpf(table: &[f32; 8], hz: f32) -> u8 {
+    (0..table.len())
+        .min_by(|&a, &b| (table[a] - hz).abs().total_cmp(&(table[b] - hz).abs()))
+        .unwrap_or(0) as u8
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock_spi::MockIcm20948;
+    use std::sync::{Arc, Mutex};
+
+    const TOLERANCE: f64 = 1e-9;
+
+    /// Configuration at the driver's ranges and widest DLPFs, sampling at `odr_hz`
+    fn at_odr(odr_hz: f64) -> SensorConfig {
+        SensorConfig::new(2, 250, 246.0, 196.6, odr_hz).unwrap()
+    }
+
+    #[test]
+    fn gyro_and_accel_dividers_count_down_from_their_own_clocks() {
+        // Full rate: the gyro can't go past 1100 Hz, the accel runs at 1125 Hz
+        let full = at_odr(1100.0);
+        assert_eq!((full.gyro_rate_divider, full.accel_rate_divider), (0, 0));
+        assert!((full.gyro_sample_rate_hz() - 1100.0).abs() < TOLERANCE);
+        assert!((full.accel_sample_rate_hz() - 1125.0).abs() < TOLERANCE);
+        assert!((full.sample_rate_hz() - full.gyro_sample_rate_hz()).abs() < TOLERANCE);
+
+        // 225 Hz isn't 1100 Hz over a whole number: the gyro rounds to 220 Hz, and the accel to the
+        // rate nearest that, 1125 / 5
+        let config = at_odr(225.0);
+        assert_eq!((config.gyro_rate_divider, config.accel_rate_divider), (4, 4));
+        assert!((config.gyro_sample_rate_hz() - 220.0).abs() < TOLERANCE);
+        assert!((config.accel_sample_rate_hz() - 225.0).abs() < TOLERANCE);
+        assert_eq!(config.fifo_config().sample_rate_divider, 4);
+
+        let config = at_odr(100.0);
+        assert_eq!((config.gyro_rate_divider, config.accel_rate_divider), (10, 10));
+        assert!((config.gyro_sample_rate_hz() - 100.0).abs() < TOLERANCE);
+    }
+
+    #[test]
+    fn slowest_gyro_rate_needs_the_accel_divider_high_bits() {
+        let config = at_odr(4.3);
+        assert_eq!(config.gyro_rate_divider, 255);
+        // 1125 / (1100 / 256) - 1 = 260.8
+        assert_eq!(config.accel_rate_divider, 261);
+
+        let device = Arc::new(Mutex::new(MockIcm20948::new()));
+        let registers = Registers::new(device.clone());
+        config.apply(&registers).unwrap();
+        assert_eq!(registers.read(GYRO_SMPLRT_DIV).unwrap(), 255);
+        assert_eq!(registers.read(ACCEL_SMPLRT_DIV_1).unwrap(), 1);
+        assert_eq!(registers.read(ACCEL_SMPLRT_DIV_2).unwrap(), 5);
+    }
+
+    #[test]
+    fn out_of_range_settings_are_rejected() {
+        for odr in [0.0, -100.0, f64::NAN, 1100.1, 4.0] {
+            assert!(SensorConfig::new(2, 250, 246.0, 196.6, odr).is_err(), "{}", odr);
+        }
+        assert!(SensorConfig::new(3, 250, 246.0, 196.6, 1100.0).is_err());
+        assert!(SensorConfig::new(2, 300, 246.0, 196.6, 1100.0).is_err());
+    }
+
+    #[test]
+    fn dlpf_bandwidths_round_to_the_nearest_supported() {
+        let config = SensorConfig::new(2, 250, 100.0, 150.0, 1100.0).unwrap();
+        assert_eq!(config.accel_dlpf, 2);
+        assert!((config.accel_bandwidth_hz() - 111.4).abs() < 1e-3);
+        assert_eq!(config.gyro_dlpf, 1);
+        assert!((config.gyro_bandwidth_hz() - 151.8).abs() < 1e-3);
+
+        // Wider than anything the DLPF does, the widest setting
+        let config = SensorConfig::new(2, 250, 1000.0, 1000.0, 1100.0).unwrap();
+        assert_eq!((config.accel_dlpf, config.gyro_dlpf), (7, 7));
+    }
+
+    #[test]
+    fn ranges_and_dlpfs_are_encoded_into_the_config_registers() {
+        let config = SensorConfig::new(16, 2000, 50.4, 51.2, 1100.0).unwrap();
+        // DLPFCFG in bits 5:3, FS_SEL in bits 2:1, FCHOICE in bit 0
+        assert_eq!(config.gyro_config_1(), (3 << 3) | (3 << 1) | 1);
+        assert_eq!(config.accel_config(), (3 << 3) | (3 << 1) | 1);
+        let config = SensorConfig::new(4, 500, 5.7, 361.4, 1100.0).unwrap();
+        assert_eq!(config.accel_config(), (6 << 3) | (1 << 1) | 1);
+        assert_eq!(config.gyro_config_1(), (7 << 3) | (1 << 1) | 1);
+
+        let device = Arc::new(Mutex::new(MockIcm20948::new()));
+        let registers = Registers::new(device.clone());
+        config.apply(&registers).unwrap();
+        assert_eq!(registers.read(ACCEL_CONFIG).unwrap(), config.accel_config());
+        assert_eq!(registers.read(GYRO_CONFIG_1).unwrap(), config.gyro_config_1());
+
+        // Full scale over the 16 bit signed range
+        assert!((AccelRange::G16.scale() as f64 - 16.0 * GRAVITY / 32768.0).abs() < 1e-9);
+        assert!((GyroRange::Dps2000.scale() as f64 - 2000f64.to_radians() / 32768.0).abs() < 1e-9);
+    }
+}
@@ -0,0 +1,349 @@
+//! Motor vibration tracking for the gyroscope.
+//!
+//! With the props spinning the gyro picks up narrow-band vibration at the motor and prop rates,
+//! which move with throttle. `VibrationAnalyzer` runs an FFT over a sliding window of gyro samples
+//! to find the strongest of these peaks, and `NotchBank` follows them with biquad notch filters.
+
+use crate::error::ImuError;
+use biquad::{Biquad, Coefficients, DirectForm1, ToHertz};
+use rustfft::num_complex::Complex;
+use rustfft::{Fft, FftPlanner};
+use std::collections::VecDeque;
+use std::f32::consts::PI;
+use std::sync::Arc;
+
+/// Fraction of the way a notch moves towards a newly detected peak, so one noisy spectrum doesn't
+/// throw it around
+const CENTER_SMOOTHING: f32 = 0.3;
+
+/// Highest fraction of the Nyquist frequency peaks are searched up to. Closer to it the notch gets
+/// too wide to place usefully, and the anti-aliasing DLPF has already attenuated the vibration.
+const MAX_NYQUIST_FRACTION: f32 = 0.9;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct VibrationSettings {
+    pub sample_rate_hz: f32,
+    pub window: usize,      // FFT length in samples
+    pub hop: usize,         // Samples between analyses
+    pub min_hz: f32,        // Peaks below this are flight dynamics, not vibration
+    pub max_hz: f32,
+    pub peaks: usize,       // Number of peaks to track
+    pub min_snr: f32,       // Peak power over the median power to count as vibration
+    pub notch_q: f32,
+}
+
+impl VibrationSettings {
+    /// These settings with `max_hz` lowered to within the Nyquist frequency, or None if that leaves
+    /// nothing above `min_hz` to search, as at a low ODR
+    pub fn within_nyquist(self) -> Option<Self> {
+        let max_hz = self.max_hz.min(MAX_NYQUIST_FRACTION * self.sample_rate_hz / 2.0);
+        (max_hz > self.min_hz).then_some(Self { max_hz, ..self })
+    }
+}
+
+/// Finds the dominant vibration frequencies in the gyro signal
+pub struct VibrationAnalyzer {
+    settings: VibrationSettings,
+    fft: Arc<dyn Fft<f32>>,
+    window_function: Vec<f32>,
+    history: [VecDeque<f32>; 3],
+    since_analysis: usize,
+}
+
+impl VibrationAnalyzer {
+    pub fn new(settings: VibrationSettings) -> Result<Self, ImuError> {
+        if settings.window < 16 {
+            return Err(ImuError::Config(format!(
+                "Vibration window must be at least 16 samples, got {}",
+                settings.window
+            )));
+        }
+        if settings.min_hz >= settings.max_hz || settings.max_hz > settings.sample_rate_hz / 2.0 {
+            return Err(ImuError::Config(format!(
+                "Vibration range {}-{} Hz must be within 0-{} Hz",
+                settings.min_hz,
+                settings.max_hz,
+                settings.sample_rate_hz / 2.0
+            )));
+        }
+
+        // Hann window to keep leakage from the strongest peak from hiding the others
+        let n = settings.window;
+        let window_function = (0..n)
+            .map(|i| 0.5 - 0.5 * (2.0 * PI * i as f32 / n as f32).cos())
+            .collect();
+
+        Ok(Self {
+            fft: FftPlanner::new().plan_fft_forward(n),
+            window_function,
+            history: [VecDeque::with_capacity(n), VecDeque::with_capacity(n), VecDeque::with_capacity(n)],
+            since_analysis: 0,
+            settings,
+        })
+    }
+
+    /// Add a bias-corrected gyro sample. Every `hop` samples, once the window is full, returns the
+    /// detected peak frequencies in Hz, strongest first.
+    pub fn push(&mut self, gyro: [f32; 3]) -> Option<Vec<f32>> {
+        for (history, value) in self.history.iter_mut().zip(gyro.iter()) {
+            if history.len() == self.settings.window {
+                history.pop_front();
+            }
+            history.push_back(*value);
+        }
+
+        self.since_analysis += 1;
+        if self.history[0].len() < self.settings.window || self.since_analysis < self.settings.hop.max(1) {
+            return None;
+        }
+        self.since_analysis = 0;
+        Some(self.analyze())
+    }
+
+    fn analyze(&self) -> Vec<f32> {
+        let n = self.settings.window;
+        let bin_hz = self.settings.sample_rate_hz / n as f32;
+
+        // Power summed over the three axes, a motor shakes all of them at the same frequency
+        let mut power = vec![0.0f32; n / 2 + 1];
+        for history in &self.history {
+            let mean = history.iter().sum::<f32>() / n as f32;
+            let mut buffer: Vec<Complex<f32>> = history
+                .iter()
+                .zip(self.window_function.iter())
+                .map(|(value, weight)| Complex::new((value - mean) * weight, 0.0))
+                .collect();
+            self.fft.process(&mut buffer);
+            for (bin, value) in power.iter_mut().zip(buffer.iter()) {
+                *bin += value.norm_sqr();
+            }
+        }
+
+        let first = ((self.settings.min_hz / bin_hz).ceil() as usize).max(1);
+        let last = ((self.settings.max_hz / bin_hz).floor() as usize).min(n / 2 - 1);
+        if first >= last {
+            return Vec::new();
+        }
+
+        // The median is a noise floor estimate that a few strong peaks don't drag up
+        let mut sorted = power[first..=last].to_vec();
+        sorted.sort_by(|a, b| a.total_cmp(b));
+        let floor = sorted[sorted.len() / 2].max(f32::MIN_POSITIVE);
+
+        let mut peaks: Vec<(f32, f32)> = (first..=last)
+            .filter(|&bin| power[bin] > power[bin - 1] && power[bin] >= power[bin + 1])
+            .filter(|&bin| power[bin] > self.settings.min_snr * floor)
+            .map(|bin| {
+                // Parabolic interpolation between bins for sub-bin frequency resolution
+                let (left, center, right) = (power[bin - 1], power[bin], power[bin + 1]);
+                let denominator = left - 2.0 * center + right;
+                let offset = if denominator.abs() > f32::EPSILON {
+                    0.5 * (left - right) / denominator
+                } else {
+                    0.0
+                };
+                ((bin as f32 + offset) * bin_hz, center)
+            })
+            .collect();
+
+        peaks.sort_by(|a, b| b.1.total_cmp(&a.1));
+        peaks.truncate(self.settings.peaks);
+        peaks.into_iter().map(|(frequency, _)| frequency).collect()
+    }
+}
+
+/// Notch filters on all three gyro axes, each following one vibration peak
+pub struct NotchBank {
+    sample_rate_hz: f32,
+    q: f32,
+    notches: Vec<Notch>,
+}
+
+struct Notch {
+    center_hz: Option<f32>, // None while there's no peak to follow, the notch is bypassed
+    axes: [DirectForm1<f32>; 3],
+}
+
+impl NotchBank {
+    pub fn new(settings: &VibrationSettings) -> Self {
+        let notches = (0..settings.peaks)
+            .map(|_| Notch {
+                center_hz: None,
+                axes: [DirectForm1::<f32>::new(passthrough()); 3],
+            })
+            .collect();
+        Self {
+            sample_rate_hz: settings.sample_rate_hz,
+            q: settings.notch_q,
+            notches,
+        }
+    }
+
+    /// Move the notches onto newly detected peaks
+    pub fn steer(&mut self, peaks: &[f32]) {
+        // Pair notches and peaks in frequency order so each notch follows the same motor harmonic
+        let mut peaks = peaks.to_vec();
+        peaks.sort_by(|a, b| a.total_cmp(b));
+
+        for (index, notch) in self.notches.iter_mut().enumerate() {
+            let Some(&peak) = peaks.get(index) else {
+                // Nothing to follow, bypass until a peak shows up again
+                if notch.center_hz.take().is_some() {
+                    for axis in notch.axes.iter_mut() {
+                        axis.reset_state();
+                    }
+                }
+                continue;
+            };
+
+            let center = match notch.center_hz {
+                Some(center) => center + CENTER_SMOOTHING * (peak - center),
+                None => peak,
+            };
+            let coeffs = match Coefficients::<f32>::from_params(
+                biquad::Type::Notch,
+                self.sample_rate_hz.hz(),
+                center.hz(),
+                self.q,
+            ) {
+                Ok(coeffs) => coeffs,
+                Err(_) => continue,
+            };
+
+            // Keep the filter state while the center moves, resetting it would glitch the output
+            for axis in notch.axes.iter_mut() {
+                axis.update_coefficients(coeffs);
+            }
+            notch.center_hz = Some(center);
+        }
+    }
+
+    /// Current notch centers in Hz, for the active notches
+    pub fn centers(&self) -> Vec<f32> {
+        self.notches.iter().filter_map(|notch| notch.center_hz).collect()
+    }
+
+    /// Filter one gyro sample through every active notch
+    pub fn run(&mut self, sample: [f32; 3]) -> [f32; 3] {
+        let mut output = sample;
+        for notch in self.notches.iter_mut().filter(|notch| notch.center_hz.is_some()) {
+            for (axis, value) in notch.axes.iter_mut().zip(output.iter_mut()) {
+                *value = axis.run(*value);
+            }
+        }
+        output
+    }
+}
+
+/// Coefficients for a biquad that passes its input straight through
+fn passthrough() -> Coefficients<f32> {
+    Coefficients {
+        a1: 0.0,
+        a2: 0.0,
+        b0: 1.0,
+        b1: 0.0,
+        b2: 0.0,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SAMPLE_RATE: f32 = 1000.0;
+    const WINDOW: usize = 256;
+    const BIN_HZ: f32 = SAMPLE_RATE / WINDOW as f32;
+
+    fn settings() -> VibrationSettings {
+        VibrationSettings {
+            sample_rate_hz: SAMPLE_RATE,
+            window: WINDOW,
+            hop: 32,
+            min_hz: 20.0,
+            max_hz: 450.0,
+            peaks: 2,
+            min_snr: 10.0,
+            notch_q: 5.0,
+        }
+    }
+
+    /// Gyro sample `i` shaking at each (frequency Hz, amplitude rad/s), on all axes with different phases
+    fn vibration(i: usize, tones: &[(f32, f32)]) -> [f32; 3] {
+        let t = i as f32 / SAMPLE_RATE;
+        [0.0, 1.0, 2.0].map(|phase| {
+            tones
+                .iter()
+                .map(|(frequency, amplitude)| amplitude * (2.0 * PI * frequency * t + phase).sin())
+                .sum()
+        })
+    }
+
+    /// Largest absolute value on any axis over `samples` samples through the bank, after it settles
+    fn peak_output(bank: &mut NotchBank, frequency: f32, samples: usize) -> f32 {
+        let mut peak: f32 = 0.0;
+        for i in 0..samples {
+            let output = bank.run(vibration(i, &[(frequency, 1.0)]));
+            if i > samples / 2 {
+                peak = output.iter().fold(peak, |peak, value| peak.max(value.abs()));
+            }
+        }
+        peak
+    }
+
+    #[test]
+    fn peaks_between_bins_are_interpolated() {
+        let mut analyzer = VibrationAnalyzer::new(settings()).unwrap();
+        // Neither tone is on a bin, the stronger a third of a bin above one
+        let strong = 38.3 * BIN_HZ;
+        let weak = 61.7 * BIN_HZ;
+        let mut peaks = None;
+        for i in 0..WINDOW {
+            peaks = analyzer.push(vibration(i, &[(strong, 1.0), (weak, 0.3)])).or(peaks);
+        }
+        let peaks = peaks.expect("no analysis once the window filled");
+
+        assert_eq!(peaks.len(), 2);
+        // A parabola through Hann windowed power is pulled towards the bin by up to 0.12 of a bin,
+        // most of the way between bins, still well inside the third of a bin rounding would lose
+        assert!((peaks[0] - strong).abs() < 0.12 * BIN_HZ, "{} vs {}", peaks[0], strong);
+        assert!((peaks[1] - weak).abs() < 0.12 * BIN_HZ, "{} vs {}", peaks[1], weak);
+    }
+
+    #[test]
+    fn notch_attenuates_the_peak_it_follows() {
+        let frequency = 38.3 * BIN_HZ;
+        let mut bank = NotchBank::new(&settings());
+        assert!(bank.centers().is_empty());
+        // Bypassed until steered
+        assert!(peak_output(&mut bank, frequency, 1000) > 0.99);
+
+        bank.steer(&[frequency]);
+        assert_eq!(bank.centers(), vec![frequency]);
+        assert!(peak_output(&mut bank, frequency, 1000) < 0.05);
+        // Well away from the notch the signal passes
+        assert!(peak_output(&mut bank, 40.0, 1000) > 0.9);
+
+        // The center moves part of the way towards a new peak
+        bank.steer(&[frequency + 10.0]);
+        assert!((bank.centers()[0] - (frequency + CENTER_SMOOTHING * 10.0)).abs() < 1e-3);
+        bank.steer(&[]);
+        assert!(bank.centers().is_empty());
+    }
+
+    #[test]
+    fn search_range_is_limited_to_below_nyquist() {
+        // Within range already, left alone
+        assert_eq!(settings().within_nyquist(), Some(settings()));
+
+        // At 500 Hz the default 450 Hz limit is above Nyquist
+        let slow = VibrationSettings { sample_rate_hz: 500.0, ..settings() };
+        assert!(VibrationAnalyzer::new(slow).is_err());
+        let limited = slow.within_nyquist().unwrap();
+        assert!((limited.max_hz - 225.0).abs() < 1e-3, "{}", limited.max_hz);
+        assert!(VibrationAnalyzer::new(limited).is_ok());
+
+        // At 40 Hz nothing above the 20 Hz minimum can be seen
+        let slowest = VibrationSettings { sample_rate_hz: 40.0, ..settings() };
+        assert_eq!(slowest.within_nyquist(), None);
+    }
+}
@@ -0,0 +1,371 @@
+//! Fixed-rate loop timing for the sampling and control loops.
+//!
+//! `PeriodicScheduler` sleeps until absolute deadlines on the monotonic clock with
+//! `clock_nanosleep(TIMER_ABSTIME)`, so time spent doing the work doesn't push later cycles back
+//! and the loop doesn't drift. A cycle that runs past its deadline is counted as an overrun and the
+//! missed deadlines are skipped rather than run back to back. Wake-up latency and execution time
+//! are collected into `SchedulerStats`, which can be published on `/diagnostics`.
+
+use diagnostic_msgs::msg::{DiagnosticStatus, KeyValue};
+use std::time::Duration;
+
+const NANOS_PER_SEC: u64 = 1_000_000_000;
+
+#[derive(Clone, Debug)]
+pub struct SchedulerConfig {
+    pub period: Duration,
+    pub realtime_priority: Option<i32>, // SCHED_FIFO priority (1-99), None keeps the normal scheduler
+    pub cpu: Option<usize>,             // Pin the loop thread to this core
+    pub report_interval: Duration,      // How often `take_report` returns statistics
+}
+
+impl SchedulerConfig {
+    /// Run at `rate_hz` on the normal scheduler, reporting once per second
+    pub fn from_rate(rate_hz: f64) -> Self {
+        Self {
+            period: Duration::from_secs_f64(1.0 / rate_hz),
+            realtime_priority: None,
+            cpu: None,
+            report_interval: Duration::from_secs(1),
+        }
+    }
+}
+
+/// Timing statistics over one reporting interval
+#[derive(Clone, Debug, Default)]
+pub struct SchedulerStats {
+    pub period: Duration,
+    pub cycles: u64,
+    pub overruns: u64,           // Cycles that were still running at the next deadline
+    pub missed_deadlines: u64,   // Deadlines skipped because of overruns
+    pub total_overruns: u64,     // Since the scheduler started
+    pub mean_latency: Duration,  // Wake-up time after the deadline
+    pub max_latency: Duration,
+    pub max_execution: Duration, // Time from waking to the next wait
+    pub realtime: bool,
+}
+
+impl SchedulerStats {
+    /// Diagnostics entry for this loop, a warning if any cycle overran
+    pub fn to_diagnostic_status(&self, name: &str, hardware_id: &str) -> DiagnosticStatus {
+        let (level, message) = if self.overruns == 0 {
+            (DiagnosticStatus::OK, "OK".to_string())
+        } else {
+            (
+                DiagnosticStatus::WARN,
+                format!("{} overruns in {} cycles", self.overruns, self.cycles),
+            )
+        };
+
+        let value = |key: &str, value: String| KeyValue {
+            key: key.to_string(),
+            value,
+        };
+        let micros = |duration: Duration| format!("{:.1}", duration.as_secs_f64() * 1e6);
+
+        DiagnosticStatus {
+            level,
+            name: name.to_string(),
+            message,
+            hardware_id: hardware_id.to_string(),
+            values: vec![
+                value("period_us", micros(self.period)),
+                value("cycles", self.cycles.to_string()),
+                value("overruns", self.overruns.to_string()),
+                value("missed_deadlines", self.missed_deadlines.to_string()),
+                value("total_overruns", self.total_overruns.to_string()),
+                value("mean_latency_us", micros(self.mean_latency)),
+                value("max_latency_us", micros(self.max_latency)),
+                value("max_execution_us", micros(self.max_execution)),
+                value("realtime", self.realtime.to_string()),
+            ],
+        }
+    }
+}
+
+/// Runs the calling thread at a fixed rate
+pub struct PeriodicScheduler {
+    period_ns: u64,
+    report_interval_ns: u64,
+    next_deadline: u64, // Monotonic clock, ns
+    last_wake: Option<u64>,
+    last_report: u64,
+    realtime: bool,
+    total_overruns: u64,
+    window: Window,
+}
+
+/// Accumulators for the current reporting interval
+#[derive(Default)]
+struct Window {
+    cycles: u64,
+    overruns: u64,
+    missed_deadlines: u64,
+    latency_sum: u64,
+    max_latency: u64,
+    max_execution: u64,
+}
+
+impl PeriodicScheduler {
+    /// Create a scheduler for the calling thread, applying the real-time priority and CPU affinity.
+    ///
+    /// Both need privileges (root or CAP_SYS_NICE for SCHED_FIFO), so if they can't be applied the
+    /// loop carries on with a warning on the normal scheduler. Must be called on the loop's thread.
+    pub fn new(config: SchedulerConfig) -> Self {
+        if let Some(cpu) = config.cpu {
+            if let Err(err) = pin_to_cpu(cpu) {
+                eprintln!("Scheduler: not pinned to CPU {}: {}", cpu, err);
+            }
+        }
+
+        let realtime = match config.realtime_priority {
+            Some(priority) => match set_fifo_priority(priority) {
+                Ok(()) => true,
+                Err(err) => {
+                    eprintln!("Scheduler: SCHED_FIFO priority {} not applied, running best effort: {}", priority, err);
+                    false
+                }
+            },
+            None => false,
+        };
+
+        let now = monotonic_now();
+        let period_ns = (config.period.as_nanos() as u64).max(1);
+        Self {
+            period_ns,
+            report_interval_ns: config.report_interval.as_nanos() as u64,
+            next_deadline: now + period_ns,
+            last_wake: None,
+            last_report: now,
+            realtime,
+            total_overruns: 0,
+            window: Window::default(),
+        }
+    }
+
+    /// Sleep until the next deadline. If the previous cycle overran it returns immediately and the
+    /// schedule moves on to the next deadline still in the future.
+    pub fn wait(&mut self) {
+        let now = monotonic_now();
+        if let Some(last_wake) = self.last_wake {
+            self.window.max_execution = self.window.max_execution.max(now.saturating_sub(last_wake));
+        }
+
+        let (deadline, on_time) = self.advance(now);
+        if on_time {
+            sleep_until(deadline);
+        }
+        self.record_wake(deadline, monotonic_now());
+    }
+
+    /// Move the schedule on from `now`, returning the deadline this cycle belongs to and whether it's
+    /// still ahead to sleep until
+    fn advance(&mut self, now: u64) -> (u64, bool) {
+        if now < self.next_deadline {
+            let deadline = self.next_deadline;
+            self.next_deadline += self.period_ns;
+            return (deadline, true);
+        }
+
+        // Running late, catch up to the deadline we're in rather than bursting through the missed ones
+        let missed = (now - self.next_deadline) / self.period_ns;
+        self.window.overruns += 1;
+        self.window.missed_deadlines += missed;
+        self.total_overruns += 1;
+        let deadline = self.next_deadline + missed * self.period_ns;
+        self.next_deadline = deadline + self.period_ns;
+        (deadline, false)
+    }
+
+    fn record_wake(&mut self, deadline: u64, wake: u64) {
+        let latency = wake.saturating_sub(deadline);
+        self.window.cycles += 1;
+        self.window.latency_sum += latency;
+        self.window.max_latency = self.window.max_latency.max(latency);
+        self.last_wake = Some(wake);
+    }
+
+    /// Statistics since the last report, once every `report_interval`
+    pub fn take_report(&mut self) -> Option<SchedulerStats> {
+        let now = monotonic_now();
+        if now.saturating_sub(self.last_report) < self.report_interval_ns || self.window.cycles == 0 {
+            return None;
+        }
+        self.last_report = now;
+
+        let window = std::mem::take(&mut self.window);
+        Some(SchedulerStats {
+            period: Duration::from_nanos(self.period_ns),
+            cycles: window.cycles,
+            overruns: window.overruns,
+            missed_deadlines: window.missed_deadlines,
+            total_overruns: self.total_overruns,
+            mean_latency: Duration::from_nanos(window.latency_sum / window.cycles),
+            max_latency: Duration::from_nanos(window.max_latency),
+            max_execution: Duration::from_nanos(window.max_execution),
+            realtime: self.realtime,
+        })
+    }
+}
+
+fn monotonic_now() -> u64 {
+    let mut time = libc::timespec { tv_sec: 0, tv_nsec: 0 };
+    // CLOCK_MONOTONIC can't fail on Linux
+    unsafe { libc::clock_gettime(libc::CLOCK_MONOTONIC, &mut time) };
+    time.tv_sec as u64 * NANOS_PER_SEC + time.tv_nsec as u64
+}
+
+fn sleep_until(deadline: u64) {
+    let time = libc::timespec {
+        tv_sec: (deadline / NANOS_PER_SEC) as libc::time_t,
+        tv_nsec: (deadline % NANOS_PER_SEC) as libc::c_long,
+    };
+    // An absolute deadline can simply be retried when a signal interrupts the sleep
+    while unsafe { libc::clock_nanosleep(libc::CLOCK_MONOTONIC, libc::TIMER_ABSTIME, &time, std::ptr::null_mut()) }
+        == libc::EINTR
+    {}
+}
+
+fn set_fifo_priority(priority: i32) -> Result<(), String> {
+    let param = libc::sched_param {
+        sched_priority: priority,
+    };
+    let result = unsafe { libc::pthread_setschedparam(libc::pthread_self(), libc::SCHED_FIFO, &param) };
+    if result != 0 {
+        return Err(std::io::Error::from_raw_os_error(result).to_string());
+    }
+    Ok(())
+}
+
+fn pin_to_cpu(cpu: usize) -> Result<(), String> {
+    if cpu >= libc::CPU_SETSIZE as usize {
+        return Err(format!("CPU index must be below {}", libc::CPU_SETSIZE));
+    }
+    unsafe {
+        let mut set: libc::cpu_set_t = std::mem::zeroed();
+        libc::CPU_SET(cpu, &mut set);
+        // pid 0 is the calling thread
+        if libc::sched_setaffinity(0, std::mem::size_of::<libc::cpu_set_t>(), &set) != 0 {
+            return Err(std::io::Error::last_os_error().to_string());
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PERIOD: u64 = 10_000_000; // ns, 100 Hz
+    const START: u64 = 1_000 * NANOS_PER_SEC;
+
+    /// A 100 Hz scheduler on the normal scheduler, reporting on every call, its next deadline at `START`
+    fn scheduler() -> PeriodicScheduler {
+        let mut scheduler = PeriodicScheduler::new(SchedulerConfig {
+            report_interval: Duration::ZERO,
+            ..SchedulerConfig::from_rate(100.0)
+        });
+        scheduler.next_deadline = START;
+        scheduler
+    }
+
+    #[test]
+    fn deadlines_advance_by_whole_periods_whatever_the_wake_time() {
+        let mut scheduler = scheduler();
+        // Work finishing at a different point of each cycle doesn't move the schedule
+        for (cycle, done) in [0.2, 0.9, 0.5, 0.99].into_iter().enumerate() {
+            let deadline = START + cycle as u64 * PERIOD;
+            let now = deadline - ((1.0 - done) * PERIOD as f64) as u64;
+            assert_eq!(scheduler.advance(now), (deadline, true));
+            scheduler.record_wake(deadline, deadline + 50_000);
+        }
+        assert_eq!(scheduler.next_deadline, START + 4 * PERIOD);
+
+        let stats = scheduler.take_report().unwrap();
+        assert_eq!(stats.cycles, 4);
+        assert_eq!(stats.overruns, 0);
+        assert_eq!(stats.mean_latency, Duration::from_micros(50));
+    }
+
+    #[test]
+    fn overrun_skips_the_missed_deadlines_and_catches_up() {
+        let mut scheduler = scheduler();
+        assert_eq!(scheduler.advance(START - 1), (START, true));
+        scheduler.record_wake(START, START);
+
+        // The next cycle runs 3.5 periods, past the deadlines at +1 and +2 periods and into +3
+        let late = START + 3 * PERIOD + PERIOD / 2;
+        assert_eq!(scheduler.advance(late), (START + 3 * PERIOD, false));
+        scheduler.record_wake(START + 3 * PERIOD, late);
+        assert_eq!(scheduler.next_deadline, START + 4 * PERIOD);
+
+        // Back on schedule from there
+        assert_eq!(scheduler.advance(late + 1000), (START + 4 * PERIOD, true));
+        scheduler.record_wake(START + 4 * PERIOD, START + 4 * PERIOD);
+
+        let stats = scheduler.take_report().unwrap();
+        assert_eq!(stats.cycles, 3);
+        assert_eq!(stats.overruns, 1);
+        assert_eq!(stats.missed_deadlines, 2);
+        assert_eq!(stats.total_overruns, 1);
+        assert_eq!(stats.max_latency, Duration::from_nanos(PERIOD / 2));
+
+        // The interval counts start again, the total doesn't
+        assert_eq!(scheduler.advance(START + 5 * PERIOD), (START + 5 * PERIOD, false));
+        scheduler.record_wake(START + 5 * PERIOD, START + 5 * PERIOD);
+        let stats = scheduler.take_report().unwrap();
+        assert_eq!((stats.cycles, stats.overruns, stats.missed_deadlines), (1, 1, 0));
+        assert_eq!(stats.total_overruns, 2);
+    }
+
+    #[test]
+    fn no_report_without_cycles() {
+        assert!(scheduler().take_report().is_none());
+    }
+
+    #[test]
+    fn diagnostic_status_warns_on_overruns() {
+        let mut stats = SchedulerStats {
+            period: Duration::from_millis(10),
+            cycles: 100,
+            overruns: 0,
+            missed_deadlines: 0,
+            total_overruns: 3,
+            mean_latency: Duration::from_nanos(52_340),
+            max_latency: Duration::from_micros(410),
+            max_execution: Duration::from_micros(2_500),
+            realtime: true,
+        };
+
+        let status = stats.to_diagnostic_status("imu loop", "icm20948_spidev0.0");
+        assert_eq!(status.level, DiagnosticStatus::OK);
+        assert_eq!(status.message, "OK");
+        assert_eq!(status.name, "imu loop");
+        assert_eq!(status.hardware_id, "icm20948_spidev0.0");
+        let values: Vec<(&str, &str)> = status
+            .values
+            .iter()
+            .map(|value| (value.key.as_str(), value.value.as_str()))
+            .collect();
+        assert_eq!(
+            values,
+            [
+                ("period_us", "10000.0"),
+                ("cycles", "100"),
+                ("overruns", "0"),
+                ("missed_deadlines", "0"),
+                ("total_overruns", "3"),
+                ("mean_latency_us", "52.3"),
+                ("max_latency_us", "410.0"),
+                ("max_execution_us", "2500.0"),
+                ("realtime", "true"),
+            ]
+        );
+
+        stats.overruns = 2;
+        stats.missed_deadlines = 5;
+        let status = stats.to_diagnostic_status("imu loop", "icm20948_spidev0.0");
+        assert_eq!(status.level, DiagnosticStatus::WARN);
+        assert_eq!(status.message, "2 overruns in 100 cycles");
+    }
+}
@@ -0,0 +1,186 @@
+//! Decides how far to trust each accelerometer sample as a gravity reference.
+//!
+//! The attitude filters correct roll and pitch by assuming the accelerometer only sees gravity. In a
+//! hard turn or climb it also sees the vehicle's acceleration, and trusting it then tilts the
+//! estimate. Two signs of that are checked: the magnitude straying from 1 g, and the measured
+//! direction disagreeing with where the current estimate puts gravity. A small magnitude error only
+//! inflates the sample's noise so it counts for less; a large one, or a large disagreement, rejects
+//! it and the filter coasts on the gyro.
+
+use attitude_pkg::rotation::{self, Quaternion};
+use crate::eskf::GRAVITY;
+
+/// Consecutive innovation rejections before the estimate is assumed to be the one that's wrong
+const MAX_INNOVATION_REJECTIONS: usize = 200;
+
+#[derive(Clone, Debug)]
+pub struct AccelGateConfig {
+    pub inflate_deviation: f64, // m/s^2 from 1 g where the noise starts to be inflated
+    pub reject_deviation: f64,  // m/s^2 from 1 g beyond which samples are rejected
+    pub max_noise_scale: f64,   // Noise multiplier reached at the rejection threshold
+    pub max_innovation: f64,    // rad between measured and estimated gravity beyond which samples are rejected
+}
+
+/// What to do with an accelerometer sample
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum AccelCheck {
+    /// Use it as it is
+    Accepted,
+    /// Use it with its noise standard deviation multiplied by this
+    Inflated(f64),
+    /// Magnitude this far (m/s^2) from 1 g
+    RejectedMagnitude(f64),
+    /// Direction this far (rad) from the estimated gravity
+    RejectedInnovation(f64),
+}
+
+impl AccelCheck {
+    pub fn is_rejected(&self) -> bool {
+        matches!(self, AccelCheck::RejectedMagnitude(_) | AccelCheck::RejectedInnovation(_))
+    }
+}
+
+pub struct AccelGate {
+    config: AccelGateConfig,
+    innovation_rejections: usize,
+    rejected: u64, // Samples rejected so far
+}
+
+impl AccelGate {
+    pub fn new(config: AccelGateConfig) -> Self {
+        Self {
+            config,
+            innovation_rejections: 0,
+            rejected: 0,
+        }
+    }
+
+    /// Check a specific force sample (m/s^2, body axes) against the filter's current orientation,
+    /// None if it hasn't initialized
+    pub fn check(&mut self, accel: [f64; 3], orientation: Option<Quaternion>) -> AccelCheck {
+        let check = self.classify(accel, orientation);
+        if check.is_rejected() {
+            self.rejected += 1;
+        }
+        check
+    }
+
+    /// Samples rejected so far
+    pub fn rejected(&self) -> u64 {
+        self.rejected
+    }
+
+    fn classify(&mut self, accel: [f64; 3], orientation: Option<Quaternion>) -> AccelCheck {
+        let norm = accel.iter().map(|a| a * a).sum::<f64>().sqrt();
+        let deviation = (norm - GRAVITY).abs();
+        if !deviation.is_finite() || deviation > self.config.reject_deviation {
+            return AccelCheck::RejectedMagnitude(deviation);
+        }
+
+        if let Some(q) = orientation {
+            let up = rotation::rotate_vector(rotation::conjugate(q), [0.0, 0.0, 1.0]);
+            let cos_angle = (up[0] * accel[0] + up[1] * accel[1] + up[2] * accel[2]) / norm;
+            let innovation = cos_angle.clamp(-1.0, 1.0).acos();
+            if innovation > self.config.max_innovation {
+                self.innovation_rejections += 1;
+                if self.innovation_rejections < MAX_INNOVATION_REJECTIONS {
+                    return AccelCheck::RejectedInnovation(innovation);
+                }
+                // Near 1 g yet consistently off for a while, so it's the estimate that has drifted
+            }
+            self.innovation_rejections = 0;
+        }
+
+        if deviation <= self.config.inflate_deviation {
+            return AccelCheck::Accepted;
+        }
+        let fraction = (deviation - self.config.inflate_deviation)
+            / (self.config.reject_deviation - self.config.inflate_deviation).max(f64::EPSILON);
+        AccelCheck::Inflated(1.0 + (self.config.max_noise_scale - 1.0).max(0.0) * fraction)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TOLERANCE: f64 = 1e-9;
+
+    fn gate() -> AccelGate {
+        AccelGate::new(AccelGateConfig {
+            inflate_deviation: 0.5,
+            reject_deviation: 2.0,
+            max_noise_scale: 10.0,
+            max_innovation: 0.2,
+        })
+    }
+
+    /// Specific force along body Z with this magnitude, m/s^2
+    fn level(magnitude: f64) -> [f64; 3] {
+        [0.0, 0.0, magnitude]
+    }
+
+    fn noise_scale(check: AccelCheck) -> f64 {
+        match check {
+            AccelCheck::Accepted => 1.0,
+            AccelCheck::Inflated(scale) => scale,
+            other => panic!("{:?}", other),
+        }
+    }
+
+    #[test]
+    fn noise_is_inflated_linearly_between_the_thresholds() {
+        let mut gate = gate();
+        assert_eq!(gate.check(level(GRAVITY), None), AccelCheck::Accepted);
+        assert_eq!(gate.check(level(GRAVITY + 0.5 - 1e-6), None), AccelCheck::Accepted);
+        assert!((noise_scale(gate.check(level(GRAVITY + 0.5 + 1e-6), None)) - 1.0).abs() < 1e-4);
+        assert!((noise_scale(gate.check(level(GRAVITY - 1.25), None)) - 5.5).abs() < TOLERANCE);
+        assert!((noise_scale(gate.check(level(GRAVITY + 2.0 - 1e-6), None)) - 10.0).abs() < 1e-4);
+        assert_eq!(gate.rejected(), 0);
+    }
+
+    #[test]
+    fn magnitude_beyond_the_reject_threshold_is_rejected() {
+        let mut gate = gate();
+        match gate.check(level(GRAVITY + 2.5), None) {
+            AccelCheck::RejectedMagnitude(deviation) => assert!((deviation - 2.5).abs() < TOLERANCE),
+            other => panic!("{:?}", other),
+        }
+        assert!(gate.check([f64::NAN, 0.0, GRAVITY], None).is_rejected());
+        assert_eq!(gate.rejected(), 2);
+    }
+
+    #[test]
+    fn persistent_innovation_rejections_fall_back_to_the_sample() {
+        let mut gate = gate();
+        let level_estimate = Some([1.0, 0.0, 0.0, 0.0]);
+        // 1 g, but tilted 0.5 rad about body X from where a level estimate puts gravity
+        let tilted = [0.0, GRAVITY * 0.5f64.sin(), GRAVITY * 0.5f64.cos()];
+
+        for _ in 1..MAX_INNOVATION_REJECTIONS {
+            match gate.check(tilted, level_estimate) {
+                AccelCheck::RejectedInnovation(angle) => assert!((angle - 0.5).abs() < TOLERANCE),
+                other => panic!("{:?}", other),
+            }
+        }
+        assert_eq!(gate.check(tilted, level_estimate), AccelCheck::Accepted);
+        assert_eq!(gate.rejected(), MAX_INNOVATION_REJECTIONS as u64 - 1);
+
+        // The count starts again after the fallback
+        assert!(gate.check(tilted, level_estimate).is_rejected());
+    }
+
+    #[test]
+    fn an_accepted_sample_restarts_the_innovation_count() {
+        let mut gate = gate();
+        let level_estimate = Some([1.0, 0.0, 0.0, 0.0]);
+        let tilted = [0.0, GRAVITY * 0.5f64.sin(), GRAVITY * 0.5f64.cos()];
+        for _ in 0..MAX_INNOVATION_REJECTIONS - 10 {
+            gate.check(tilted, level_estimate);
+        }
+        assert_eq!(gate.check(level(GRAVITY), level_estimate), AccelCheck::Accepted);
+        for _ in 0..20 {
+            assert!(gate.check(tilted, level_estimate).is_rejected());
+        }
+    }
+}
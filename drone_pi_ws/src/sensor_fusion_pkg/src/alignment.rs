@@ -0,0 +1,223 @@
+//! Startup alignment: the initial roll and pitch from an average of still accelerometer samples.
+//!
+//! A single accelerometer sample is a poor gravity reference on a vehicle with motors or people
+//! nearby, and a filter started from a vibration spike spends its first seconds converging from a
+//! wrong attitude. `Alignment` averages a window of samples before the filter starts, and only
+//! accepts the window if the vehicle sat still through it: the gyro stays below a rate and the
+//! accelerometer magnitude barely varies. A window with motion in it is thrown away and a new one
+//! started. If the vehicle is never still long enough, alignment gives up after a timeout and uses
+//! the average it has, so the estimator still starts.
+
+#[derive(Clone, Copy, Debug)]
+pub struct AlignmentConfig {
+    pub samples: usize,      // Still samples averaged
+    pub max_accel_std: f64,  // m/s^2, largest standard deviation of the accelerometer magnitude over the window
+    pub max_gyro_rate: f64,  // rad/s, largest rotation rate of any sample in the window
+    pub timeout: f64,        // s, after which the current average is used anyway, 0 to wait forever
+}
+
+/// Where alignment is after a sample
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum AlignmentStatus {
+    /// Still averaging
+    Collecting,
+    /// The window had motion in it and was restarted
+    Moving,
+    /// Mean specific force (m/s^2, body axes) over a still window
+    Aligned([f64; 3]),
+    /// Never still within the timeout, mean specific force of the samples since the last restart
+    TimedOut([f64; 3]),
+}
+
+pub struct Alignment {
+    config: AlignmentConfig,
+    accel_sum: [f64; 3],
+    norm_sum: f64,
+    norm_square_sum: f64,
+    count: usize,
+    elapsed: f64, // s since alignment started
+}
+
+impl Alignment {
+    pub fn new(config: AlignmentConfig) -> Self {
+        Self {
+            config: AlignmentConfig {
+                samples: config.samples.max(1),
+                ..config
+            },
+            accel_sum: [0.0; 3],
+            norm_sum: 0.0,
+            norm_square_sum: 0.0,
+            count: 0,
+            elapsed: 0.0,
+        }
+    }
+
+    /// Add an IMU sample taken `dt` seconds after the previous one
+    pub fn add(&mut self, accel: [f64; 3], gyro: [f64; 3], dt: f64) -> AlignmentStatus {
+        self.elapsed += dt;
+        let rate = (gyro[0] * gyro[0] + gyro[1] * gyro[1] + gyro[2] * gyro[2]).sqrt();
+        let norm = (accel[0] * accel[0] + accel[1] * accel[1] + accel[2] * accel[2]).sqrt();
+        if !norm.is_finite() || !rate.is_finite() {
+            return AlignmentStatus::Collecting;
+        }
+
+        let mut status = if rate > self.config.max_gyro_rate {
+            self.restart();
+            AlignmentStatus::Moving
+        } else {
+            for (sum, a) in self.accel_sum.iter_mut().zip(accel) {
+                *sum += a;
+            }
+            self.norm_sum += norm;
+            self.norm_square_sum += norm * norm;
+            self.count += 1;
+            AlignmentStatus::Collecting
+        };
+
+        if self.count >= self.config.samples {
+            let n = self.count as f64;
+            let mean_norm = self.norm_sum / n;
+            let norm_std = (self.norm_square_sum / n - mean_norm * mean_norm).max(0.0).sqrt();
+            if norm_std <= self.config.max_accel_std {
+                return AlignmentStatus::Aligned(self.mean());
+            }
+            status = AlignmentStatus::Moving;
+            if !self.timed_out() {
+                self.restart();
+            }
+        }
+
+        if self.timed_out() {
+            // Nothing averaged since a restart on this very sample, so it's all there is
+            let mean = if self.count > 0 { self.mean() } else { accel };
+            return AlignmentStatus::TimedOut(mean);
+        }
+        status
+    }
+
+    /// Still samples averaged so far in the current window
+    pub fn count(&self) -> usize {
+        self.count
+    }
+
+    fn timed_out(&self) -> bool {
+        self.config.timeout > 0.0 && self.elapsed >= self.config.timeout
+    }
+
+    fn mean(&self) -> [f64; 3] {
+        self.accel_sum.map(|sum| sum / self.count as f64)
+    }
+
+    fn restart(&mut self) {
+        self.accel_sum = [0.0; 3];
+        self.norm_sum = 0.0;
+        self.norm_square_sum = 0.0;
+        self.count = 0;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TOLERANCE: f64 = 1e-9;
+    const DT: f64 = 0.01;
+    const STILL_GYRO: [f64; 3] = [0.001, -0.002, 0.0];
+    const TURNING_GYRO: [f64; 3] = [0.0, 0.0, 0.5];
+
+    fn alignment(timeout: f64) -> Alignment {
+        Alignment::new(AlignmentConfig {
+            samples: 50,
+            max_accel_std: 0.05,
+            max_gyro_rate: 0.05,
+            timeout,
+        })
+    }
+
+    fn assert_vector(a: [f64; 3], b: [f64; 3]) {
+        for axis in 0..3 {
+            assert!((a[axis] - b[axis]).abs() < TOLERANCE, "{:?} != {:?}", a, b);
+        }
+    }
+
+    #[test]
+    fn still_window_aligns_on_the_mean() {
+        let mut alignment = alignment(0.0);
+        // Alternating small errors about a tilted gravity vector, cancelling over the window
+        let sample = |i: usize| {
+            let wobble = [0.01, -0.01][i % 2];
+            [1.0 + wobble, -2.0, 9.5 - wobble]
+        };
+        for i in 0..49 {
+            assert_eq!(alignment.add(sample(i), STILL_GYRO, DT), AlignmentStatus::Collecting);
+        }
+        match alignment.add(sample(49), STILL_GYRO, DT) {
+            AlignmentStatus::Aligned(mean) => assert_vector(mean, [1.0, -2.0, 9.5]),
+            other => panic!("{:?}", other),
+        }
+    }
+
+    #[test]
+    fn rotation_restarts_the_window() {
+        let mut alignment = alignment(0.0);
+        for _ in 0..30 {
+            alignment.add([0.0, 0.0, 9.8], STILL_GYRO, DT);
+        }
+        assert_eq!(alignment.count(), 30);
+        assert_eq!(alignment.add([0.0, 0.0, 9.8], TURNING_GYRO, DT), AlignmentStatus::Moving);
+        assert_eq!(alignment.count(), 0);
+
+        // A full still window after the motion aligns on it alone
+        for _ in 0..49 {
+            alignment.add([0.0, 3.0, 9.3], STILL_GYRO, DT);
+        }
+        match alignment.add([0.0, 3.0, 9.3], STILL_GYRO, DT) {
+            AlignmentStatus::Aligned(mean) => assert_vector(mean, [0.0, 3.0, 9.3]),
+            other => panic!("{:?}", other),
+        }
+    }
+
+    #[test]
+    fn vibrating_window_is_thrown_away() {
+        let mut alignment = alignment(0.0);
+        let mut status = AlignmentStatus::Collecting;
+        for i in 0..50 {
+            let magnitude = if i % 2 == 0 { 9.3 } else { 10.3 };
+            status = alignment.add([0.0, 0.0, magnitude], STILL_GYRO, DT);
+        }
+        assert_eq!(status, AlignmentStatus::Moving);
+        assert_eq!(alignment.count(), 0);
+    }
+
+    #[test]
+    fn times_out_on_the_samples_since_the_last_restart() {
+        let mut alignment = alignment(1.0);
+        for _ in 0..80 {
+            alignment.add([5.0, 0.0, 8.0], TURNING_GYRO, DT);
+        }
+        // 20 still samples, fewer than a window, before the timeout
+        let mut status = AlignmentStatus::Collecting;
+        for _ in 0..20 {
+            status = alignment.add([0.0, 1.0, 9.7], STILL_GYRO, DT);
+        }
+        match status {
+            AlignmentStatus::TimedOut(mean) => assert_vector(mean, [0.0, 1.0, 9.7]),
+            other => panic!("{:?}", other),
+        }
+    }
+
+    #[test]
+    fn times_out_on_the_last_sample_when_nothing_was_still() {
+        let mut alignment = alignment(1.0);
+        let mut status = AlignmentStatus::Collecting;
+        for _ in 0..100 {
+            status = alignment.add([5.0, 0.0, 8.0], TURNING_GYRO, DT);
+        }
+        assert_eq!(alignment.count(), 0);
+        match status {
+            AlignmentStatus::TimedOut(mean) => assert_vector(mean, [5.0, 0.0, 8.0]),
+            other => panic!("{:?}", other),
+        }
+    }
+}
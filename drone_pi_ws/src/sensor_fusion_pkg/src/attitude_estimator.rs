@@ -0,0 +1,712 @@
+//! Attitude estimator node.
+//!
+//! Subscribes to `/raw_imu` and `/raw_mag`, runs the attitude filter selected by the `estimator`
+//! parameter on every IMU sample, corrects its heading with the magnetometer, and publishes the
+//! orientation on `/quaternion_estimate` with the stamp of the IMU sample it was estimated from.
+//! Samples are queued between the subscription and the estimator thread so every one is integrated,
+//! in order, and the queue's health is published on `/diagnostics` once per second. Filters that
+//! estimate the gyro bias also publish it on `/gyro_bias_estimate`. Accelerometer samples that
+//! don't look like gravity are trusted less or skipped, flagged on `/accel_rejected`. A filter that
+//! has diverged is restarted from the current accelerometer and magnetometer samples, and the
+//! restarts are reported on `/diagnostics`. At startup the filter waits for the vehicle to sit still
+//! and starts from an average of the accelerometer, reporting that it's initializing until then.
+//! Alongside the quaternion, the same estimate is published as roll, pitch and yaw on
+//! `/euler_estimate` and the bias corrected body rate on `/angular_rate_estimate`.
+use attitude_pkg::estimator::AttitudeEstimator;
+use attitude_pkg::rotation;
+use rclrs::{create_node, Context, Node, RclrsError, Subscription, Publisher, QOS_PROFILE_DEFAULT};
+use imu_publisher_pkg::calibration::CalibrationFile;
+use sensor_fusion_pkg::accel_gate::{AccelCheck, AccelGate, AccelGateConfig};
+use sensor_fusion_pkg::alignment::{Alignment, AlignmentConfig, AlignmentStatus};
+use sensor_fusion_pkg::complementary::{ComplementaryConfig, MadgwickConfig, MahonyConfig};
+use sensor_fusion_pkg::eskf::EskfConfig;
+use sensor_fusion_pkg::estimate_output::estimate_output;
+use sensor_fusion_pkg::estimators::{create_estimator, EstimatorConfig};
+use sensor_fusion_pkg::filter_health::{FilterHealth, HealthConfig};
+use sensor_fusion_pkg::mag_yaw::{MagUpdate, MagYawConfig, MagYawCorrector};
+use sensor_fusion_pkg::sample_queue::SampleQueue;
+use sensor_fusion_pkg::sample_timing::{self, MaxDtAction, StampCheck, StampTracker};
+use sensor_msgs::msg::{Imu, MagneticField};
+use geometry_msgs::msg::{Quaternion, Twist, TwistStamped, Vector3, Vector3Stamped};
+use diagnostic_msgs::msg::{DiagnosticArray, DiagnosticStatus, KeyValue};
+use std_msgs::msg::Bool;
+use std::{
+    env,
+    path::Path,
+    sync::{Arc, Mutex, OnceLock},
+    thread::{self, Thread},
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+/// How often the queue, timing and filter health are published on /diagnostics
+const DIAGNOSTICS_PERIOD: Duration = Duration::from_secs(1);
+
+/// Counters as of the last diagnostics, to tell whether anything happened since
+#[derive(Clone, Copy, Debug, Default)]
+struct ReportedCounts {
+    dropped: u64,
+    resets: u64,
+}
+
+pub struct AttitudeEstimatorNode {
+    node: Arc<Node>,
+    _subscriber: Arc<Subscription<Imu>>,
+    _publisher: Arc<Publisher<Imu>>,
+    bias_publisher: Arc<Publisher<Vector3Stamped>>,
+    euler_publisher: Arc<Publisher<Vector3Stamped>>,
+    rate_publisher: Arc<Publisher<TwistStamped>>,
+    accel_rejected_publisher: Arc<Publisher<Bool>>,
+    _mag_subscriber: Arc<Subscription<MagneticField>>,
+    diagnostics_publisher: Arc<Publisher<DiagnosticArray>>,
+    queue: Arc<SampleQueue<Imu>>, // IMU samples not yet integrated, oldest first
+    worker: Arc<OnceLock<Thread>>, // Estimator thread, woken by the subscription when a sample is queued
+    mag_data: Arc<Mutex<Option<MagneticField>>>, // Latest magnetometer sample not yet used
+    estimator: Mutex<Box<dyn AttitudeEstimator>>,
+    alignment: Mutex<Option<Alignment>>, // Startup alignment, None once the filter has started
+    mag_yaw: Mutex<Option<MagYawCorrector>>, // Heading correction, None if disabled
+    accel_gate: Mutex<AccelGate>, // How far each accelerometer sample is trusted
+    health: Mutex<FilterHealth>, // Divergence checks and restart count
+    stamps: Mutex<StampTracker>, // Time steps from the /raw_imu header stamps
+    max_dt_action: MaxDtAction,
+    euler_degrees: bool, // Publish roll, pitch and yaw in degrees rather than radians
+}
+
+impl AttitudeEstimatorNode {
+    fn new(context: &Context, node_name: &str, default_estimator: &str) -> Result<Self, Box<dyn std::error::Error>> {
+        let node = create_node(context, node_name)?;
+
+        // Which filter to run, see sensor_fusion_pkg::estimators
+        let estimator_name = node
+            .declare_parameter("estimator")
+            .default(Arc::<str>::from(default_estimator))
+            .mandatory()?;
+        let config = EstimatorConfig {
+            eskf: eskf_config(&node)?,
+            complementary: complementary_config(&node)?,
+            mahony: mahony_config(&node)?,
+            madgwick: madgwick_config(&node)?,
+        };
+        let estimator = create_estimator(&estimator_name.get(), &config)?;
+        println!("Running the {} attitude estimator", estimator.name());
+
+        // Longest step between IMU samples the gyro is integrated over
+        let max_dt = node
+            .declare_parameter("timing.max_dt") // s
+            .default(0.05)
+            .mandatory()?;
+        let max_dt_action = node
+            .declare_parameter("timing.max_dt_action") // "coast" or "reset"
+            .default(Arc::<str>::from("coast"))
+            .mandatory()?;
+        let max_dt_action = MaxDtAction::from_name(&max_dt_action.get())?;
+
+        // Startup alignment
+        let init_samples = node
+            .declare_parameter("init.samples") // Still accelerometer samples averaged
+            .default(200_i64)
+            .mandatory()?;
+        let init_accel_std = node
+            .declare_parameter("init.max_accel_std") // m/s^2, accelerometer magnitude over the window
+            .default(0.2)
+            .mandatory()?;
+        let init_gyro_rate = node
+            .declare_parameter("init.max_gyro_rate") // rad/s
+            .default(0.1)
+            .mandatory()?;
+        let init_timeout = node
+            .declare_parameter("init.timeout") // s without stillness before aligning anyway, 0 to wait forever
+            .default(10.0)
+            .mandatory()?;
+        let alignment = Alignment::new(AlignmentConfig {
+            samples: init_samples.get().max(1) as usize,
+            max_accel_std: init_accel_std.get(),
+            max_gyro_rate: init_gyro_rate.get(),
+            timeout: init_timeout.get(),
+        });
+
+        // Accelerometer trust during manoeuvres
+        let inflate_deviation = node
+            .declare_parameter("accel_gate.inflate_deviation") // m/s^2 from 1 g
+            .default(0.5)
+            .mandatory()?;
+        let reject_deviation = node
+            .declare_parameter("accel_gate.reject_deviation") // m/s^2 from 1 g
+            .default(3.0)
+            .mandatory()?;
+        let max_noise_scale = node
+            .declare_parameter("accel_gate.max_noise_scale") // Noise multiplier at the rejection threshold
+            .default(10.0)
+            .mandatory()?;
+        let max_innovation = node
+            .declare_parameter("accel_gate.max_innovation_deg") // Between measured and estimated gravity
+            .default(15.0)
+            .mandatory()?;
+        let accel_gate = AccelGate::new(AccelGateConfig {
+            inflate_deviation: inflate_deviation.get(),
+            reject_deviation: reject_deviation.get(),
+            max_noise_scale: max_noise_scale.get(),
+            max_innovation: max_innovation.get().to_radians(),
+        });
+
+        // Divergence limits beyond which the filter is restarted
+        let max_norm_error = node
+            .declare_parameter("health.max_norm_error") // | |q| - 1 |
+            .default(0.01)
+            .mandatory()?;
+        let max_tilt_std = node
+            .declare_parameter("health.max_tilt_std_deg") // Roll or pitch standard deviation
+            .default(30.0)
+            .mandatory()?;
+        let max_nis = node
+            .declare_parameter("health.max_nis") // Average NIS of the accelerometer updates, about 2 when consistent
+            .default(20.0)
+            .mandatory()?;
+        let health = FilterHealth::new(HealthConfig {
+            max_norm_error: max_norm_error.get(),
+            max_tilt_std: max_tilt_std.get().to_radians(),
+            max_nis: max_nis.get(),
+        });
+
+        // Samples held for the estimator thread before the oldest are dropped
+        let queue_capacity = node
+            .declare_parameter("queue.capacity")
+            .default(64_i64)
+            .mandatory()?;
+        let queue = Arc::new(SampleQueue::new(queue_capacity.get().max(1) as usize));
+        let queue_mut = Arc::clone(&queue);
+        let worker: Arc<OnceLock<Thread>> = Arc::new(OnceLock::new());
+        let worker_wake = Arc::clone(&worker);
+
+        let _subscriber = node.create_subscription::<Imu, _>(
+            "/raw_imu", // Subscribes to raw IMU data
+            QOS_PROFILE_DEFAULT,
+            move |msg: Imu| {
+                // Queue the message, never blocking on the estimator
+                queue_mut.push(msg);
+
+                // Wake the estimator thread
+                if let Some(worker) = worker_wake.get() {
+                    worker.unpark();
+                }
+            },
+        )?;
+
+        let mag_data: Arc<Mutex<Option<MagneticField>>> = Arc::new(Mutex::new(None));
+        let mag_data_mut = Arc::clone(&mag_data);
+
+        let _mag_subscriber = node.create_subscription::<MagneticField, _>(
+            "/raw_mag", // Subscribes to magnetometer data for heading
+            QOS_PROFILE_DEFAULT,
+            move |msg: MagneticField| {
+                *mag_data_mut.lock().unwrap() = Some(msg);
+            },
+        )?;
+
+        let _publisher = node.create_publisher::<Imu>(
+            "/quaternion_estimate", // Publishes quaternion estimates
+            QOS_PROFILE_DEFAULT,
+        )?;
+        let bias_publisher = node.create_publisher::<Vector3Stamped>(
+            "/gyro_bias_estimate", // Publishes the filter's gyro bias, rad/s
+            QOS_PROFILE_DEFAULT,
+        )?;
+        let euler_publisher = node.create_publisher::<Vector3Stamped>(
+            "/euler_estimate", // Publishes roll, pitch and yaw (ZYX) as x, y and z
+            QOS_PROFILE_DEFAULT,
+        )?;
+        let rate_publisher = node.create_publisher::<TwistStamped>(
+            "/angular_rate_estimate", // Publishes the bias corrected body rate, rad/s
+            QOS_PROFILE_DEFAULT,
+        )?;
+        let euler_degrees = node
+            .declare_parameter("euler.degrees") // Units of /euler_estimate, radians if false
+            .default(false)
+            .mandatory()?;
+        let accel_rejected_publisher = node.create_publisher::<Bool>(
+            "/accel_rejected", // Publishes whether each IMU sample's accelerometer update was skipped
+            QOS_PROFILE_DEFAULT,
+        )?;
+        let diagnostics_publisher = node
+            .create_publisher::<DiagnosticArray>("/diagnostics", QOS_PROFILE_DEFAULT)?;
+
+        // Magnetometer heading correction parameters
+        let mag_enabled = node.declare_parameter("mag.enabled").default(true).mandatory()?;
+        let declination = node
+            .declare_parameter("mag.declination_deg") // Local magnetic declination, east positive
+            .default(0.0)
+            .mandatory()?;
+        let expected_field = node
+            .declare_parameter("mag.expected_field_ut") // 0 to learn it at startup
+            .default(0.0)
+            .mandatory()?;
+        let field_tolerance = node
+            .declare_parameter("mag.field_tolerance") // Fraction of the expected field strength
+            .default(0.15)
+            .mandatory()?;
+        let dip_tolerance = node
+            .declare_parameter("mag.dip_tolerance_deg")
+            .default(10.0)
+            .mandatory()?;
+        let heading_noise = node
+            .declare_parameter("mag.heading_noise_deg")
+            .default(5.0)
+            .mandatory()?;
+        let yaw_drift = node
+            .declare_parameter("mag.yaw_drift_deg") // Heading drift after one second without the magnetometer
+            .default(0.5)
+            .mandatory()?;
+        let innovation_gate = node
+            .declare_parameter("mag.innovation_gate") // Sigmas
+            .default(3.0)
+            .mandatory()?;
+
+        let mag_yaw = if mag_enabled.get() {
+            Some(MagYawCorrector::new(MagYawConfig {
+                declination: declination.get().to_radians(),
+                expected_field: Some(expected_field.get()).filter(|field| *field > 0.0),
+                field_tolerance: field_tolerance.get(),
+                dip_tolerance: dip_tolerance.get().to_radians(),
+                heading_noise: heading_noise.get().to_radians(),
+                yaw_drift: yaw_drift.get().to_radians(),
+                innovation_gate: innovation_gate.get(),
+            }))
+        } else {
+            None
+        };
+
+        Ok(Self {
+            node,
+            _subscriber,
+            _publisher,
+            bias_publisher,
+            euler_publisher,
+            rate_publisher,
+            accel_rejected_publisher,
+            _mag_subscriber,
+            diagnostics_publisher,
+            queue,
+            worker,
+            mag_data,
+            estimator: Mutex::new(estimator),
+            alignment: Mutex::new(Some(alignment)),
+            mag_yaw: Mutex::new(mag_yaw),
+            accel_gate: Mutex::new(accel_gate),
+            health: Mutex::new(health),
+            stamps: Mutex::new(StampTracker::new(max_dt.get())),
+            max_dt_action,
+            euler_degrees: euler_degrees.get(),
+        })
+    }
+
+    /// Integrate the queued IMU samples, oldest first
+    fn process_queue(&self) {
+        while let Some(data) = self.queue.pop() {
+            if let Err(e) = self.process_sample(data) {
+                eprintln!("Error processing IMU sample: {:?}", e);
+            }
+        }
+    }
+
+    /// Run the estimator on one IMU sample and publish the orientation
+    fn process_sample(&self, data: Imu) -> Result<(), RclrsError> {
+        let gyro_data = [data.angular_velocity.x, data.angular_velocity.y, data.angular_velocity.z];
+        let accel_data = [data.linear_acceleration.x, data.linear_acceleration.y, data.linear_acceleration.z];
+
+        // Step from the previous sample's stamp, so callback jitter doesn't reach the integration.
+        // The prediction is skipped when there's nothing sensible to integrate over. Gaps, duplicates
+        // and out of order stamps can come every sample, so they're only counted, on diagnostics
+        let mut estimator = self.estimator.lock().unwrap();
+        let stamp = sample_timing::stamp_nanos(data.header.stamp.sec, data.header.stamp.nanosec);
+        let check = self.stamps.lock().unwrap().check(stamp);
+        let (mut predict_dt, elapsed) = match check {
+            StampCheck::First => (None, 0.0),
+            StampCheck::Step(dt) => (Some(dt), dt),
+            StampCheck::Gap { dt, .. } => (Some(dt), dt),
+            StampCheck::Exceeded(dt) => {
+                match self.max_dt_action {
+                    MaxDtAction::Coast => eprintln!("No IMU data for {:.1} ms, coasting", dt * 1e3),
+                    MaxDtAction::Reset => {
+                        eprintln!("No IMU data for {:.1} ms, resetting the {} estimator", dt * 1e3, estimator.name());
+                        estimator.reset();
+                        self.health.lock().unwrap().restart();
+                        if let Some(mag_yaw) = self.mag_yaw.lock().unwrap().as_mut() {
+                            mag_yaw.reset_heading();
+                        }
+                    }
+                }
+                (None, dt)
+            }
+            StampCheck::Duplicate | StampCheck::OutOfOrder(_) => return Ok(()),
+        };
+
+        // Hold the filter back until the vehicle has sat still long enough to average gravity, then
+        // start it from the average and carry on from this sample
+        let mut alignment = self.alignment.lock().unwrap();
+        if let Some(aligner) = alignment.as_mut() {
+            match aligner.add(accel_data, gyro_data, elapsed) {
+                AlignmentStatus::Collecting | AlignmentStatus::Moving => return Ok(()),
+                AlignmentStatus::Aligned(accel) => {
+                    println!("Aligned from {} still accelerometer samples", aligner.count());
+                    estimator.reset();
+                    estimator.update(accel);
+                }
+                AlignmentStatus::TimedOut(accel) => {
+                    eprintln!("The vehicle wasn't still at startup, aligning from {} samples anyway", aligner.count().max(1));
+                    estimator.reset();
+                    estimator.update(accel);
+                }
+            }
+            *alignment = None;
+            predict_dt = None;
+        }
+        drop(alignment);
+
+        if let Some(dt) = predict_dt {
+            estimator.predict(gyro_data, dt);
+        }
+        // Only trust the accelerometer as far as it looks like gravity alone
+        let accel_check = self.accel_gate.lock().unwrap().check(accel_data, estimator.orientation());
+        match accel_check {
+            AccelCheck::Accepted => estimator.update(accel_data),
+            AccelCheck::Inflated(noise_scale) => estimator.update_scaled(accel_data, noise_scale),
+            AccelCheck::RejectedMagnitude(_) | AccelCheck::RejectedInnovation(_) => {}
+        }
+        self.accel_rejected_publisher.publish(&Bool {
+            data: accel_check.is_rejected(),
+        })?;
+
+        // Restart a diverged filter from this sample, unless it was rejected, and the heading from
+        // the next magnetometer sample
+        let divergence = self.health.lock().unwrap().check(estimator.as_ref());
+        if let Some(divergence) = divergence {
+            eprintln!("The {} estimator diverged ({}), reinitializing", estimator.name(), divergence);
+            estimator.reset();
+            if !accel_check.is_rejected() {
+                estimator.update(accel_data);
+            }
+            self.health.lock().unwrap().record_reset(divergence);
+            if let Some(mag_yaw) = self.mag_yaw.lock().unwrap().as_mut() {
+                mag_yaw.reset_heading();
+            }
+        }
+
+        // Correct the unobservable yaw with the magnetometer, in the filter if it takes it
+        let mut mag_yaw = self.mag_yaw.lock().unwrap();
+        if let Some(mag_yaw) = mag_yaw.as_mut() {
+            mag_yaw.predict(elapsed);
+            if let Some(mag) = self.mag_data.lock().unwrap().take() {
+                let field = [
+                    mag.magnetic_field.x * 1e6, // Tesla to micro tesla
+                    mag.magnetic_field.y * 1e6,
+                    mag.magnetic_field.z * 1e6,
+                ];
+                if let MagUpdate::Initialized = mag_yaw.update(estimator.as_mut(), field) {
+                    if let Some(q) = estimator.orientation() {
+                        let yaw = rotation::quaternion_to_euler(mag_yaw.correct(q))[2];
+                        println!("Heading initialized from magnetometer, yaw {:.1} deg", yaw.to_degrees());
+                    }
+                }
+            }
+        }
+
+        let Some(mut q) = estimator.orientation() else {
+            return Ok(()); // Still initializing
+        };
+
+        // Filters without a magnetometer update have their heading corrected from outside, and once
+        // the magnetometer holds it, its uncertainty is the yaw uncertainty
+        let mut mag_yaw_variance = None;
+        if let Some(mag_yaw) = mag_yaw.as_ref() {
+            q = mag_yaw.correct(q);
+            mag_yaw_variance = mag_yaw.variance();
+        }
+        drop(mag_yaw);
+
+        let bias = estimator.gyro_bias();
+        let output = estimate_output(
+            q,
+            estimator.euler_covariance(),
+            mag_yaw_variance,
+            gyro_data,
+            bias,
+            self.euler_degrees,
+        );
+        let rate = Vector3 {
+            x: output.rate[0],
+            y: output.rate[1],
+            z: output.rate[2],
+        };
+
+        // All stamped with the IMU sample's time, so consumers can line the estimate up with the data
+        let imu_msg = Imu {
+            header: data.header,
+            orientation: Quaternion {
+                w: q[0],
+                x: q[1],
+                y: q[2],
+                z: q[3],
+            },
+            orientation_covariance: output.orientation_covariance,
+            angular_velocity: rate.clone(),
+            // The measurement noise, as published with the raw sample
+            angular_velocity_covariance: data.angular_velocity_covariance,
+            linear_acceleration: data.linear_acceleration,
+            linear_acceleration_covariance: data.linear_acceleration_covariance,
+        };
+        self._publisher.publish(&imu_msg)?;
+
+        let euler_msg = Vector3Stamped {
+            header: imu_msg.header.clone(),
+            vector: Vector3 {
+                x: output.euler[0],
+                y: output.euler[1],
+                z: output.euler[2],
+            },
+        };
+        self.euler_publisher.publish(&euler_msg)?;
+
+        let rate_msg = TwistStamped {
+            header: imu_msg.header.clone(),
+            twist: Twist {
+                linear: Vector3 { x: 0.0, y: 0.0, z: 0.0 },
+                angular: rate,
+            },
+        };
+        self.rate_publisher.publish(&rate_msg)?;
+
+        if let Some(bias) = bias {
+            let bias_msg = Vector3Stamped {
+                header: imu_msg.header,
+                vector: Vector3 {
+                    x: bias[0],
+                    y: bias[1],
+                    z: bias[2],
+                },
+            };
+            self.bias_publisher.publish(&bias_msg)?;
+        }
+        Ok(())
+    }
+
+    /// Publish the input and filter health, warning about samples dropped or filter restarts since
+    /// the counts in `reported`, which are then brought up to date
+    fn publish_diagnostics(&self, reported: &mut ReportedCounts) -> Result<(), RclrsError> {
+        let queue = self.queue.stats();
+        // Locked in the same order as when processing a sample
+        let estimator_name = self.estimator.lock().unwrap().name();
+        let initializing = self.alignment.lock().unwrap().is_some();
+        let health = self.health.lock().unwrap();
+        let status = vec![
+            self.input_status(queue.dropped - reported.dropped),
+            filter_status(estimator_name, initializing, &health, health.resets() - reported.resets),
+        ];
+        reported.dropped = queue.dropped;
+        reported.resets = health.resets();
+        drop(health);
+
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap();
+        let diagnostics = DiagnosticArray {
+            header: std_msgs::msg::Header {
+                stamp: builtin_interfaces::msg::Time {
+                    sec: now.as_secs() as i32,
+                    nanosec: now.subsec_nanos(),
+                },
+                frame_id: String::new(),
+            },
+            status,
+        };
+        self.diagnostics_publisher.publish(&diagnostics)
+    }
+
+    /// Queue and stamp counters as a diagnostic status, a warning if samples were dropped
+    fn input_status(&self, new_drops: u64) -> DiagnosticStatus {
+        let queue = self.queue.stats();
+        let stamps = self.stamps.lock().unwrap().stats();
+        let accel_rejected = self.accel_gate.lock().unwrap().rejected();
+
+        let (level, message) = if new_drops > 0 {
+            (
+                DiagnosticStatus::WARN,
+                format!("{} samples dropped, the estimator is falling behind", new_drops),
+            )
+        } else {
+            (DiagnosticStatus::OK, "OK".to_string())
+        };
+        DiagnosticStatus {
+            level,
+            name: "attitude_estimator: input".to_string(),
+            message,
+            hardware_id: "imu_link".to_string(),
+            values: vec![
+                key_value("queue_capacity", queue.capacity),
+                key_value("queued", queue.queued),
+                key_value("queue_high_water", queue.high_water),
+                key_value("queue_dropped", queue.dropped),
+                key_value("stamp_gaps", stamps.gaps),
+                key_value("samples_missed", stamps.missed),
+                key_value("max_dt_exceeded", stamps.exceeded),
+                key_value("duplicate_stamps", stamps.duplicates),
+                key_value("out_of_order_stamps", stamps.out_of_order),
+                key_value("accel_rejected", accel_rejected),
+            ],
+        }
+    }
+
+}
+
+/// Filter health as a diagnostic status: a warning while it's initializing, as nothing is published,
+/// and an error if it was restarted since the last one
+fn filter_status(estimator_name: &str, initializing: bool, health: &FilterHealth, new_resets: u64) -> DiagnosticStatus {
+    let last_divergence = health
+        .last_divergence()
+        .map_or_else(|| "none".to_string(), |divergence| divergence.to_string());
+
+    let (level, message) = if initializing {
+        (
+            DiagnosticStatus::WARN,
+            "Initializing, waiting for the vehicle to be still".to_string(),
+        )
+    } else if new_resets > 0 {
+        (
+            DiagnosticStatus::ERROR,
+            format!("Diverged and reinitialized {} times: {}", new_resets, last_divergence),
+        )
+    } else {
+        (DiagnosticStatus::OK, "OK".to_string())
+    };
+    DiagnosticStatus {
+        level,
+        name: "attitude_estimator: filter".to_string(),
+        message,
+        hardware_id: "imu_link".to_string(),
+        values: vec![
+            key_value("estimator", estimator_name),
+            key_value("state", if initializing { "initializing" } else { "running" }),
+            key_value("resets", health.resets()),
+            key_value("last_divergence", last_divergence),
+            key_value("average_nis", health.average_nis().map_or("n/a".to_string(), |nis| format!("{:.2}", nis))),
+        ],
+    }
+}
+
+fn key_value(key: &str, value: impl ToString) -> KeyValue {
+    KeyValue {
+        key: key.to_string(),
+        value: value.to_string(),
+    }
+}
+
+/// Settings for the `eskf` filter, with the starting bias from a calibration file if one is given
+fn eskf_config(node: &Node) -> Result<EskfConfig, Box<dyn std::error::Error>> {
+    let defaults = EskfConfig::default();
+    let gyro_noise = node
+        .declare_parameter("eskf.gyro_noise") // rad/s/sqrt(Hz)
+        .default(defaults.gyro_noise)
+        .mandatory()?;
+    let bias_random_walk = node
+        .declare_parameter("eskf.bias_random_walk") // rad/s^2/sqrt(Hz)
+        .default(defaults.bias_random_walk)
+        .mandatory()?;
+    let accel_noise = node
+        .declare_parameter("eskf.accel_noise") // m/s^2
+        .default(defaults.accel_noise)
+        .mandatory()?;
+    let initial_bias_std = node
+        .declare_parameter("eskf.initial_bias_std") // rad/s
+        .default(defaults.initial_bias_std)
+        .mandatory()?;
+    // Only for /raw_imu without the bias removed. The publisher normally removes it, and the filter
+    // then estimates what's left starting from zero
+    let calibration_file = node
+        .declare_parameter("eskf.calibration_file")
+        .default(Arc::<str>::from(""))
+        .mandatory()?;
+
+    let calibration_file = calibration_file.get();
+    let initial_bias = if calibration_file.is_empty() {
+        defaults.initial_bias
+    } else {
+        let calibration = CalibrationFile::load(Path::new(&*calibration_file))?
+            .ok_or_else(|| format!("Calibration file {} doesn't exist", calibration_file))?;
+        let bias = calibration.gyro_bias(None).map(f64::from);
+        println!("ESKF gyro bias seeded from {}: {:?} rad/s", calibration_file, bias);
+        bias
+    };
+
+    Ok(EskfConfig {
+        gyro_noise: gyro_noise.get(),
+        bias_random_walk: bias_random_walk.get(),
+        accel_noise: accel_noise.get(),
+        initial_bias,
+        initial_bias_std: initial_bias_std.get(),
+    })
+}
+
+/// Settings for the `complementary` filter
+fn complementary_config(node: &Node) -> Result<ComplementaryConfig, Box<dyn std::error::Error>> {
+    let time_constant = node
+        .declare_parameter("complementary.time_constant") // s
+        .default(ComplementaryConfig::default().time_constant)
+        .mandatory()?;
+    Ok(ComplementaryConfig {
+        time_constant: time_constant.get(),
+    })
+}
+
+/// Settings for the `mahony` filter
+fn mahony_config(node: &Node) -> Result<MahonyConfig, Box<dyn std::error::Error>> {
+    let defaults = MahonyConfig::default();
+    let kp = node.declare_parameter("mahony.kp").default(defaults.kp).mandatory()?;
+    let ki = node.declare_parameter("mahony.ki").default(defaults.ki).mandatory()?;
+    Ok(MahonyConfig {
+        kp: kp.get(),
+        ki: ki.get(),
+    })
+}
+
+/// Settings for the `madgwick` filter
+fn madgwick_config(node: &Node) -> Result<MadgwickConfig, Box<dyn std::error::Error>> {
+    let beta = node
+        .declare_parameter("madgwick.beta") // rad/s
+        .default(MadgwickConfig::default().beta)
+        .mandatory()?;
+    Ok(MadgwickConfig { beta: beta.get() })
+}
+
+fn main() -> Result<(), Box<dyn std::error::Error>> {
+    run("attitude_estimator", "ekf")
+}
+
+/// Run the node as `node_name`, with the `estimator` parameter defaulting to `default_estimator`.
+/// Also used by the executables kept under the node's old names.
+pub fn run(node_name: &str, default_estimator: &str) -> Result<(), Box<dyn std::error::Error>> {
+    let context = Context::new(env::args())?;
+
+    let estimator_node = Arc::new(AttitudeEstimatorNode::new(&context, node_name, default_estimator)?);
+
+    // Spawn a thread to run the estimator on every queued sample
+    let estimator_node_thread = Arc::clone(&estimator_node);
+    thread::spawn(move || {
+        let _ = estimator_node_thread.worker.set(thread::current());
+        let mut last_report = Instant::now();
+        let mut reported = ReportedCounts::default();
+        loop {
+            estimator_node_thread.process_queue();
+
+            if last_report.elapsed() >= DIAGNOSTICS_PERIOD {
+                last_report = Instant::now();
+                if let Err(e) = estimator_node_thread.publish_diagnostics(&mut reported) {
+                    eprintln!("Error publishing estimator diagnostics: {:?}", e);
+                }
+            }
+
+            // Sleep until the subscription queues a sample. An unpark while busy isn't lost, it
+            // makes this return straight away, and the timeout keeps the diagnostics going without data
+            thread::park_timeout(DIAGNOSTICS_PERIOD);
+        }
+    });
+
+    // Spin the node
+    rclrs::spin(estimator_node.node.clone())?;
+    Ok(())
+}
@@ -0,0 +1,329 @@
+//! Lightweight complementary filters: a plain complementary filter, Mahony and Madgwick.
+//!
+//! All three integrate the gyro and pull the estimate a little towards the accelerometer's gravity
+//! direction on every sample, so they are cheap, have nothing to diverge, and make a baseline to
+//! compare the Kalman filters against. They differ in how the pull is made: the complementary
+//! filter rotates a fixed fraction of the way, Mahony feeds the error back through a PI controller on
+//! the body rate (its integral term ends up estimating the gyro bias), and Madgwick takes a gradient
+//! descent step on the gravity error. Like the Kalman filters, they start from the first
+//! accelerometer sample and each correction is applied over the last prediction's time step.
+
+use attitude_pkg::estimator::AttitudeEstimator;
+use attitude_pkg::rotation::{self, Quaternion};
+
+#[derive(Clone, Debug)]
+pub struct ComplementaryConfig {
+    pub time_constant: f64, // s, how long the accelerometer takes to pull a tilt error most of the way out
+}
+
+impl Default for ComplementaryConfig {
+    fn default() -> Self {
+        Self { time_constant: 1.0 }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct MahonyConfig {
+    pub kp: f64, // rad/s of correction per unit of gravity direction error
+    pub ki: f64, // Integral gain, 0 to not estimate the gyro bias
+}
+
+impl Default for MahonyConfig {
+    fn default() -> Self {
+        Self { kp: 1.0, ki: 0.02 }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct MadgwickConfig {
+    pub beta: f64, // Gradient step, rad/s
+}
+
+impl Default for MadgwickConfig {
+    fn default() -> Self {
+        Self { beta: 0.1 }
+    }
+}
+
+/// Gyro integration with each accelerometer sample rotating the estimate a fraction of the way to it
+pub struct ComplementaryFilter {
+    config: ComplementaryConfig,
+    q: Option<Quaternion>, // Body to world, None until the first accelerometer sample
+    dt: f64,               // s, time step of the last prediction
+}
+
+impl ComplementaryFilter {
+    pub fn new(config: ComplementaryConfig) -> Self {
+        Self { config, q: None, dt: 0.0 }
+    }
+}
+
+impl AttitudeEstimator for ComplementaryFilter {
+    fn name(&self) -> &'static str {
+        "complementary"
+    }
+
+    fn predict(&mut self, gyro: [f64; 3], dt: f64) {
+        self.q = self.q.map(|q| integrate(q, gyro, dt));
+        self.dt = dt;
+    }
+
+    fn update(&mut self, accel: [f64; 3]) {
+        self.update_scaled(accel, 1.0);
+    }
+
+    fn update_scaled(&mut self, accel: [f64; 3], noise_scale: f64) {
+        let Some(q) = self.q else {
+            self.q = Some(rotation::gravity_to_quaternion(accel));
+            return;
+        };
+        let Some(error) = gravity_error(q, accel) else {
+            return;
+        };
+
+        // Rotate the fraction a first order low pass with this time constant moves in one step. The
+        // error's size is the sine of the angle, close enough to the angle for the small ones seen here
+        let time_constant = self.config.time_constant * noise_scale;
+        let fraction = self.dt / (time_constant + self.dt);
+        self.q = Some(rotate(q, error.map(|e| e * fraction)));
+    }
+
+    fn orientation(&self) -> Option<Quaternion> {
+        self.q
+    }
+
+    fn reset(&mut self) {
+        self.q = None;
+    }
+}
+
+/// Mahony's explicit complementary filter: proportional and integral feedback of the gravity error
+pub struct MahonyFilter {
+    config: MahonyConfig,
+    q: Option<Quaternion>, // Body to world, None until the first accelerometer sample
+    bias: [f64; 3],        // rad/s, the integral term, subtracted from the gyro
+    dt: f64,               // s, time step of the last prediction
+}
+
+impl MahonyFilter {
+    pub fn new(config: MahonyConfig) -> Self {
+        Self {
+            config,
+            q: None,
+            bias: [0.0; 3],
+            dt: 0.0,
+        }
+    }
+}
+
+impl AttitudeEstimator for MahonyFilter {
+    fn name(&self) -> &'static str {
+        "mahony"
+    }
+
+    fn predict(&mut self, gyro: [f64; 3], dt: f64) {
+        let rate = [0, 1, 2].map(|axis| gyro[axis] - self.bias[axis]);
+        self.q = self.q.map(|q| integrate(q, rate, dt));
+        self.dt = dt;
+    }
+
+    fn update(&mut self, accel: [f64; 3]) {
+        self.update_scaled(accel, 1.0);
+    }
+
+    fn update_scaled(&mut self, accel: [f64; 3], noise_scale: f64) {
+        let Some(q) = self.q else {
+            self.q = Some(rotation::gravity_to_quaternion(accel));
+            return;
+        };
+        let Some(error) = gravity_error(q, accel) else {
+            return;
+        };
+
+        // A less trusted sample gets proportionally less feedback
+        let kp = self.config.kp / noise_scale;
+        let ki = self.config.ki / noise_scale;
+        for (bias, e) in self.bias.iter_mut().zip(error) {
+            *bias -= ki * e * self.dt;
+        }
+        self.q = Some(rotate(q, error.map(|e| kp * e * self.dt)));
+    }
+
+    fn orientation(&self) -> Option<Quaternion> {
+        self.q
+    }
+
+    fn gyro_bias(&self) -> Option<[f64; 3]> {
+        Some(self.bias)
+    }
+
+    fn reset(&mut self) {
+        self.q = None;
+        self.bias = [0.0; 3];
+    }
+}
+
+/// Madgwick's filter: a normalized gradient descent step on the gravity error after each gyro step
+pub struct MadgwickFilter {
+    config: MadgwickConfig,
+    q: Option<Quaternion>, // Body to world, None until the first accelerometer sample
+    dt: f64,               // s, time step of the last prediction
+}
+
+impl MadgwickFilter {
+    pub fn new(config: MadgwickConfig) -> Self {
+        Self { config, q: None, dt: 0.0 }
+    }
+}
+
+impl AttitudeEstimator for MadgwickFilter {
+    fn name(&self) -> &'static str {
+        "madgwick"
+    }
+
+    fn predict(&mut self, gyro: [f64; 3], dt: f64) {
+        self.q = self.q.map(|q| integrate(q, gyro, dt));
+        self.dt = dt;
+    }
+
+    fn update(&mut self, accel: [f64; 3]) {
+        self.update_scaled(accel, 1.0);
+    }
+
+    fn update_scaled(&mut self, accel: [f64; 3], noise_scale: f64) {
+        let Some(q) = self.q else {
+            self.q = Some(rotation::gravity_to_quaternion(accel));
+            return;
+        };
+        let norm = (accel[0] * accel[0] + accel[1] * accel[1] + accel[2] * accel[2]).sqrt();
+        if norm < f64::EPSILON {
+            return;
+        }
+        let [ax, ay, az] = accel.map(|a| a / norm);
+        let [w, x, y, z] = q;
+
+        // Error between gravity in body axes under q and the measured direction, and its gradient
+        // over the quaternion's components (J^T f)
+        let f = [
+            2.0 * (x * z - w * y) - ax,
+            2.0 * (w * x + y * z) - ay,
+            1.0 - 2.0 * (x * x + y * y) - az,
+        ];
+        let gradient = [
+            -2.0 * y * f[0] + 2.0 * x * f[1],
+            2.0 * z * f[0] + 2.0 * w * f[1] - 4.0 * x * f[2],
+            -2.0 * w * f[0] + 2.0 * z * f[1] - 4.0 * y * f[2],
+            2.0 * x * f[0] + 2.0 * y * f[1],
+        ];
+        let gradient_norm = gradient.iter().map(|g| g * g).sum::<f64>().sqrt();
+        if gradient_norm < f64::EPSILON {
+            return;
+        }
+
+        let step = self.config.beta / noise_scale * self.dt / gradient_norm;
+        self.q = Some(rotation::normalize([0, 1, 2, 3].map(|i| q[i] - step * gradient[i])));
+    }
+
+    fn orientation(&self) -> Option<Quaternion> {
+        self.q
+    }
+
+    fn reset(&mut self) {
+        self.q = None;
+    }
+}
+
+/// Step a body to world quaternion by dt seconds of body rate
+fn integrate(q: Quaternion, rate: [f64; 3], dt: f64) -> Quaternion {
+    rotate(q, rate.map(|r| r * dt))
+}
+
+/// Apply a rotation vector in body axes
+fn rotate(q: Quaternion, angle: [f64; 3]) -> Quaternion {
+    rotation::normalize(rotation::multiply(q, rotation::rotation_vector_to_quaternion(angle)))
+}
+
+/// Body axes rotation that turns the estimated gravity direction towards the measured one, its
+/// length the sine of the angle between them. None for a zero sample.
+fn gravity_error(q: Quaternion, accel: [f64; 3]) -> Option<[f64; 3]> {
+    let norm = (accel[0] * accel[0] + accel[1] * accel[1] + accel[2] * accel[2]).sqrt();
+    if norm < f64::EPSILON {
+        return None;
+    }
+    let a = accel.map(|a| a / norm);
+    let v = rotation::rotate_vector(rotation::conjugate(q), [0.0, 0.0, 1.0]);
+    Some([
+        a[1] * v[2] - a[2] * v[1],
+        a[2] * v[0] - a[0] * v[2],
+        a[0] * v[1] - a[1] * v[0],
+    ])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const DT: f64 = 0.01;
+    const GRAVITY: f64 = 9.81;
+
+    /// Start the filter level, then feed `seconds` of a stationary vehicle at this roll and pitch
+    /// with the gyro reading `bias`
+    fn run_tilted(filter: &mut dyn AttitudeEstimator, roll: f64, pitch: f64, bias: [f64; 3], seconds: f64) {
+        filter.update([0.0, 0.0, GRAVITY]);
+        let q = rotation::euler_to_quaternion(roll, pitch, 0.0);
+        let accel = rotation::rotate_vector(rotation::conjugate(q), [0.0, 0.0, GRAVITY]);
+        for _ in 0..(seconds / DT) as usize {
+            filter.predict(bias, DT);
+            filter.update(accel);
+        }
+    }
+
+    /// Largest of the roll and pitch errors, rad
+    fn tilt_error(filter: &dyn AttitudeEstimator, roll: f64, pitch: f64) -> f64 {
+        let euler = filter.euler().unwrap();
+        (euler[0] - roll).abs().max((euler[1] - pitch).abs())
+    }
+
+    #[test]
+    fn every_filter_converges_to_a_static_tilt() {
+        let (roll, pitch) = (0.35, -0.25);
+        let filters: [Box<dyn AttitudeEstimator>; 3] = [
+            Box::new(ComplementaryFilter::new(ComplementaryConfig::default())),
+            Box::new(MahonyFilter::new(MahonyConfig::default())),
+            Box::new(MadgwickFilter::new(MadgwickConfig::default())),
+        ];
+        for mut filter in filters {
+            assert_eq!(filter.orientation(), None);
+            // Long enough for Mahony's integral, wound up by the initial error, to unwind
+            run_tilted(filter.as_mut(), roll, pitch, [0.0; 3], 200.0);
+            // Madgwick's step has a fixed length, beta dt, so it dithers about 2 beta dt around the tilt
+            let error = tilt_error(filter.as_ref(), roll, pitch);
+            assert!(error < 3e-3, "{} off by {}", filter.name(), error);
+        }
+    }
+
+    #[test]
+    fn mahony_integral_absorbs_a_constant_gyro_bias() {
+        let (roll, pitch) = (0.2, 0.3);
+        let bias = [0.02, -0.015, 0.01];
+        let mut filter = MahonyFilter::new(MahonyConfig::default());
+        run_tilted(&mut filter, roll, pitch, bias, 400.0);
+
+        // A bias about the gravity direction only turns the yaw, which the accelerometer can't see,
+        // so only the part across it is compared
+        let q = rotation::euler_to_quaternion(roll, pitch, 0.0);
+        let up = rotation::rotate_vector(rotation::conjugate(q), [0.0, 0.0, 1.0]);
+        let estimated = filter.gyro_bias().unwrap();
+        let error = [0, 1, 2].map(|i| estimated[i] - bias[i]);
+        let along = error[0] * up[0] + error[1] * up[1] + error[2] * up[2];
+        let across = [0, 1, 2].map(|i| error[i] - along * up[i]);
+        let across = (across[0] * across[0] + across[1] * across[1] + across[2] * across[2]).sqrt();
+        assert!(across < 1e-3, "{:?}", estimated);
+        assert!(tilt_error(&filter, roll, pitch) < 1e-3);
+
+        // Without the integral term the same bias holds the estimate off the tilt
+        let mut proportional = MahonyFilter::new(MahonyConfig { ki: 0.0, ..MahonyConfig::default() });
+        run_tilted(&mut proportional, roll, pitch, bias, 400.0);
+        assert!(tilt_error(&proportional, roll, pitch) > 0.01);
+    }
+}
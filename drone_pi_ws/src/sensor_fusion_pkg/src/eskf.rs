@@ -0,0 +1,377 @@
+//! Error-state Kalman filter on attitude and gyro bias.
+//!
+//! The static gyro bias subtracted in the IMU publisher is measured once, at rest and at one
+//! temperature, so a residual bias is left in flight and the attitude integrates it. This filter
+//! carries the bias as state next to the quaternion: the nominal state is the body to world
+//! quaternion and the bias (7 values), and the Kalman filter runs on a 6 element error state, a small
+//! body frame rotation and a bias correction, which is folded into the nominal state after every
+//! accelerometer update. Gravity only constrains the bias about the horizontal axes, so the bias about
+//! the body Z axis is only learned while the vehicle is tilted, or from magnetometer headings: those
+//! are a measurement update on the same error state, so they correct yaw and its bias jointly with
+//! the rest of the state and covariance.
+
+use attitude_pkg::estimator::{AttitudeEstimator, HeadingUpdate};
+use attitude_pkg::rotation::{self, wrap_angle, Covariance, Quaternion};
+use nalgebra::{Matrix1x6, Matrix3, Matrix3x6, Matrix6, Matrix6x1, Matrix6x3, Vector3, Vector6};
+
+/// Standard gravity, m/s^2
+pub const GRAVITY: f64 = 9.80665;
+
+/// 1 sigma of the roll and pitch taken from the first accelerometer sample, and of the unknown yaw, rad
+const INITIAL_ATTITUDE_STD: f64 = 0.1;
+
+#[derive(Clone, Debug)]
+pub struct EskfConfig {
+    pub gyro_noise: f64,         // rad/s/sqrt(Hz), gyro white noise density
+    pub bias_random_walk: f64,   // rad/s^2/sqrt(Hz), how fast the bias wanders
+    pub accel_noise: f64,        // m/s^2, 1 sigma of an accelerometer sample as a gravity reference
+    pub initial_bias: [f64; 3],  // rad/s, bias to start from, e.g. from a stored calibration
+    pub initial_bias_std: f64,   // rad/s, 1 sigma of that starting bias
+}
+
+impl Default for EskfConfig {
+    fn default() -> Self {
+        Self {
+            gyro_noise: 0.005,
+            bias_random_walk: 2e-5,
+            accel_noise: 0.5,
+            initial_bias: [0.0; 3],
+            initial_bias_std: 0.01,
+        }
+    }
+}
+
+pub struct GyroBiasEskf {
+    config: EskfConfig,
+    q: Option<Quaternion>, // Body to world, None until the first accelerometer sample
+    bias: Vector3<f64>,    // rad/s, subtracted from the gyro
+    p: Matrix6<f64>,       // Error state covariance, rotation (rad) then bias (rad/s)
+    nis: Option<f64>,      // Of the accelerometer update since the last prediction
+    heading_known: bool,   // Whether the yaw has been set from a magnetometer sample
+}
+
+impl GyroBiasEskf {
+    pub fn new(config: EskfConfig) -> Self {
+        let mut filter = Self {
+            bias: Vector3::from(config.initial_bias),
+            config,
+            q: None,
+            p: Matrix6::zeros(),
+            nis: None,
+            heading_known: false,
+        };
+        filter.reset();
+        filter
+    }
+
+    /// Error state covariance, body frame rotation (rad) then bias (rad/s)
+    pub fn covariance(&self) -> &Matrix6<f64> {
+        &self.p
+    }
+
+    /// Correct roll, pitch and the horizontal bias with the direction of a specific force sample,
+    /// its noise scaled by `noise_scale`
+    fn correct(&mut self, accel: [f64; 3], noise_scale: f64) {
+        let Some(q) = self.q else {
+            self.q = Some(rotation::gravity_to_quaternion(accel));
+            return;
+        };
+        let measured = Vector3::from(accel);
+        let norm = measured.norm();
+        if norm < f64::EPSILON {
+            return;
+        }
+
+        // Compare directions only: gravity seen in body axes against the measured specific force.
+        // A small body rotation e moves the prediction h to h + h x e
+        let world_up = rotation::rotate_vector(rotation::conjugate(q), [0.0, 0.0, 1.0]);
+        let predicted = Vector3::from(world_up);
+        let innovation = measured / norm - predicted;
+
+        let mut h = Matrix3x6::zeros();
+        h.fixed_view_mut::<3, 3>(0, 0).copy_from(&predicted.cross_matrix());
+        let r = Matrix3::from_diagonal_element((noise_scale * self.config.accel_noise / GRAVITY).powi(2));
+
+        let s = h * self.p * h.transpose() + r;
+        let Some(s_inv) = s.try_inverse() else {
+            return;
+        };
+        self.nis = Some(innovation.dot(&(s_inv * innovation)));
+        let k: Matrix6x3<f64> = self.p * h.transpose() * s_inv;
+        let correction: Vector6<f64> = k * innovation;
+
+        // Joseph form keeps the covariance symmetric and positive
+        let i_kh = Matrix6::identity() - k * h;
+        self.p = i_kh * self.p * i_kh.transpose() + k * r * k.transpose();
+
+        self.fold(q, correction);
+    }
+
+    /// Fold an error state correction into the nominal state, which leaves the error state at zero
+    fn fold(&mut self, q: Quaternion, correction: Vector6<f64>) {
+        let angle = correction.fixed_rows::<3>(0).into_owned();
+        self.q = Some(rotation::normalize(rotation::multiply(q, rotation_quaternion(angle))));
+        self.bias += correction.fixed_rows::<3>(3);
+    }
+}
+
+impl AttitudeEstimator for GyroBiasEskf {
+    fn name(&self) -> &'static str {
+        "eskf"
+    }
+
+    fn predict(&mut self, gyro: [f64; 3], dt: f64) {
+        let Some(q) = self.q else {
+            return;
+        };
+        let rate = Vector3::from(gyro) - self.bias;
+        let angle = rate * dt;
+        self.q = Some(rotation::normalize(rotation::multiply(q, rotation_quaternion(angle))));
+
+        // The rotation error is in body axes, so it's carried through this step's rotation, and a
+        // bias error integrates straight into it
+        let mut f = Matrix6::identity();
+        f.fixed_view_mut::<3, 3>(0, 0).copy_from(&rotation_matrix(-angle));
+        f.fixed_view_mut::<3, 3>(0, 3).copy_from(&(-Matrix3::identity() * dt));
+
+        let mut noise = Matrix6::zeros();
+        noise
+            .fixed_view_mut::<3, 3>(0, 0)
+            .fill_diagonal(self.config.gyro_noise.powi(2) * dt);
+        noise
+            .fixed_view_mut::<3, 3>(3, 3)
+            .fill_diagonal(self.config.bias_random_walk.powi(2) * dt);
+
+        self.p = f * self.p * f.transpose() + noise;
+        self.nis = None;
+    }
+
+    fn update(&mut self, accel: [f64; 3]) {
+        self.correct(accel, 1.0);
+    }
+
+    fn update_scaled(&mut self, accel: [f64; 3], noise_scale: f64) {
+        self.correct(accel, noise_scale);
+    }
+
+    fn orientation(&self) -> Option<Quaternion> {
+        self.q
+    }
+
+    fn euler_covariance(&self) -> Option<Covariance> {
+        let q = self.q?;
+        let covariance = [0, 1, 2].map(|row| [0, 1, 2].map(|col| self.p[(row, col)]));
+        Some(rotation::body_to_euler_covariance(q, covariance))
+    }
+
+    fn nis(&self) -> Option<f64> {
+        self.nis
+    }
+
+    fn gyro_bias(&self) -> Option<[f64; 3]> {
+        Some(self.bias.into())
+    }
+
+    fn update_mag(&mut self, mag: [f64; 3], heading: f64, noise: f64, gate: f64) -> Option<HeadingUpdate> {
+        let mut q = self.q?;
+        let Some((error, _)) = heading_error(q, mag, heading) else {
+            return Some(HeadingUpdate::Rejected(0.0));
+        };
+
+        // Yaw is unobservable until now, so the first heading is taken as it is, turning the whole
+        // estimate about world up; the update below then only narrows the covariance
+        if !self.heading_known {
+            let half = -error / 2.0;
+            q = rotation::normalize(rotation::multiply([half.cos(), 0.0, 0.0, half.sin()], q));
+            self.q = Some(q);
+            self.heading_known = true;
+        }
+        let (innovation, h) = heading_error(q, mag, heading)?;
+
+        let r = noise * noise;
+        let mut s = (h * self.p * h.transpose())[(0, 0)] + r;
+        if gate.is_infinite() && innovation * innovation > s {
+            // Told to take a sample far outside the uncertainty, so the heading has drifted further
+            // than the covariance says. Open it up about world up to reach the sample.
+            let up = rotation_matrix_of(q).row(2).transpose();
+            let mut opened = self.p.fixed_view_mut::<3, 3>(0, 0);
+            opened += up * up.transpose() * innovation * innovation;
+            s = (h * self.p * h.transpose())[(0, 0)] + r;
+        }
+        if innovation * innovation > gate * gate * s {
+            return Some(HeadingUpdate::Rejected(-innovation));
+        }
+        // The accelerometer NIS isn't touched, it's what the health checks average
+        let k: Matrix6x1<f64> = self.p * h.transpose() / s;
+        let i_kh = Matrix6::identity() - k * h;
+        self.p = i_kh * self.p * i_kh.transpose() + k * r * k.transpose();
+        self.fold(q, k * innovation);
+        Some(HeadingUpdate::Applied(-error))
+    }
+
+    fn reset(&mut self) {
+        self.q = None;
+        self.nis = None;
+        self.heading_known = false;
+        self.bias = Vector3::from(self.config.initial_bias);
+        self.p = Matrix6::zeros();
+        self.p
+            .fixed_view_mut::<3, 3>(0, 0)
+            .fill_diagonal(INITIAL_ATTITUDE_STD.powi(2));
+        self.p
+            .fixed_view_mut::<3, 3>(3, 3)
+            .fill_diagonal(self.config.initial_bias_std.powi(2));
+    }
+}
+
+/// Quaternion for a rotation vector (axis times angle, rad)
+fn rotation_quaternion(angle: Vector3<f64>) -> Quaternion {
+    rotation::rotation_vector_to_quaternion(angle.into())
+}
+
+/// Rotation matrix for a rotation vector (axis times angle, rad)
+fn rotation_matrix(angle: Vector3<f64>) -> Matrix3<f64> {
+    rotation_matrix_of(rotation_quaternion(angle))
+}
+
+/// Rotation matrix of a quaternion
+fn rotation_matrix_of(q: Quaternion) -> Matrix3<f64> {
+    let m = rotation::quaternion_to_matrix(q);
+    Matrix3::from_fn(|row, col| m[row][col])
+}
+
+/// Magnetometer heading error and its Jacobian over the error state: the heading the sample's
+/// tilt compensated field points in less `heading`, None if the field is vertical
+fn heading_error(q: Quaternion, mag: [f64; 3], heading: f64) -> Option<(f64, Matrix1x6<f64>)> {
+    let [fx, fy, fz] = rotation::rotate_vector(q, mag);
+    let horizontal_squared = fx * fx + fy * fy;
+    if horizontal_squared < f64::EPSILON {
+        return None;
+    }
+    let error = wrap_angle(fy.atan2(fx) - heading);
+
+    // A small body rotation e turns the world frame field by -R e, which moves its heading by
+    // -(R e)_z, and by the tilt error through the vertical part of the field
+    let over_world_rotation = Vector3::new(fx * fz, fy * fz, -horizontal_squared) / horizontal_squared;
+    let mut h = Matrix1x6::zeros();
+    h.fixed_view_mut::<1, 3>(0, 0)
+        .copy_from(&(over_world_rotation.transpose() * rotation_matrix_of(q)));
+    Some((error, h))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const DT: f64 = 0.01;
+
+    /// Deterministic standard normal samples (xorshift and Box-Muller)
+    struct Noise(u64);
+
+    impl Noise {
+        fn uniform(&mut self) -> f64 {
+            self.0 ^= self.0 << 13;
+            self.0 ^= self.0 >> 7;
+            self.0 ^= self.0 << 17;
+            (self.0 >> 11) as f64 / (1u64 << 53) as f64
+        }
+
+        fn normal(&mut self) -> f64 {
+            let u = self.uniform().max(f64::MIN_POSITIVE);
+            (-2.0 * u.ln()).sqrt() * (std::f64::consts::TAU * self.uniform()).cos()
+        }
+
+        fn vector(&mut self, std: f64) -> [f64; 3] {
+            [0, 1, 2].map(|_| std * self.normal())
+        }
+    }
+
+    /// Specific force seen at rest in attitude q
+    fn static_accel(q: Quaternion) -> [f64; 3] {
+        rotation::rotate_vector(rotation::conjugate(q), [0.0, 0.0, GRAVITY])
+    }
+
+    /// Feed `seconds` of a stationary vehicle in attitude q, gyro reading `bias`, with white noise
+    /// of the filter's own densities if `noise` is given. Returns the NIS of every update.
+    fn run_static(
+        filter: &mut GyroBiasEskf,
+        q: Quaternion,
+        bias: [f64; 3],
+        seconds: f64,
+        mut noise: Option<&mut Noise>,
+    ) -> Vec<f64> {
+        let gyro_std = filter.config.gyro_noise / DT.sqrt();
+        let accel_std = filter.config.accel_noise;
+        let mut nis = Vec::new();
+        for _ in 0..(seconds / DT) as usize {
+            let (gyro_noise, accel_noise) = match noise.as_deref_mut() {
+                Some(noise) => (noise.vector(gyro_std), noise.vector(accel_std)),
+                None => ([0.0; 3], [0.0; 3]),
+            };
+            let accel = static_accel(q);
+            filter.predict([0, 1, 2].map(|i| bias[i] + gyro_noise[i]), DT);
+            filter.update([0, 1, 2].map(|i| accel[i] + accel_noise[i]));
+            nis.extend(filter.nis());
+        }
+        nis
+    }
+
+    #[test]
+    fn tilted_static_accelerometer_gives_roll_and_pitch() {
+        let mut filter = GyroBiasEskf::new(EskfConfig::default());
+        // Starts level, then sits at a tilt
+        filter.update([0.0, 0.0, GRAVITY]);
+        let (roll, pitch) = (0.4, -0.3);
+        run_static(&mut filter, rotation::euler_to_quaternion(roll, pitch, 0.0), [0.0; 3], 30.0, None);
+
+        let euler = filter.euler().unwrap();
+        assert!((euler[0] - roll).abs() < 1e-3, "{:?}", euler);
+        assert!((euler[1] - pitch).abs() < 1e-3, "{:?}", euler);
+    }
+
+    #[test]
+    fn constant_gyro_bias_converges_into_the_estimate() {
+        let mut filter = GyroBiasEskf::new(EskfConfig::default());
+        let q = rotation::euler_to_quaternion(0.5, -0.4, 1.0);
+        let bias = [0.01, -0.02, 0.015];
+        filter.update(static_accel(q));
+        run_static(&mut filter, q, bias, 60.0, None);
+
+        // A bias about the gravity direction is a yaw drift, which gravity can't see, so only the
+        // part across it is compared
+        let up = Vector3::from(static_accel(q)).normalize();
+        let across = |v: Vector3<f64>| v - up * up.dot(&v);
+        let error = across(Vector3::from(filter.gyro_bias().unwrap()) - Vector3::from(bias));
+        assert!(error.norm() < 5e-4, "{}", error);
+
+        let euler = filter.euler().unwrap();
+        assert!((euler[0] - 0.5).abs() < 1e-3 && (euler[1] + 0.4).abs() < 1e-3, "{:?}", euler);
+    }
+
+    #[test]
+    fn covariance_stays_symmetric_positive_definite() {
+        let mut filter = GyroBiasEskf::new(EskfConfig::default());
+        let mut noise = Noise(0x2545_f491_4f6c_dd1d);
+        let q = rotation::euler_to_quaternion(0.2, 0.1, -0.7);
+        filter.update(static_accel(q));
+        run_static(&mut filter, q, [0.005, 0.0, -0.005], 60.0, Some(&mut noise));
+
+        let p = filter.covariance();
+        assert!((p - p.transpose()).amax() < 1e-15, "{}", p);
+        assert!(p.cholesky().is_some(), "{}", p);
+    }
+
+    #[test]
+    fn nis_averages_two_on_consistent_data() {
+        let mut filter = GyroBiasEskf::new(EskfConfig::default());
+        let mut noise = Noise(0x9e37_79b9_7f4a_7c15);
+        let q = rotation::euler_to_quaternion(-0.3, 0.2, 0.4);
+        filter.update(static_accel(q));
+        // Past the start, where the covariance is still settling
+        run_static(&mut filter, q, [0.0; 3], 10.0, Some(&mut noise));
+        let nis = run_static(&mut filter, q, [0.0; 3], 60.0, Some(&mut noise));
+
+        let average = nis.iter().sum::<f64>() / nis.len() as f64;
+        assert!((average - 2.0).abs() < 0.25, "{}", average);
+    }
+}
@@ -0,0 +1,133 @@
+//! The estimate in the form it's published in.
+//!
+//! Besides the quaternion, the attitude estimator publishes roll, pitch and yaw, the body rate with
+//! the filter's bias estimate removed, and the orientation covariance in the row-major layout of
+//! `sensor_msgs/Imu`. Once the magnetometer holds the heading, the yaw variance is its variance
+//! rather than the filter's, which can't observe yaw on its own.
+
+use attitude_pkg::rotation::{self, Covariance, Quaternion};
+
+/// One estimate in the units and layout of the published messages
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct EstimateOutput {
+    pub euler: [f64; 3],                  // Roll, pitch, yaw, in rad or degrees
+    pub rate: [f64; 3],                   // rad/s, the gyro less the filter's bias estimate
+    pub orientation_covariance: [f64; 9], // Roll, pitch and yaw error, row major, zero if unknown
+}
+
+/// Publishable form of orientation `q` (heading already corrected), the filter's Euler error
+/// covariance if it has one, the magnetometer's yaw variance once it holds the heading, and the
+/// gyro sample with the filter's bias estimate if it makes one
+pub fn estimate_output(
+    q: Quaternion,
+    covariance: Option<Covariance>,
+    mag_yaw_variance: Option<f64>,
+    gyro: [f64; 3],
+    gyro_bias: Option<[f64; 3]>,
+    degrees: bool,
+) -> EstimateOutput {
+    let euler = rotation::quaternion_to_euler(q);
+    let euler = if degrees { euler.map(f64::to_degrees) } else { euler };
+
+    // The rate the estimate was propagated with
+    let rate = gyro_bias.map_or(gyro, |bias| [0, 1, 2].map(|axis| gyro[axis] - bias[axis]));
+
+    // The magnetometer's heading is independent of the filter's roll and pitch
+    let covariance = covariance.map(|mut c| {
+        if let Some(variance) = mag_yaw_variance {
+            c[0][2] = 0.0;
+            c[1][2] = 0.0;
+            c[2] = [0.0, 0.0, variance];
+        }
+        c
+    });
+    let orientation_covariance = covariance.map_or([0.0; 9], |c| {
+        [c[0][0], c[0][1], c[0][2], c[1][0], c[1][1], c[1][2], c[2][0], c[2][1], c[2][2]]
+    });
+
+    EstimateOutput {
+        euler,
+        rate,
+        orientation_covariance,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TOLERANCE: f64 = 1e-9;
+
+    /// Error covariance with every entry distinct, so misplaced entries show
+    const COVARIANCE: Covariance = [[1e-4, 2e-5, 3e-5], [2e-5, 5e-4, 6e-5], [3e-5, 6e-5, 9e-2]];
+
+    /// Euler angles in rad for the published degrees, converted back to a quaternion
+    fn quaternion_from_degrees(euler: [f64; 3]) -> Quaternion {
+        let [roll, pitch, yaw] = euler.map(f64::to_radians);
+        rotation::euler_to_quaternion(roll, pitch, yaw)
+    }
+
+    /// Whether two unit quaternions are the same rotation, either sign
+    fn same_rotation(a: Quaternion, b: Quaternion) -> bool {
+        let dot: f64 = a.iter().zip(b).map(|(a, b)| a * b).sum();
+        (dot.abs() - 1.0).abs() < TOLERANCE
+    }
+
+    #[test]
+    fn euler_angles_and_rate_are_converted() {
+        let q = rotation::euler_to_quaternion(0.1, -0.2, 2.5);
+        let output = estimate_output(q, None, None, [0.1, 0.2, 0.3], Some([0.01, -0.02, 0.03]), false);
+        for (angle, expected) in output.euler.iter().zip([0.1, -0.2, 2.5]) {
+            assert!((angle - expected).abs() < TOLERANCE, "{:?}", output.euler);
+        }
+        for (rate, expected) in output.rate.iter().zip([0.09, 0.22, 0.27]) {
+            assert!((rate - expected).abs() < TOLERANCE, "{:?}", output.rate);
+        }
+        assert_eq!(output.orientation_covariance, [0.0; 9]);
+
+        let degrees = estimate_output(q, None, None, [0.1, 0.2, 0.3], None, true);
+        assert!((degrees.euler[2] - 2.5f64.to_degrees()).abs() < TOLERANCE);
+        // Without a bias estimate the gyro is published as it came
+        assert_eq!(degrees.rate, [0.1, 0.2, 0.3]);
+    }
+
+    #[test]
+    fn covariance_is_row_major_with_the_magnetometer_yaw() {
+        let q = rotation::IDENTITY;
+        let output = estimate_output(q, Some(COVARIANCE), None, [0.0; 3], None, false);
+        assert_eq!(output.orientation_covariance, [1e-4, 2e-5, 3e-5, 2e-5, 5e-4, 6e-5, 3e-5, 6e-5, 9e-2]);
+
+        // Roll and pitch keep the filter's, yaw is the magnetometer's and uncorrelated with them
+        let output = estimate_output(q, Some(COVARIANCE), Some(4e-3), [0.0; 3], None, false);
+        assert_eq!(output.orientation_covariance, [1e-4, 2e-5, 0.0, 2e-5, 5e-4, 0.0, 0.0, 0.0, 4e-3]);
+
+        // Nothing to go on without the filter's
+        let output = estimate_output(q, None, Some(4e-3), [0.0; 3], None, false);
+        assert_eq!(output.orientation_covariance, [0.0; 9]);
+    }
+
+    #[test]
+    fn angles_near_gimbal_lock_still_give_the_same_rotation() {
+        for pitch in [89.0, 89.999, 89.999_999_9, 90.0, -89.999, -90.0] {
+            for (roll, yaw) in [(0.0, 30.0), (10.0, -150.0), (-170.0, 5.0)] {
+                let q = quaternion_from_degrees([roll, pitch, yaw]);
+                let output = estimate_output(q, None, None, [0.0; 3], None, true);
+                assert!(output.euler.iter().all(|angle| angle.is_finite()), "{:?}", output.euler);
+                assert!(output.euler[1].abs() <= 90.0, "{:?}", output.euler);
+                assert!(
+                    same_rotation(quaternion_from_degrees(output.euler), q),
+                    "{:?} from {:?}",
+                    output.euler,
+                    [roll, pitch, yaw]
+                );
+            }
+        }
+
+        // In the lock itself roll is folded into yaw: pitched up, only yaw - roll is defined
+        let q = quaternion_from_degrees([10.0, 90.0, 30.0]);
+        let output = estimate_output(q, None, None, [0.0; 3], None, true);
+        assert!(output.euler[0].abs() < TOLERANCE, "{:?}", output.euler);
+        assert!((output.euler[1] - 90.0).abs() < TOLERANCE);
+        assert!((output.euler[2] - 20.0).abs() < 1e-6, "{:?}", output.euler);
+    }
+}
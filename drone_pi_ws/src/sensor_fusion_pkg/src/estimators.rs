@@ -0,0 +1,216 @@
+//! The attitude filters the estimator node can run, selected by name.
+
+use attitude_pkg::estimator::AttitudeEstimator;
+use attitude_pkg::rotation::{self, Covariance, Quaternion};
+use crate::complementary::{
+    ComplementaryConfig, ComplementaryFilter, MadgwickConfig, MadgwickFilter, MahonyConfig, MahonyFilter,
+};
+use crate::eskf::{EskfConfig, GyroBiasEskf};
+use rust_ekf::{EKFEuler, EKF};
+
+/// Names accepted by `create_estimator`
+pub const ESTIMATOR_NAMES: [&str; 6] = ["ekf", "euler_ekf", "eskf", "complementary", "mahony", "madgwick"];
+
+/// Time step `rust_ekf::EKFEuler` integrates every prediction over, s (100 Hz)
+const EULER_EKF_DT: f64 = 0.01;
+
+/// Fraction the average sample step may differ from `EULER_EKF_DT` before a warning
+const EULER_EKF_DT_TOLERANCE: f64 = 0.1;
+
+/// Fraction of the way the average step moves towards each new one
+const DT_SMOOTHING: f64 = 0.05;
+
+/// Settings for the filters that take any
+#[derive(Clone, Debug, Default)]
+pub struct EstimatorConfig {
+    pub eskf: EskfConfig,
+    pub complementary: ComplementaryConfig,
+    pub mahony: MahonyConfig,
+    pub madgwick: MadgwickConfig,
+}
+
+/// The filter called `name`, or an error listing the ones there are
+pub fn create_estimator(name: &str, config: &EstimatorConfig) -> Result<Box<dyn AttitudeEstimator>, String> {
+    match name {
+        "ekf" => Ok(Box::new(QuaternionEkf::new())),
+        "euler_ekf" => Ok(Box::new(EulerEkf::new())),
+        "eskf" => Ok(Box::new(GyroBiasEskf::new(config.eskf.clone()))),
+        "complementary" => Ok(Box::new(ComplementaryFilter::new(config.complementary.clone()))),
+        "mahony" => Ok(Box::new(MahonyFilter::new(config.mahony.clone()))),
+        "madgwick" => Ok(Box::new(MadgwickFilter::new(config.madgwick.clone()))),
+        other => Err(format!(
+            "Unknown estimator {}, expected one of {}",
+            other,
+            ESTIMATOR_NAMES.join(", ")
+        )),
+    }
+}
+
+/// `rust_ekf::EKF`, a quaternion EKF started from the first accelerometer sample
+pub struct QuaternionEkf {
+    ekf: Option<EKF>, // None until the first accelerometer sample
+}
+
+impl QuaternionEkf {
+    pub fn new() -> Self {
+        Self { ekf: None }
+    }
+}
+
+impl Default for QuaternionEkf {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl AttitudeEstimator for QuaternionEkf {
+    fn name(&self) -> &'static str {
+        "ekf"
+    }
+
+    fn predict(&mut self, gyro: [f64; 3], dt: f64) {
+        if let Some(ekf) = self.ekf.as_mut() {
+            ekf.predict(gyro, dt);
+        }
+    }
+
+    fn update(&mut self, accel: [f64; 3]) {
+        match self.ekf.as_mut() {
+            Some(ekf) => ekf.update(accel),
+            None => self.ekf = Some(EKF::new(Some(accel))),
+        }
+    }
+
+    fn orientation(&self) -> Option<Quaternion> {
+        let state = self.ekf.as_ref()?.get_state();
+        Some([state[0], state[1], state[2], state[3]])
+    }
+
+    fn euler_covariance(&self) -> Option<Covariance> {
+        let ekf = self.ekf.as_ref()?;
+        let p = ekf.covariance;
+        let covariance = [0, 1, 2, 3].map(|row| [0, 1, 2, 3].map(|col| p[(row, col)]));
+        let q = self.orientation()?;
+        Some(rotation::body_to_euler_covariance(q, rotation::quaternion_to_body_covariance(q, covariance)))
+    }
+
+    fn reset(&mut self) {
+        self.ekf = None;
+    }
+}
+
+/// `rust_ekf::EKFEuler`, an EKF on roll, pitch and yaw. It steps with its own fixed time step,
+/// `EULER_EKF_DT`, so it only tracks correctly at that rate. The steps it's given are averaged and a
+/// warning logged, once, if they settle away from it.
+pub struct EulerEkf {
+    ekf: EKFEuler,
+    updated: bool,            // Whether it has seen an accelerometer sample since starting
+    average_dt: Option<f64>,  // s, of the predictions so far
+    steps: usize,             // Predictions averaged
+    warned: bool,             // Whether the rate warning has been logged
+}
+
+impl EulerEkf {
+    pub fn new() -> Self {
+        Self {
+            ekf: EKFEuler::new(),
+            updated: false,
+            average_dt: None,
+            steps: 0,
+            warned: false,
+        }
+    }
+
+    /// Average step the filter has been given, once there have been enough to judge the rate by,
+    /// if it's too far from the one the filter integrates over
+    fn mistuned_dt(&self) -> Option<f64> {
+        let average = self.average_dt.filter(|_| self.steps as f64 >= 1.0 / DT_SMOOTHING)?;
+        ((average - EULER_EKF_DT).abs() > EULER_EKF_DT_TOLERANCE * EULER_EKF_DT).then_some(average)
+    }
+}
+
+impl Default for EulerEkf {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl AttitudeEstimator for EulerEkf {
+    fn name(&self) -> &'static str {
+        "euler_ekf"
+    }
+
+    fn predict(&mut self, gyro: [f64; 3], dt: f64) {
+        self.ekf.predict(gyro);
+
+        let average = self.average_dt.map_or(dt, |average| average + DT_SMOOTHING * (dt - average));
+        self.average_dt = Some(average);
+        self.steps += 1;
+        if !self.warned {
+            if let Some(average) = self.mistuned_dt() {
+                eprintln!(
+                    "The euler_ekf estimator integrates over {:.1} ms steps but samples arrive every {:.1} ms, its attitude will be off",
+                    EULER_EKF_DT * 1e3,
+                    average * 1e3
+                );
+                self.warned = true;
+            }
+        }
+    }
+
+    fn update(&mut self, accel: [f64; 3]) {
+        self.ekf.update(accel);
+        self.updated = true;
+    }
+
+    fn orientation(&self) -> Option<Quaternion> {
+        if !self.updated {
+            return None;
+        }
+        let state = self.ekf.get_state();
+        Some(rotation::euler_to_quaternion(state[0], state[1], state[2]))
+    }
+
+    fn euler_covariance(&self) -> Option<Covariance> {
+        if !self.updated {
+            return None;
+        }
+        let p = self.ekf.covariance;
+        Some([0, 1, 2].map(|row| [0, 1, 2].map(|col| p[(row, col)])))
+    }
+
+    fn reset(&mut self) {
+        // The sample rate hasn't changed, so neither has the warning
+        self.ekf = EKFEuler::new();
+        self.updated = false;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn every_name_creates_its_estimator() {
+        let config = EstimatorConfig::default();
+        for name in ESTIMATOR_NAMES {
+            assert_eq!(create_estimator(name, &config).unwrap().name(), name);
+        }
+        assert!(create_estimator("kalman", &config).is_err());
+    }
+
+    #[test]
+    fn euler_ekf_flags_a_rate_it_was_not_tuned_for() {
+        let mut tuned = EulerEkf::new();
+        let mut slow = EulerEkf::new();
+        for i in 0..100 {
+            // A lost sample now and then doesn't move the average far
+            let dt = if i == 50 { 2.0 * EULER_EKF_DT } else { EULER_EKF_DT };
+            tuned.predict([0.0; 3], dt);
+            slow.predict([0.0; 3], 0.005);
+        }
+        assert_eq!(tuned.mistuned_dt(), None);
+        assert!((slow.mistuned_dt().unwrap() - 0.005).abs() < 1e-9);
+        assert!(slow.warned);
+    }
+}
@@ -0,0 +1,266 @@
+//! Divergence checks on the attitude filter's state.
+//!
+//! A filter that has gone wrong doesn't recover on its own: a NaN from one bad sample or a
+//! quaternion that has drifted off unit length propagates into every later estimate, and a filter
+//! whose covariance has collapsed stops listening to the accelerometer. `FilterHealth` looks at the
+//! filter after every sample for values that aren't finite, a quaternion norm away from 1, a tilt
+//! uncertainty beyond anything a working filter reaches, and accelerometer innovations persistently
+//! larger than the filter's own covariance predicts (NIS, averaged so single outliers don't count).
+//! On any of these the caller restarts the filter from the current measurements. A filter may start
+//! out more uncertain than the tilt limit, so that limit only applies from the uncertainty the
+//! filter started with.
+
+use attitude_pkg::estimator::AttitudeEstimator;
+use std::fmt;
+
+/// Updates the NIS average spans, roughly
+const NIS_WINDOW: f64 = 200.0;
+
+/// Average NIS of a consistent filter, which the average starts from. Gravity's direction has two
+/// degrees of freedom.
+const EXPECTED_NIS: f64 = 2.0;
+
+#[derive(Clone, Copy, Debug)]
+pub struct HealthConfig {
+    pub max_norm_error: f64, // Largest | |q| - 1 | allowed
+    pub max_tilt_std: f64,   // rad, largest roll or pitch standard deviation allowed
+    pub max_nis: f64,        // Largest average NIS allowed
+}
+
+/// Why a filter was judged to have diverged
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Divergence {
+    /// NaN or infinite orientation or covariance
+    NotFinite,
+    /// Quaternion this far from unit length
+    QuaternionNorm(f64),
+    /// Roll or pitch standard deviation this large, rad
+    TiltUncertainty(f64),
+    /// Average NIS this large
+    Nis(f64),
+}
+
+impl fmt::Display for Divergence {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Divergence::NotFinite => write!(f, "state is not finite"),
+            Divergence::QuaternionNorm(error) => write!(f, "quaternion norm off by {:.4}", error),
+            Divergence::TiltUncertainty(std) => write!(f, "tilt uncertainty {:.1} deg", std.to_degrees()),
+            Divergence::Nis(nis) => write!(f, "average NIS {:.1}", nis),
+        }
+    }
+}
+
+pub struct FilterHealth {
+    config: HealthConfig,
+    average_nis: Option<f64>,
+    initial_tilt_std: Option<f64>, // rad, at the first check since the filter started
+    resets: u64,
+    last_divergence: Option<Divergence>,
+}
+
+impl FilterHealth {
+    pub fn new(config: HealthConfig) -> Self {
+        Self {
+            config,
+            average_nis: None,
+            initial_tilt_std: None,
+            resets: 0,
+            last_divergence: None,
+        }
+    }
+
+    /// Check the filter after a sample, returning why it has diverged if it has
+    pub fn check(&mut self, estimator: &dyn AttitudeEstimator) -> Option<Divergence> {
+        let q = estimator.orientation()?;
+        if q.iter().any(|c| !c.is_finite()) {
+            return Some(Divergence::NotFinite);
+        }
+        let norm_error = (q.iter().map(|c| c * c).sum::<f64>().sqrt() - 1.0).abs();
+        if norm_error > self.config.max_norm_error {
+            return Some(Divergence::QuaternionNorm(norm_error));
+        }
+
+        if let Some(covariance) = estimator.euler_covariance() {
+            if covariance.iter().flatten().any(|c| !c.is_finite()) {
+                return Some(Divergence::NotFinite);
+            }
+            let tilt_std = covariance[0][0].max(covariance[1][1]).max(0.0).sqrt();
+            let initial_tilt_std = *self.initial_tilt_std.get_or_insert(tilt_std);
+            if tilt_std > self.config.max_tilt_std.max(initial_tilt_std) {
+                return Some(Divergence::TiltUncertainty(tilt_std));
+            }
+        }
+
+        if let Some(nis) = estimator.nis() {
+            if !nis.is_finite() {
+                return Some(Divergence::NotFinite);
+            }
+            let average = self.average_nis.unwrap_or(EXPECTED_NIS);
+            let average = average + (nis - average) / NIS_WINDOW;
+            self.average_nis = Some(average);
+            if average > self.config.max_nis {
+                return Some(Divergence::Nis(average));
+            }
+        }
+        None
+    }
+
+    /// The caller restarted the filter because of `divergence`
+    pub fn record_reset(&mut self, divergence: Divergence) {
+        self.resets += 1;
+        self.last_divergence = Some(divergence);
+        self.restart();
+    }
+
+    /// The filter was restarted for some other reason, so what was learned about it no longer holds
+    pub fn restart(&mut self) {
+        self.average_nis = None;
+        self.initial_tilt_std = None;
+    }
+
+    /// Times the filter has been restarted
+    pub fn resets(&self) -> u64 {
+        self.resets
+    }
+
+    /// Why the filter was last restarted
+    pub fn last_divergence(&self) -> Option<Divergence> {
+        self.last_divergence
+    }
+
+    /// Average NIS of the accelerometer updates, if the filter reports it
+    pub fn average_nis(&self) -> Option<f64> {
+        self.average_nis
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use attitude_pkg::rotation::{Covariance, Quaternion};
+
+    /// A filter whose state is whatever the test sets
+    struct FixedState {
+        q: Quaternion,
+        covariance: Covariance,
+        nis: Option<f64>,
+    }
+
+    impl FixedState {
+        fn new() -> Self {
+            Self {
+                q: [1.0, 0.0, 0.0, 0.0],
+                covariance: [[0.01, 0.0, 0.0], [0.0, 0.01, 0.0], [0.0, 0.0, 0.01]],
+                nis: Some(EXPECTED_NIS),
+            }
+        }
+    }
+
+    impl AttitudeEstimator for FixedState {
+        fn name(&self) -> &'static str {
+            "fixed"
+        }
+        fn predict(&mut self, _gyro: [f64; 3], _dt: f64) {}
+        fn update(&mut self, _accel: [f64; 3]) {}
+        fn orientation(&self) -> Option<Quaternion> {
+            Some(self.q)
+        }
+        fn euler_covariance(&self) -> Option<Covariance> {
+            Some(self.covariance)
+        }
+        fn nis(&self) -> Option<f64> {
+            self.nis
+        }
+        fn reset(&mut self) {}
+    }
+
+    fn health() -> FilterHealth {
+        FilterHealth::new(HealthConfig {
+            max_norm_error: 0.01,
+            max_tilt_std: 0.2,
+            max_nis: 5.0,
+        })
+    }
+
+    #[test]
+    fn consistent_filter_is_healthy() {
+        let mut health = health();
+        let filter = FixedState::new();
+        for _ in 0..1000 {
+            assert_eq!(health.check(&filter), None);
+        }
+        assert!((health.average_nis().unwrap() - EXPECTED_NIS).abs() < 1e-9);
+    }
+
+    #[test]
+    fn nan_anywhere_is_not_finite() {
+        let mut filter = FixedState::new();
+        filter.q[2] = f64::NAN;
+        assert_eq!(health().check(&filter), Some(Divergence::NotFinite));
+
+        let mut filter = FixedState::new();
+        filter.covariance[1][0] = f64::NAN;
+        assert_eq!(health().check(&filter), Some(Divergence::NotFinite));
+
+        let mut filter = FixedState::new();
+        filter.nis = Some(f64::INFINITY);
+        assert_eq!(health().check(&filter), Some(Divergence::NotFinite));
+    }
+
+    #[test]
+    fn quaternion_off_unit_length_diverges() {
+        let mut filter = FixedState::new();
+        filter.q = [1.005, 0.0, 0.0, 0.0];
+        assert_eq!(health().check(&filter), None);
+        filter.q = [1.05, 0.0, 0.0, 0.0];
+        match health().check(&filter) {
+            Some(Divergence::QuaternionNorm(error)) => assert!((error - 0.05).abs() < 1e-9),
+            other => panic!("{:?}", other),
+        }
+    }
+
+    #[test]
+    fn tilt_limit_applies_from_the_starting_uncertainty() {
+        let mut health = health();
+        let mut filter = FixedState::new();
+        // Starts beyond the limit, which is fine while it doesn't grow
+        filter.covariance[0][0] = 0.3f64.powi(2);
+        assert_eq!(health.check(&filter), None);
+        filter.covariance[0][0] = 0.25f64.powi(2);
+        assert_eq!(health.check(&filter), None);
+        filter.covariance[1][1] = 0.35f64.powi(2);
+        match health.check(&filter) {
+            Some(Divergence::TiltUncertainty(std)) => assert!((std - 0.35).abs() < 1e-9),
+            other => panic!("{:?}", other),
+        }
+
+        // After a restart the next check sets the starting uncertainty again
+        health.restart();
+        assert_eq!(health.check(&filter), None);
+    }
+
+    #[test]
+    fn single_outlier_passes_but_persistent_large_nis_diverges() {
+        let mut health = health();
+        let mut filter = FixedState::new();
+        filter.nis = Some(100.0);
+        assert_eq!(health.check(&filter), None);
+        filter.nis = Some(EXPECTED_NIS);
+        for _ in 0..200 {
+            assert_eq!(health.check(&filter), None);
+        }
+
+        filter.nis = Some(20.0);
+        // The average moves 1/200 of the way per update, so it takes a few dozen to pass 5
+        let checks = (1..=200)
+            .find(|_| matches!(health.check(&filter), Some(Divergence::Nis(_))))
+            .expect("never diverged");
+        assert!((30..60).contains(&checks), "{}", checks);
+
+        health.record_reset(Divergence::Nis(5.0));
+        assert_eq!(health.resets(), 1);
+        assert_eq!(health.last_divergence(), Some(Divergence::Nis(5.0)));
+        assert_eq!(health.average_nis(), None);
+    }
+}
@@ -0,0 +1,12 @@
+//! Estimation building blocks shared by the sensor fusion nodes.
+
+pub mod accel_gate;
+pub mod alignment;
+pub mod complementary;
+pub mod eskf;
+pub mod estimate_output;
+pub mod estimators;
+pub mod filter_health;
+pub mod mag_yaw;
+pub mod sample_queue;
+pub mod sample_timing;
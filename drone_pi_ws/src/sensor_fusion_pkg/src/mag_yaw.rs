@@ -0,0 +1,356 @@
+//! Magnetometer heading correction for an attitude estimate that only fuses gyro and accelerometer.
+//!
+//! Roll and pitch are observable from gravity but yaw isn't, so the estimator's heading drifts.
+//! Samples whose field strength or dip angle don't match the reference field are treated as magnetic
+//! disturbances and skipped, and the rest are heading measurements towards true north. Filters that
+//! take magnetometer updates (`AttitudeEstimator::update_mag`) fold them into their own state and
+//! covariance. For the others this keeps a yaw correction about the world Z axis (ENU, yaw 0 = east)
+//! as a scalar Kalman filter: it grows uncertain between magnetometer samples, and each
+//! tilt-compensated heading measurement pulls it back so the corrected estimate points at true north.
+
+use attitude_pkg::estimator::{AttitudeEstimator, HeadingUpdate};
+use attitude_pkg::rotation::{self, wrap_angle};
+use std::f64::consts::PI;
+
+/// Magnetometer samples averaged to learn the reference field when it isn't configured
+const REFERENCE_SAMPLES: usize = 50;
+
+/// Consecutive innovation rejections before the heading is assumed to have really changed
+const MAX_INNOVATION_REJECTIONS: usize = 100;
+
+#[derive(Clone, Debug)]
+pub struct MagYawConfig {
+    pub declination: f64,            // rad, magnetic north east of true north is positive
+    pub expected_field: Option<f64>, // uT, learned from the first samples if None
+    pub field_tolerance: f64,        // Fraction of the expected field strength
+    pub dip_tolerance: f64,          // rad
+    pub heading_noise: f64,          // rad, 1 sigma of a single heading measurement
+    pub yaw_drift: f64,              // rad, 1 sigma of heading drift after one second
+    pub innovation_gate: f64,        // Sigmas
+}
+
+/// What happened to a magnetometer sample
+#[derive(Clone, Debug, PartialEq)]
+pub enum MagUpdate {
+    /// The filter hasn't initialized, so there's no attitude to tilt compensate with
+    Waiting,
+    /// Still averaging samples to learn the reference field
+    Learning,
+    /// First accepted sample, heading set directly
+    Initialized,
+    /// Heading correction updated by this innovation (rad)
+    Applied(f64),
+    /// Field strength (uT) too far from the reference
+    RejectedMagnitude(f64),
+    /// Dip angle (rad) too far from the reference
+    RejectedDip(f64),
+    /// Heading innovation (rad) outside the gate
+    RejectedInnovation(f64),
+}
+
+pub struct MagYawCorrector {
+    config: MagYawConfig,
+    yaw_offset: f64, // rad, rotation about world Z applied to the estimate
+    variance: f64,   // rad^2
+    initialized: bool,
+    reference_field: Option<f64>,
+    reference_dip: Option<f64>,
+    reference_sum: (f64, f64, usize), // Field strength and dip sums while learning
+    innovation_rejections: usize,
+    in_filter: bool, // The filter takes the measurements itself, so there's no correction to keep
+}
+
+impl MagYawCorrector {
+    pub fn new(config: MagYawConfig) -> Self {
+        Self {
+            reference_field: config.expected_field,
+            config,
+            yaw_offset: 0.0,
+            variance: 0.0,
+            initialized: false,
+            reference_dip: None,
+            reference_sum: (0.0, 0.0, 0),
+            innovation_rejections: 0,
+            in_filter: false,
+        }
+    }
+
+    /// Forget the heading correction after the estimator restarted with a new yaw, keeping the
+    /// learned reference field. The next accepted sample sets the heading again.
+    pub fn reset_heading(&mut self) {
+        self.yaw_offset = 0.0;
+        self.variance = 0.0;
+        self.initialized = false;
+        self.innovation_rejections = 0;
+    }
+
+    /// Grow the heading uncertainty by dt seconds of drift
+    pub fn predict(&mut self, dt: f64) {
+        self.variance += self.config.yaw_drift * self.config.yaw_drift * dt;
+    }
+
+    /// Apply the heading correction to an estimator quaternion [w, x, y, z], which leaves it as it
+    /// is for filters that take the magnetometer themselves
+    pub fn correct(&self, q: [f64; 4]) -> [f64; 4] {
+        let half = self.yaw_offset / 2.0;
+        rotation::multiply([half.cos(), 0.0, 0.0, half.sin()], q)
+    }
+
+    /// Heading correction currently applied, rad
+    pub fn yaw_offset(&self) -> f64 {
+        self.yaw_offset
+    }
+
+    /// Variance of the corrected heading, rad^2, None until the first heading measurement or if
+    /// the filter keeps its own
+    pub fn variance(&self) -> Option<f64> {
+        (self.initialized && !self.in_filter).then_some(self.variance)
+    }
+
+    /// Correct the estimator's heading with a body frame magnetometer sample in uT, in the filter
+    /// if it takes magnetometer updates, otherwise through the heading correction
+    pub fn update(&mut self, estimator: &mut dyn AttitudeEstimator, mag: [f64; 3]) -> MagUpdate {
+        let Some(q) = estimator.orientation() else {
+            return MagUpdate::Waiting;
+        };
+
+        // Tilt compensation: express the field in the world frame using the current attitude.
+        // Only roll and pitch matter for the horizontal/vertical split.
+        let field = rotation::rotate_vector(self.correct(q), mag);
+        let horizontal = field[0].hypot(field[1]);
+        let strength = horizontal.hypot(field[2]);
+        let dip = (-field[2]).atan2(horizontal); // Positive pointing down, as in the northern hemisphere
+
+        // Learn what an undisturbed field looks like here
+        let (reference_field, reference_dip) = match (self.reference_field, self.reference_dip) {
+            (Some(field), Some(dip)) => (field, dip),
+            _ => {
+                self.reference_sum.0 += strength;
+                self.reference_sum.1 += dip;
+                self.reference_sum.2 += 1;
+                if self.reference_sum.2 >= REFERENCE_SAMPLES {
+                    let n = self.reference_sum.2 as f64;
+                    self.reference_field.get_or_insert(self.reference_sum.0 / n);
+                    self.reference_dip = Some(self.reference_sum.1 / n);
+                }
+                return MagUpdate::Learning;
+            }
+        };
+
+        // Disturbance gating: nearby steel or motor currents change the strength and direction
+        if (strength - reference_field).abs() > self.config.field_tolerance * reference_field {
+            return MagUpdate::RejectedMagnitude(strength);
+        }
+        if (dip - reference_dip).abs() > self.config.dip_tolerance {
+            return MagUpdate::RejectedDip(dip);
+        }
+
+        // In ENU, magnetic north is declination east of true north (+Y). If the estimate's yaw is
+        // off by e, the world frame field appears rotated by e from where it should be.
+        let expected_heading = PI / 2.0 - self.config.declination;
+
+        // Past enough rejections in a row the gate is opened so the sample gets through, see below
+        let gate = if self.innovation_rejections + 1 >= MAX_INNOVATION_REJECTIONS {
+            f64::INFINITY
+        } else {
+            self.config.innovation_gate
+        };
+        match estimator.update_mag(mag, expected_heading, self.config.heading_noise, gate) {
+            Some(HeadingUpdate::Applied(innovation)) => {
+                self.in_filter = true;
+                self.innovation_rejections = 0;
+                if !self.initialized {
+                    self.initialized = true;
+                    return MagUpdate::Initialized;
+                }
+                return MagUpdate::Applied(innovation);
+            }
+            Some(HeadingUpdate::Rejected(innovation)) => {
+                self.in_filter = true;
+                self.innovation_rejections += 1;
+                return MagUpdate::RejectedInnovation(innovation);
+            }
+            None => {}
+        }
+
+        let error = wrap_angle(field[1].atan2(field[0]) - expected_heading);
+        let innovation = -error;
+
+        let measurement_variance = self.config.heading_noise * self.config.heading_noise;
+        if !self.initialized {
+            self.yaw_offset = wrap_angle(self.yaw_offset + innovation);
+            self.variance = measurement_variance;
+            self.initialized = true;
+            return MagUpdate::Initialized;
+        }
+
+        let innovation_variance = self.variance + measurement_variance;
+        if innovation * innovation > self.config.innovation_gate.powi(2) * innovation_variance {
+            self.innovation_rejections += 1;
+            if self.innovation_rejections < MAX_INNOVATION_REJECTIONS {
+                return MagUpdate::RejectedInnovation(innovation);
+            }
+            // Consistently off rather than a glitch, so the estimate must have drifted. Open up the
+            // uncertainty so this sample gets through.
+            self.variance = innovation * innovation;
+        }
+        self.innovation_rejections = 0;
+
+        let gain = self.variance / (self.variance + measurement_variance);
+        self.yaw_offset = wrap_angle(self.yaw_offset + gain * innovation);
+        self.variance *= 1.0 - gain;
+        MagUpdate::Applied(innovation)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::complementary::{ComplementaryConfig, ComplementaryFilter};
+    use crate::eskf::{EskfConfig, GyroBiasEskf, GRAVITY};
+
+    const TOLERANCE: f64 = 1e-6;
+    const DECLINATION: f64 = 0.26; // rad, about 15 deg east
+    const HORIZONTAL: f64 = 20.0;  // uT
+    const VERTICAL: f64 = 45.0;    // uT, downwards
+
+    fn corrector() -> MagYawCorrector {
+        MagYawCorrector::new(MagYawConfig {
+            declination: DECLINATION,
+            expected_field: None,
+            field_tolerance: 0.15,
+            dip_tolerance: 0.1,
+            heading_noise: 0.05,
+            yaw_drift: 0.01,
+            innovation_gate: 3.0,
+        })
+    }
+
+    /// Body frame field of a level vehicle at `yaw` (ENU, 0 = east)
+    fn level_field(yaw: f64) -> [f64; 3] {
+        // Magnetic north is DECLINATION clockwise of true north, which is yaw PI/2
+        let bearing = PI / 2.0 - DECLINATION - yaw;
+        [HORIZONTAL * bearing.cos(), HORIZONTAL * bearing.sin(), -VERTICAL]
+    }
+
+    /// A filter sitting level with yaw 0, whatever the vehicle's real heading
+    fn level(mut estimator: Box<dyn AttitudeEstimator>) -> Box<dyn AttitudeEstimator> {
+        estimator.update([0.0, 0.0, GRAVITY]);
+        estimator
+    }
+
+    /// Learn the reference field from undisturbed samples at `yaw`
+    fn learn(corrector: &mut MagYawCorrector, estimator: &mut dyn AttitudeEstimator, yaw: f64) {
+        for _ in 0..REFERENCE_SAMPLES {
+            assert_eq!(corrector.update(estimator, level_field(yaw)), MagUpdate::Learning);
+        }
+    }
+
+    fn corrected_yaw(corrector: &MagYawCorrector, estimator: &dyn AttitudeEstimator) -> f64 {
+        rotation::quaternion_to_euler(corrector.correct(estimator.orientation().unwrap()))[2]
+    }
+
+    #[test]
+    fn waits_for_the_filter_to_start() {
+        let mut estimator = GyroBiasEskf::new(EskfConfig::default());
+        assert_eq!(corrector().update(&mut estimator, level_field(0.0)), MagUpdate::Waiting);
+    }
+
+    #[test]
+    fn facing_true_north_with_east_declination() {
+        // Level, nose to true north: magnetic north is DECLINATION to the right, -Y in body axes
+        let field = [HORIZONTAL * DECLINATION.cos(), -HORIZONTAL * DECLINATION.sin(), -VERTICAL];
+        for (i, x) in level_field(PI / 2.0).iter().zip(field).enumerate() {
+            assert!((x.0 - x.1).abs() < TOLERANCE, "{} {:?}", i, x);
+        }
+
+        for estimator in [
+            level(Box::new(ComplementaryFilter::new(ComplementaryConfig::default()))),
+            level(Box::new(GyroBiasEskf::new(EskfConfig::default()))),
+        ] {
+            let mut estimator = estimator;
+            let mut corrector = corrector();
+            learn(&mut corrector, estimator.as_mut(), PI / 2.0);
+            assert_eq!(corrector.update(estimator.as_mut(), field), MagUpdate::Initialized);
+            let yaw = corrected_yaw(&corrector, estimator.as_ref());
+            assert!((yaw - PI / 2.0).abs() < TOLERANCE, "{} {}", estimator.name(), yaw);
+        }
+    }
+
+    #[test]
+    fn external_correction_only_for_filters_without_a_mag_update() {
+        let mut complementary = level(Box::new(ComplementaryFilter::new(ComplementaryConfig::default())));
+        let mut external = corrector();
+        learn(&mut external, complementary.as_mut(), 1.0);
+        external.update(complementary.as_mut(), level_field(1.0));
+        assert!((external.yaw_offset() - 1.0).abs() < TOLERANCE);
+        assert_eq!(external.variance(), Some(0.05 * 0.05));
+
+        let mut eskf = level(Box::new(GyroBiasEskf::new(EskfConfig::default())));
+        let mut in_filter = corrector();
+        learn(&mut in_filter, eskf.as_mut(), 1.0);
+        for _ in 0..20 {
+            eskf.predict([0.0; 3], 0.1);
+            eskf.update([0.0, 0.0, GRAVITY]);
+            in_filter.predict(0.1);
+            assert!(in_filter.update(eskf.as_mut(), level_field(1.0)) != MagUpdate::Learning);
+        }
+        assert_eq!(in_filter.yaw_offset(), 0.0);
+        assert_eq!(in_filter.variance(), None);
+        assert!((eskf.euler().unwrap()[2] - 1.0).abs() < TOLERANCE);
+
+        // Yaw uncertainty comes from the filter's own update, below a single measurement's and
+        // correlated with the tilt through the field's dip
+        let covariance = eskf.euler_covariance().unwrap();
+        assert!(covariance[2][2] < 0.05 * 0.05, "{:?}", covariance);
+        assert!(covariance[0][2].abs() > 1e-5, "{:?}", covariance);
+    }
+
+    #[test]
+    fn disturbed_field_is_rejected() {
+        let mut estimator = level(Box::new(GyroBiasEskf::new(EskfConfig::default())));
+        let mut corrector = corrector();
+        learn(&mut corrector, estimator.as_mut(), 0.0);
+        assert_eq!(corrector.update(estimator.as_mut(), level_field(0.0)), MagUpdate::Initialized);
+
+        // Stronger, same direction
+        let stronger = level_field(0.0).map(|f| f * 1.3);
+        assert!(matches!(corrector.update(estimator.as_mut(), stronger), MagUpdate::RejectedMagnitude(_)));
+
+        // Same strength, steeper
+        let steeper_dip = VERTICAL.atan2(HORIZONTAL) + 0.2;
+        let strength = HORIZONTAL.hypot(VERTICAL);
+        let [x, y, _] = level_field(0.0).map(|f| f / HORIZONTAL * strength * steeper_dip.cos());
+        let steeper = [x, y, -strength * steeper_dip.sin()];
+        match corrector.update(estimator.as_mut(), steeper) {
+            MagUpdate::RejectedDip(dip) => assert!((dip - steeper_dip).abs() < TOLERANCE),
+            other => panic!("{:?}", other),
+        }
+        assert!((estimator.euler().unwrap()[2]).abs() < TOLERANCE);
+    }
+
+    #[test]
+    fn heading_jump_is_gated_until_it_persists() {
+        for estimator in [
+            level(Box::new(ComplementaryFilter::new(ComplementaryConfig::default()))),
+            level(Box::new(GyroBiasEskf::new(EskfConfig::default()))),
+        ] {
+            let mut estimator = estimator;
+            let mut corrector = corrector();
+            learn(&mut corrector, estimator.as_mut(), 0.0);
+            corrector.update(estimator.as_mut(), level_field(0.0));
+
+            // The vehicle's heading appears to jump by 1 rad and stay there
+            for _ in 1..MAX_INNOVATION_REJECTIONS {
+                match corrector.update(estimator.as_mut(), level_field(1.0)) {
+                    MagUpdate::RejectedInnovation(innovation) => {
+                        assert!((innovation - 1.0).abs() < TOLERANCE, "{}", innovation)
+                    }
+                    other => panic!("{} {:?}", estimator.name(), other),
+                }
+            }
+            assert!(matches!(corrector.update(estimator.as_mut(), level_field(1.0)), MagUpdate::Applied(_)));
+            assert!(corrected_yaw(&corrector, estimator.as_ref()) > 0.5, "{}", estimator.name());
+        }
+    }
+}
@@ -0,0 +1,109 @@
+//! Bounded queue from the subscription callback to the estimator thread.
+//!
+//! A single latest-sample slot loses a sample whenever two arrive before the estimator gets to them,
+//! and that sample's rotation is never integrated. `SampleQueue` holds every sample until the
+//! estimator takes it, without a lock the callback could block on. It's bounded so a stalled
+//! estimator can't grow it without limit; when it's full the oldest sample is dropped, since the
+//! estimator is better off catching up to the present. Drops and the deepest the queue has been are
+//! counted for the diagnostics.
+
+use crossbeam_queue::ArrayQueue;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+
+/// Queue counters for the diagnostics
+#[derive(Clone, Copy, Debug, Default)]
+pub struct QueueStats {
+    pub capacity: usize,
+    pub queued: usize,     // Samples waiting right now
+    pub high_water: usize, // Most samples ever waiting at once
+    pub dropped: u64,      // Samples pushed out by newer ones while full
+}
+
+pub struct SampleQueue<T> {
+    queue: ArrayQueue<T>,
+    high_water: AtomicUsize,
+    dropped: AtomicU64,
+}
+
+impl<T> SampleQueue<T> {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            queue: ArrayQueue::new(capacity.max(1)),
+            high_water: AtomicUsize::new(0),
+            dropped: AtomicU64::new(0),
+        }
+    }
+
+    /// Add a sample, dropping the oldest if the queue is full. Returns false if one was dropped.
+    pub fn push(&self, sample: T) -> bool {
+        let dropped = self.queue.force_push(sample).is_some();
+        if dropped {
+            self.dropped.fetch_add(1, Ordering::Relaxed);
+        }
+        self.high_water.fetch_max(self.queue.len(), Ordering::Relaxed);
+        !dropped
+    }
+
+    /// Oldest waiting sample
+    pub fn pop(&self) -> Option<T> {
+        self.queue.pop()
+    }
+
+    pub fn stats(&self) -> QueueStats {
+        QueueStats {
+            capacity: self.queue.capacity(),
+            queued: self.queue.len(),
+            high_water: self.high_water.load(Ordering::Relaxed),
+            dropped: self.dropped.load(Ordering::Relaxed),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn high_water_mark_stays_at_the_deepest_queue() {
+        let queue = SampleQueue::new(8);
+        for sample in 0..5 {
+            assert!(queue.push(sample));
+        }
+        assert_eq!(queue.pop(), Some(0));
+        assert_eq!(queue.pop(), Some(1));
+        queue.push(5);
+
+        let stats = queue.stats();
+        assert_eq!(stats.capacity, 8);
+        assert_eq!(stats.queued, 4);
+        assert_eq!(stats.high_water, 5);
+        assert_eq!(stats.dropped, 0);
+    }
+
+    #[test]
+    fn full_queue_drops_the_oldest_and_counts_it() {
+        let queue = SampleQueue::new(3);
+        for sample in 0..3 {
+            assert!(queue.push(sample));
+        }
+        assert!(!queue.push(3));
+        assert!(!queue.push(4));
+
+        let stats = queue.stats();
+        assert_eq!(stats.dropped, 2);
+        assert_eq!(stats.high_water, 3);
+        assert_eq!(queue.pop(), Some(2));
+        assert_eq!(queue.pop(), Some(3));
+        assert_eq!(queue.pop(), Some(4));
+        assert_eq!(queue.pop(), None);
+    }
+
+    #[test]
+    fn zero_capacity_still_holds_one_sample() {
+        let queue = SampleQueue::new(0);
+        assert!(queue.push(1));
+        assert!(!queue.push(2));
+        assert_eq!(queue.stats().capacity, 1);
+        assert_eq!(queue.pop(), Some(2));
+    }
+}
@@ -0,0 +1,210 @@
+//! Time steps between IMU samples from their header stamps.
+//!
+//! The time between callbacks is whatever the executor and DDS make it, so integrating the gyro over
+//! it turns scheduling jitter into attitude error. The stamps are when the sensor took the samples,
+//! so the step between consecutive stamps is the one to integrate over. `StampTracker` checks each
+//! stamp against the last: repeated and backwards stamps are dropped, a step well over the usual
+//! one is reported as a gap (messages were lost on the way), and a step beyond `max_dt` is too long
+//! to integrate a single gyro sample over, so the caller coasts or resets instead.
+
+/// Step, as a multiple of the usual step, beyond which samples are counted as lost
+const GAP_FACTOR: f64 = 1.5;
+
+/// Fraction of the way the usual step moves towards each new one
+const PERIOD_SMOOTHING: f64 = 0.05;
+
+/// What to do when the step between samples is beyond `max_dt`
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MaxDtAction {
+    Coast, // Skip the prediction over the gap and carry on from the current attitude
+    Reset, // Restart the filter from the next accelerometer sample
+}
+
+impl MaxDtAction {
+    pub fn from_name(name: &str) -> Result<Self, String> {
+        match name {
+            "coast" => Ok(MaxDtAction::Coast),
+            "reset" => Ok(MaxDtAction::Reset),
+            other => Err(format!("Max dt action must be coast or reset, got {}", other)),
+        }
+    }
+}
+
+/// How a sample's stamp follows the previous one
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum StampCheck {
+    /// First sample, nothing to step from
+    First,
+    /// Normal step, s
+    Step(f64),
+    /// Step (s) spanning `missed` lost samples, still short enough to integrate
+    Gap { dt: f64, missed: u64 },
+    /// Step (s) beyond the maximum
+    Exceeded(f64),
+    /// Same stamp as the previous sample
+    Duplicate,
+    /// Stamp before the previous sample's by this much (s)
+    OutOfOrder(f64),
+}
+
+/// Counts of the irregular stamps seen so far
+#[derive(Clone, Copy, Debug, Default)]
+pub struct StampStats {
+    pub gaps: u64,
+    pub missed: u64, // Samples lost in the gaps, estimated from the usual step
+    pub exceeded: u64,
+    pub duplicates: u64,
+    pub out_of_order: u64,
+}
+
+pub struct StampTracker {
+    max_dt: f64,         // s
+    last: Option<i64>,   // Newest stamp accepted, ns
+    period: Option<f64>, // Usual step, s
+    stats: StampStats,
+}
+
+impl StampTracker {
+    pub fn new(max_dt: f64) -> Self {
+        Self {
+            max_dt,
+            last: None,
+            period: None,
+            stats: StampStats::default(),
+        }
+    }
+
+    /// Check a sample's stamp (ns, see `stamp_nanos`) against the previous one. Duplicate and out of
+    /// order samples don't move the tracker on, the caller should drop them.
+    pub fn check(&mut self, stamp: i64) -> StampCheck {
+        let Some(last) = self.last else {
+            self.last = Some(stamp);
+            return StampCheck::First;
+        };
+        if stamp == last {
+            self.stats.duplicates += 1;
+            return StampCheck::Duplicate;
+        }
+        if stamp < last {
+            self.stats.out_of_order += 1;
+            return StampCheck::OutOfOrder((last - stamp) as f64 * 1e-9);
+        }
+
+        self.last = Some(stamp);
+        let dt = (stamp - last) as f64 * 1e-9;
+        if dt > self.max_dt {
+            self.stats.exceeded += 1;
+            return StampCheck::Exceeded(dt);
+        }
+
+        match self.period {
+            Some(period) if dt > GAP_FACTOR * period => {
+                let missed = ((dt / period).round() as u64).saturating_sub(1).max(1);
+                self.stats.gaps += 1;
+                self.stats.missed += missed;
+                StampCheck::Gap { dt, missed }
+            }
+            Some(period) => {
+                self.period = Some(period + PERIOD_SMOOTHING * (dt - period));
+                StampCheck::Step(dt)
+            }
+            None => {
+                self.period = Some(dt);
+                StampCheck::Step(dt)
+            }
+        }
+    }
+
+    /// Usual step between samples, s, once there has been one
+    pub fn period(&self) -> Option<f64> {
+        self.period
+    }
+
+    pub fn stats(&self) -> StampStats {
+        self.stats
+    }
+}
+
+/// A `builtin_interfaces/Time` stamp in ns
+pub fn stamp_nanos(sec: i32, nanosec: u32) -> i64 {
+    sec as i64 * 1_000_000_000 + nanosec as i64
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PERIOD_NS: i64 = 10_000_000; // 100 Hz
+
+    /// A tracker that has seen `count` samples a period apart, the last at `count - 1` periods
+    fn tracker_after(count: i64) -> StampTracker {
+        let mut tracker = StampTracker::new(0.1);
+        for i in 0..count {
+            tracker.check(i * PERIOD_NS);
+        }
+        tracker
+    }
+
+    #[test]
+    fn regular_stamps_step_by_the_period() {
+        let mut tracker = StampTracker::new(0.1);
+        assert_eq!(tracker.check(0), StampCheck::First);
+        for i in 1..10 {
+            match tracker.check(i * PERIOD_NS) {
+                StampCheck::Step(dt) => assert!((dt - 0.01).abs() < 1e-12, "{}", dt),
+                other => panic!("{:?}", other),
+            }
+        }
+        assert!((tracker.period().unwrap() - 0.01).abs() < 1e-12);
+    }
+
+    #[test]
+    fn lost_samples_are_a_gap() {
+        let mut tracker = tracker_after(10);
+        // Samples 10 to 12 never arrived
+        match tracker.check(13 * PERIOD_NS) {
+            StampCheck::Gap { dt, missed } => {
+                assert!((dt - 0.04).abs() < 1e-12, "{}", dt);
+                assert_eq!(missed, 3);
+            }
+            other => panic!("{:?}", other),
+        }
+        let stats = tracker.stats();
+        assert_eq!((stats.gaps, stats.missed), (1, 3));
+
+        // The gap doesn't stretch the usual step
+        assert!((tracker.period().unwrap() - 0.01).abs() < 1e-12);
+        assert_eq!(tracker.check(14 * PERIOD_NS), StampCheck::Step(0.01));
+    }
+
+    #[test]
+    fn step_beyond_max_dt_is_exceeded() {
+        let mut tracker = tracker_after(10);
+        match tracker.check(9 * PERIOD_NS + 200_000_000) {
+            StampCheck::Exceeded(dt) => assert!((dt - 0.2).abs() < 1e-12, "{}", dt),
+            other => panic!("{:?}", other),
+        }
+        assert_eq!(tracker.stats().exceeded, 1);
+        assert_eq!(tracker.stats().gaps, 0);
+    }
+
+    #[test]
+    fn repeated_stamp_is_a_duplicate_and_dropped() {
+        let mut tracker = tracker_after(10);
+        assert_eq!(tracker.check(9 * PERIOD_NS), StampCheck::Duplicate);
+        assert_eq!(tracker.stats().duplicates, 1);
+        // Still steps from the sample before the duplicate
+        assert_eq!(tracker.check(10 * PERIOD_NS), StampCheck::Step(0.01));
+    }
+
+    #[test]
+    fn earlier_stamp_is_out_of_order_and_dropped() {
+        let mut tracker = tracker_after(10);
+        match tracker.check(7 * PERIOD_NS) {
+            StampCheck::OutOfOrder(behind) => assert!((behind - 0.02).abs() < 1e-12, "{}", behind),
+            other => panic!("{:?}", other),
+        }
+        assert_eq!(tracker.stats().out_of_order, 1);
+        assert_eq!(tracker.check(10 * PERIOD_NS), StampCheck::Step(0.01));
+    }
+}